@@ -0,0 +1,6 @@
+//! Register models of emulated devices, independent of the hypervisor, so
+//! they can be tested on the host.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod uart16550;
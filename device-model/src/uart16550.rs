@@ -0,0 +1,490 @@
+//! Register model of the UART 16550. (ref: https://wiki.osdev.org/Serial_Ports)
+//!
+//! Transmission is instantaneous, so the transmitter holding register is
+//! always empty. Received bytes are queued in the receiver FIFO, which holds
+//! one byte only if FIFOs are disabled.
+
+/// Receiver buffer (read), transmitter holding (write), or divisor latch low (DLAB = 1).
+pub const DATA_REG: u16 = 0;
+/// Interrupt enable, or divisor latch high (DLAB = 1).
+pub const INT_EN_REG: u16 = 1;
+/// Interrupt identification (read), or FIFO control (write).
+pub const FIFO_CTRL_REG: u16 = 2;
+pub const LINE_CTRL_REG: u16 = 3;
+pub const MODEM_CTRL_REG: u16 = 4;
+pub const LINE_STATUS_REG: u16 = 5;
+pub const MODEM_STATUS_REG: u16 = 6;
+pub const SCRATCH_REG: u16 = 7;
+
+const UART_FIFO_CAPACITY: usize = 16;
+
+bitflags::bitflags! {
+    /// Interrupt enable flags
+    struct IntEnFlags: u8 {
+        const RECEIVED_DATA = 1;
+        const THR_EMPTY = 1 << 1;
+        const LINE_STATUS = 1 << 2;
+        const MODEM_STATUS = 1 << 3;
+    }
+
+    /// FIFO control flags
+    struct FifoCtrlFlags: u8 {
+        const ENABLE = 1;
+        const CLEAR_RX = 1 << 1;
+        const CLEAR_TX = 1 << 2;
+        const TRIGGER_LEVEL = 0b11 << 6;
+    }
+
+    /// Line control flags
+    struct LineCtrlFlags: u8 {
+        const DIVISOR_LATCH = 1 << 7;
+    }
+
+    /// Modem control flags
+    struct ModemCtrlFlags: u8 {
+        const DTR = 1;
+        const RTS = 1 << 1;
+        const OUT1 = 1 << 2;
+        const OUT2 = 1 << 3;
+        const LOOPBACK = 1 << 4;
+    }
+
+    /// Line status flags
+    struct LineStsFlags: u8 {
+        const INPUT_FULL = 1;
+        const OVERRUN_ERROR = 1 << 1;
+        // 2 to 4 are other errors, never occur
+        const OUTPUT_EMPTY = 1 << 5;
+        const TRANSMITTER_EMPTY = 1 << 6;
+        // 7 unknown
+    }
+
+    /// Modem status flags
+    struct ModemStsFlags: u8 {
+        const DELTA_CTS = 1;
+        const DELTA_DSR = 1 << 1;
+        const TRAILING_EDGE_RI = 1 << 2;
+        const DELTA_DCD = 1 << 3;
+        const CTS = 1 << 4;
+        const DSR = 1 << 5;
+        const RI = 1 << 6;
+        const DCD = 1 << 7;
+    }
+}
+
+/// Interrupt identification values, in descending order of priority.
+const IIR_NO_INT: u8 = 0x01;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_RECEIVED_DATA: u8 = 0x04;
+const IIR_CHAR_TIMEOUT: u8 = 0x0c;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+/// FIFO queue for caching bytes read.
+struct Fifo<const CAP: usize> {
+    buf: [u8; CAP],
+    head: usize,
+    num: usize,
+}
+
+impl<const CAP: usize> Fifo<CAP> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; CAP],
+            head: 0,
+            num: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.num
+    }
+
+    fn is_empty(&self) -> bool {
+        self.num == 0
+    }
+
+    fn push(&mut self, value: u8) {
+        assert!(self.num < CAP);
+        self.buf[(self.head + self.num) % CAP] = value;
+        self.num += 1;
+    }
+
+    fn pop(&mut self) -> u8 {
+        assert!(self.num > 0);
+        let ret = self.buf[self.head];
+        self.head += 1;
+        self.head %= CAP;
+        self.num -= 1;
+        ret
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.num = 0;
+    }
+}
+
+/// The register model of the UART, independent of the I/O backend.
+pub struct UartState {
+    rx_fifo: Fifo<UART_FIFO_CAPACITY>,
+    divisor: u16,
+    ier: IntEnFlags,
+    fcr: FifoCtrlFlags,
+    lcr: u8,
+    mcr: ModemCtrlFlags,
+    /// Error flags of the line status, cleared on read.
+    lsr_errors: LineStsFlags,
+    /// Delta flags of the modem status, cleared on read.
+    msr_deltas: ModemStsFlags,
+    scratch: u8,
+    /// Whether the THR empty interrupt is pending, cleared by reading IIR or
+    /// writing THR.
+    thr_ipending: bool,
+}
+
+impl UartState {
+    pub const fn new() -> Self {
+        Self {
+            rx_fifo: Fifo::new(),
+            divisor: 0,
+            ier: IntEnFlags::empty(),
+            fcr: FifoCtrlFlags::empty(),
+            lcr: 0,
+            mcr: ModemCtrlFlags::empty(),
+            lsr_errors: LineStsFlags::empty(),
+            msr_deltas: ModemStsFlags::empty(),
+            scratch: 0,
+            thr_ipending: false,
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LineCtrlFlags::DIVISOR_LATCH.bits() != 0
+    }
+
+    /// Whether the transmitter is looped back to the receiver.
+    pub fn loopback(&self) -> bool {
+        self.mcr.contains(ModemCtrlFlags::LOOPBACK)
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr.contains(FifoCtrlFlags::ENABLE)
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            UART_FIFO_CAPACITY
+        } else {
+            1
+        }
+    }
+
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        match (self.fcr & FifoCtrlFlags::TRIGGER_LEVEL).bits() >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    /// Whether the receiver can accept more bytes.
+    pub fn can_receive(&self) -> bool {
+        self.rx_fifo.len() < self.rx_capacity()
+    }
+
+    /// A byte arrives at the receiver, sets the overrun error if it's full.
+    pub fn receive(&mut self, value: u8) {
+        if self.can_receive() {
+            self.rx_fifo.push(value);
+        } else {
+            self.lsr_errors |= LineStsFlags::OVERRUN_ERROR;
+        }
+    }
+
+    fn modem_status(&self) -> ModemStsFlags {
+        if self.mcr.contains(ModemCtrlFlags::LOOPBACK) {
+            // modem control outputs are connected to modem status inputs
+            let mut msr = ModemStsFlags::empty();
+            msr.set(ModemStsFlags::CTS, self.mcr.contains(ModemCtrlFlags::RTS));
+            msr.set(ModemStsFlags::DSR, self.mcr.contains(ModemCtrlFlags::DTR));
+            msr.set(ModemStsFlags::RI, self.mcr.contains(ModemCtrlFlags::OUT1));
+            msr.set(ModemStsFlags::DCD, self.mcr.contains(ModemCtrlFlags::OUT2));
+            msr
+        } else {
+            // always connected
+            ModemStsFlags::CTS | ModemStsFlags::DSR | ModemStsFlags::DCD
+        }
+    }
+
+    fn line_status(&self) -> LineStsFlags {
+        let mut lsr =
+            self.lsr_errors | LineStsFlags::OUTPUT_EMPTY | LineStsFlags::TRANSMITTER_EMPTY;
+        if !self.rx_fifo.is_empty() {
+            lsr |= LineStsFlags::INPUT_FULL;
+        }
+        lsr
+    }
+
+    /// The highest priority pending interrupt, or `IIR_NO_INT`.
+    fn interrupt_id(&self) -> u8 {
+        let rx_len = self.rx_fifo.len();
+        if self.ier.contains(IntEnFlags::LINE_STATUS) && !self.lsr_errors.is_empty() {
+            IIR_LINE_STATUS
+        } else if self.ier.contains(IntEnFlags::RECEIVED_DATA) && rx_len >= self.rx_trigger_level()
+        {
+            IIR_RECEIVED_DATA
+        } else if self.ier.contains(IntEnFlags::RECEIVED_DATA) && rx_len > 0 {
+            // no timer for the character timeout, report it at once below the trigger level
+            IIR_CHAR_TIMEOUT
+        } else if self.ier.contains(IntEnFlags::THR_EMPTY) && self.thr_ipending {
+            IIR_THR_EMPTY
+        } else if self.ier.contains(IntEnFlags::MODEM_STATUS) && !self.msr_deltas.is_empty() {
+            IIR_MODEM_STATUS
+        } else {
+            IIR_NO_INT
+        }
+    }
+
+    /// Whether the interrupt line is asserted. It's connected to the PIC by
+    /// OUT2, and disconnected in the loopback mode.
+    pub fn irq_pending(&self) -> bool {
+        self.mcr.contains(ModemCtrlFlags::OUT2)
+            && !self.mcr.contains(ModemCtrlFlags::LOOPBACK)
+            && self.interrupt_id() != IIR_NO_INT
+    }
+
+    /// Reads the register at `offset` from the port base, in `0..8`.
+    pub fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            DATA_REG if self.dlab() => self.divisor as u8,
+            DATA_REG => {
+                if self.rx_fifo.is_empty() {
+                    0
+                } else {
+                    self.rx_fifo.pop()
+                }
+            }
+            INT_EN_REG if self.dlab() => (self.divisor >> 8) as u8,
+            INT_EN_REG => self.ier.bits(),
+            FIFO_CTRL_REG => {
+                let id = self.interrupt_id();
+                if id == IIR_THR_EMPTY {
+                    self.thr_ipending = false;
+                }
+                if self.fifo_enabled() {
+                    id | IIR_FIFO_ENABLED
+                } else {
+                    id
+                }
+            }
+            LINE_CTRL_REG => self.lcr,
+            MODEM_CTRL_REG => self.mcr.bits(),
+            LINE_STATUS_REG => {
+                let lsr = self.line_status();
+                self.lsr_errors = LineStsFlags::empty();
+                lsr.bits()
+            }
+            MODEM_STATUS_REG => {
+                let msr = self.modem_status() | self.msr_deltas;
+                self.msr_deltas = ModemStsFlags::empty();
+                msr.bits()
+            }
+            SCRATCH_REG => self.scratch,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Writes the register at `offset` from the port base, in `0..8`.
+    /// Returns the byte to transmit to the backend, if any.
+    pub fn write(&mut self, offset: u16, value: u8) -> Option<u8> {
+        match offset {
+            DATA_REG if self.dlab() => self.divisor = (self.divisor & 0xff00) | value as u16,
+            DATA_REG => {
+                // the byte is sent out at once, and THR becomes empty again
+                self.thr_ipending = true;
+                if self.mcr.contains(ModemCtrlFlags::LOOPBACK) {
+                    self.receive(value);
+                } else {
+                    return Some(value);
+                }
+            }
+            INT_EN_REG if self.dlab() => self.divisor = (self.divisor & 0xff) | (value as u16) << 8,
+            INT_EN_REG => {
+                let ier = IntEnFlags::from_bits_truncate(value);
+                if ier.contains(IntEnFlags::THR_EMPTY) && !self.ier.contains(IntEnFlags::THR_EMPTY)
+                {
+                    self.thr_ipending = true;
+                }
+                self.ier = ier;
+            }
+            FIFO_CTRL_REG => {
+                let fcr = FifoCtrlFlags::from_bits_truncate(value);
+                if fcr.contains(FifoCtrlFlags::CLEAR_RX)
+                    || fcr.contains(FifoCtrlFlags::ENABLE) != self.fifo_enabled()
+                {
+                    self.rx_fifo.clear();
+                }
+                self.fcr = fcr & (FifoCtrlFlags::ENABLE | FifoCtrlFlags::TRIGGER_LEVEL);
+            }
+            LINE_CTRL_REG => self.lcr = value,
+            MODEM_CTRL_REG => {
+                let old_msr = self.modem_status();
+                self.mcr = ModemCtrlFlags::from_bits_truncate(value);
+                let new_msr = self.modem_status();
+                let changed = old_msr ^ new_msr;
+                if changed.contains(ModemStsFlags::CTS) {
+                    self.msr_deltas |= ModemStsFlags::DELTA_CTS;
+                }
+                if changed.contains(ModemStsFlags::DSR) {
+                    self.msr_deltas |= ModemStsFlags::DELTA_DSR;
+                }
+                if old_msr.contains(ModemStsFlags::RI) && !new_msr.contains(ModemStsFlags::RI) {
+                    self.msr_deltas |= ModemStsFlags::TRAILING_EDGE_RI;
+                }
+                if changed.contains(ModemStsFlags::DCD) {
+                    self.msr_deltas |= ModemStsFlags::DELTA_DCD;
+                }
+            }
+            LINE_STATUS_REG | MODEM_STATUS_REG => {} // ignore, factory test only
+            SCRATCH_REG => self.scratch = value,
+            _ => unreachable!(),
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iir(state: &mut UartState) -> u8 {
+        state.read(FIFO_CTRL_REG) & 0x0f
+    }
+
+    #[test]
+    fn iir_priority() {
+        let mut state = UartState::new();
+        state.write(INT_EN_REG, 0x0f);
+        state.write(MODEM_CTRL_REG, ModemCtrlFlags::LOOPBACK.bits());
+        state.write(
+            MODEM_CTRL_REG,
+            (ModemCtrlFlags::LOOPBACK | ModemCtrlFlags::RTS).bits(),
+        );
+        state.write(DATA_REG, b'a');
+        state.write(DATA_REG, b'b'); // overrun, FIFOs are disabled
+
+        assert_eq!(iir(&mut state), IIR_LINE_STATUS);
+        state.read(LINE_STATUS_REG);
+        assert_eq!(iir(&mut state), IIR_RECEIVED_DATA);
+        assert_eq!(state.read(DATA_REG), b'a');
+        assert_eq!(iir(&mut state), IIR_THR_EMPTY);
+        assert_eq!(iir(&mut state), IIR_MODEM_STATUS);
+        state.read(MODEM_STATUS_REG);
+        assert_eq!(iir(&mut state), IIR_NO_INT);
+    }
+
+    #[test]
+    fn thr_empty_cleared_on_iir_read() {
+        let mut state = UartState::new();
+        assert_eq!(iir(&mut state), IIR_NO_INT);
+        state.write(INT_EN_REG, IntEnFlags::THR_EMPTY.bits());
+        assert_eq!(iir(&mut state), IIR_THR_EMPTY);
+        assert_eq!(iir(&mut state), IIR_NO_INT);
+
+        assert_eq!(state.write(DATA_REG, b'x'), Some(b'x'));
+        assert_eq!(iir(&mut state), IIR_THR_EMPTY);
+        assert_eq!(iir(&mut state), IIR_NO_INT);
+    }
+
+    #[test]
+    fn dlab_divisor_latches() {
+        let mut state = UartState::new();
+        state.write(LINE_CTRL_REG, 0x83);
+        state.write(DATA_REG, 0x0c);
+        state.write(INT_EN_REG, 0x01);
+        assert_eq!(state.divisor, 0x010c);
+        assert_eq!(state.read(DATA_REG), 0x0c);
+        assert_eq!(state.read(INT_EN_REG), 0x01);
+        assert!(state.ier.is_empty());
+
+        state.write(LINE_CTRL_REG, 0x03);
+        assert_eq!(state.write(DATA_REG, b'x'), Some(b'x'));
+        state.write(INT_EN_REG, IntEnFlags::RECEIVED_DATA.bits());
+        assert_eq!(state.read(INT_EN_REG), IntEnFlags::RECEIVED_DATA.bits());
+        assert_eq!(state.divisor, 0x010c);
+    }
+
+    #[test]
+    fn fifo_trigger_levels() {
+        for (fcr, level) in [(0x01, 1), (0x41, 4), (0x81, 8), (0xc1, 14)] {
+            let mut state = UartState::new();
+            state.write(FIFO_CTRL_REG, fcr);
+            state.write(INT_EN_REG, IntEnFlags::RECEIVED_DATA.bits());
+            assert_eq!(state.read(FIFO_CTRL_REG), IIR_NO_INT | IIR_FIFO_ENABLED);
+            for i in 1..level {
+                state.receive(i as u8);
+                assert_eq!(iir(&mut state), IIR_CHAR_TIMEOUT);
+            }
+            state.receive(level as u8);
+            assert_eq!(
+                state.read(FIFO_CTRL_REG),
+                IIR_RECEIVED_DATA | IIR_FIFO_ENABLED
+            );
+        }
+
+        // a 1-byte holding register with FIFOs disabled
+        let mut state = UartState::new();
+        state.write(INT_EN_REG, IntEnFlags::RECEIVED_DATA.bits());
+        state.receive(1);
+        state.receive(2);
+        assert_eq!(state.read(FIFO_CTRL_REG), IIR_RECEIVED_DATA);
+        assert!(state.line_status().contains(LineStsFlags::OVERRUN_ERROR));
+    }
+
+    #[test]
+    fn loopback_modem_status() {
+        let mut state = UartState::new();
+        let connected = ModemStsFlags::CTS | ModemStsFlags::DSR | ModemStsFlags::DCD;
+        assert_eq!(state.read(MODEM_STATUS_REG), connected.bits());
+
+        let outputs =
+            ModemCtrlFlags::DTR | ModemCtrlFlags::RTS | ModemCtrlFlags::OUT1 | ModemCtrlFlags::OUT2;
+        state.write(MODEM_CTRL_REG, (ModemCtrlFlags::LOOPBACK | outputs).bits());
+        assert_eq!(
+            state.read(MODEM_STATUS_REG),
+            (connected | ModemStsFlags::RI).bits()
+        );
+
+        state.write(MODEM_CTRL_REG, ModemCtrlFlags::LOOPBACK.bits());
+        let deltas = ModemStsFlags::DELTA_CTS
+            | ModemStsFlags::DELTA_DSR
+            | ModemStsFlags::TRAILING_EDGE_RI
+            | ModemStsFlags::DELTA_DCD;
+        assert_eq!(state.read(MODEM_STATUS_REG), deltas.bits());
+        assert_eq!(state.read(MODEM_STATUS_REG), 0);
+
+        // transmitted bytes are received
+        assert_eq!(state.write(DATA_REG, b'x'), None);
+        assert_eq!(state.read(DATA_REG), b'x');
+    }
+
+    #[test]
+    fn irq_gated_by_out2() {
+        let mut state = UartState::new();
+        state.write(INT_EN_REG, IntEnFlags::THR_EMPTY.bits());
+        assert!(!state.irq_pending());
+        state.write(MODEM_CTRL_REG, ModemCtrlFlags::OUT2.bits());
+        assert!(state.irq_pending());
+        state.write(
+            MODEM_CTRL_REG,
+            (ModemCtrlFlags::OUT2 | ModemCtrlFlags::LOOPBACK).bits(),
+        );
+        assert!(!state.irq_pending());
+    }
+}
@@ -19,6 +19,62 @@ pub fn irqs_disabled() -> bool {
     !rflags::read().contains(RFlags::INTERRUPT_FLAG)
 }
 
+/// Run the NMI handler of the host, for the NMIs received when the guest is
+/// running.
+#[inline]
+pub fn raise_nmi() {
+    unsafe { asm!("int 2") };
+}
+
+/// Read an MSR, returns `None` if it causes #GP, for reserved or unimplemented
+/// MSRs.
+pub fn rdmsr_safe(msr: u32) -> Option<u64> {
+    let (low, high): (u32, u32);
+    let mut fault = 0u32;
+    unsafe {
+        asm!(
+            "2: rdmsr",
+            "jmp 4f",
+            "3: mov {fault:e}, 1",
+            "4:",
+            ".pushsection .ex_table, \"a\"",
+            ".balign 8",
+            ".quad 2b, 3b",
+            ".popsection",
+            fault = inout(reg) fault,
+            in("ecx") msr,
+            out("eax") low,
+            out("edx") high,
+            options(nostack),
+        );
+    }
+    (fault == 0).then_some(low as u64 | (high as u64) << 32)
+}
+
+/// Write an MSR, returns `false` if it causes #GP, for reserved bits or
+/// unimplemented MSRs.
+pub fn wrmsr_safe(msr: u32, value: u64) -> bool {
+    let mut fault = 0u32;
+    unsafe {
+        asm!(
+            "2: wrmsr",
+            "jmp 4f",
+            "3: mov {fault:e}, 1",
+            "4:",
+            ".pushsection .ex_table, \"a\"",
+            ".balign 8",
+            ".quad 2b, 3b",
+            ".popsection",
+            fault = inout(reg) fault,
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack),
+        );
+    }
+    fault == 0
+}
+
 #[inline]
 pub fn wait_for_ints() {
     if !irqs_disabled() {
@@ -0,0 +1,37 @@
+//! I/O APIC, routes ISA IRQs of host devices to the local APIC.
+
+use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
+
+use super::lapic::{local_apic, vectors::ISA_IRQ_VECTOR_BASE};
+use crate::mm::address::phys_to_virt;
+
+const IOAPIC_BASE: usize = 0xfec0_0000;
+
+static mut IO_APIC: Option<IoApic> = None;
+
+fn io_apic<'a>() -> &'a mut IoApic {
+    unsafe { IO_APIC.as_mut().unwrap() }
+}
+
+/// Route the ISA `irq` to the current CPU, as vector `ISA_IRQ_VECTOR_BASE + irq`.
+pub fn enable_irq(irq: u8) {
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_mode(IrqMode::Fixed);
+    entry.set_flags(IrqFlags::empty()); // edge-triggered, active high
+    entry.set_vector(ISA_IRQ_VECTOR_BASE + irq);
+    entry.set_dest(unsafe { local_apic().id() } as u8);
+    unsafe {
+        io_apic().set_table_entry(irq, entry);
+        io_apic().enable_irq(irq);
+    }
+}
+
+pub fn init() {
+    println!("Initializing I/O APIC...");
+    unsafe {
+        // all entries are masked by `init()`
+        let mut ioapic = IoApic::new(phys_to_virt(IOAPIC_BASE) as u64);
+        ioapic.init(ISA_IRQ_VECTOR_BASE);
+        IO_APIC = Some(ioapic);
+    }
+}
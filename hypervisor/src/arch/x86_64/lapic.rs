@@ -4,6 +4,8 @@ use x86_64::instructions::port::Port;
 use self::vectors::*;
 
 pub mod vectors {
+    /// Vectors of ISA IRQs routed by the I/O APIC.
+    pub const ISA_IRQ_VECTOR_BASE: u8 = 0x20;
     pub const APIC_TIMER_VECTOR: u8 = 0xf0;
     pub const APIC_SPURIOUS_VECTOR: u8 = 0xf1;
     pub const APIC_ERROR_VECTOR: u8 = 0xf2;
@@ -1,10 +1,12 @@
 mod boot;
 mod gdt;
 mod idt;
+mod ioapic;
 mod lapic;
 mod trap;
 
 pub mod instructions;
+pub mod power;
 pub mod timer;
 pub mod uart16550;
 
@@ -19,5 +21,7 @@ pub fn init() {
     gdt::init();
     idt::init();
     lapic::init();
+    ioapic::init();
     timer::init();
+    uart::init_irq();
 }
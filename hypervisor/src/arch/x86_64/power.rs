@@ -0,0 +1,36 @@
+//! Terminate the machine, mainly for automated test runs on QEMU.
+
+use core::ops::Range;
+
+use x86_64::instructions::port::PortWriteOnly;
+
+use super::instructions;
+
+/// The port of QEMU `-device isa-debug-exit,iobase=0xf4,iosize=0x04`, QEMU
+/// exits with `(status << 1) | 1` after a write.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+/// The ACPI PM1a control register of the QEMU q35 machine.
+const PM1A_CONTROL_PORT: u16 = 0x604;
+/// Enter the sleep type 0 (S5), as reported in the DSDT of QEMU.
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+
+/// The I/O ports written by [`exit`].
+pub fn exit_ports() -> [Range<u16>; 2] {
+    [
+        DEBUG_EXIT_PORT..DEBUG_EXIT_PORT + 4,
+        PM1A_CONTROL_PORT..PM1A_CONTROL_PORT + 2,
+    ]
+}
+
+/// Exit QEMU with `status` by the debug exit device, or power off the machine
+/// by ACPI if there is no such device. Halt if neither works.
+pub fn exit(status: u32) -> ! {
+    unsafe {
+        PortWriteOnly::<u32>::new(DEBUG_EXIT_PORT).write(status);
+        PortWriteOnly::<u16>::new(PM1A_CONTROL_PORT).write(PM1_CNT_SLP_EN);
+    }
+    instructions::disable_irqs();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -52,12 +52,14 @@ fn x86_trap_handler(tf: &mut TrapFrame) {
                 tf.error_code,
             );
         }
+        GENERAL_PROTECTION_FAULT_VECTOR if fixup_exception(tf) => {}
         GENERAL_PROTECTION_FAULT_VECTOR => {
             panic!(
                 "General Protection Exception @ {:#x}, error_code = {:#x}, kernel killed it.",
                 tf.rip, tf.error_code,
             );
         }
+        NONMASKABLE_INTERRUPT_VECTOR => warn!("NMI @ {:#x}", tf.rip),
         IRQ_VECTOR_START..=IRQ_VECTOR_END => handle_irq(tf.vector as u8),
         _ => {
             panic!(
@@ -68,12 +70,43 @@ fn x86_trap_handler(tf: &mut TrapFrame) {
     }
 }
 
+/// An entry of the exception table in the `.ex_table` section: a fault of the
+/// instruction at `addr` continues at `fixup`, like the `*_safe` instructions.
+#[repr(C)]
+struct ExceptionTableEntry {
+    addr: usize,
+    fixup: usize,
+}
+
+/// Jump to the fixup code if the faulting instruction has one.
+fn fixup_exception(tf: &mut TrapFrame) -> bool {
+    extern "C" {
+        fn __ex_table_start();
+        fn __ex_table_end();
+    }
+    let start = __ex_table_start as usize as *const ExceptionTableEntry;
+    let len = (__ex_table_end as usize - __ex_table_start as usize)
+        / core::mem::size_of::<ExceptionTableEntry>();
+    let table = unsafe { core::slice::from_raw_parts(start, len) };
+    match table.iter().find(|e| e.addr == tf.rip as usize) {
+        Some(entry) => {
+            tf.rip = entry.fixup as u64;
+            true
+        }
+        None => false,
+    }
+}
+
 pub fn handle_irq(vector: u8) {
     match vector {
         APIC_TIMER_VECTOR => {
             trace!("TIMER");
             unsafe { local_apic().end_of_interrupt() };
         }
+        _ if Some(vector) == super::uart::console_irq().map(|irq| ISA_IRQ_VECTOR_BASE + irq) => {
+            super::uart::handle_irq();
+            unsafe { local_apic().end_of_interrupt() };
+        }
         _ => warn!("Unhandled IRQ {}", vector),
     }
 }
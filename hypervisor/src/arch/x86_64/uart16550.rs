@@ -1,12 +1,21 @@
 //! Uart 16550.
 
+use core::ops::Range;
+
 use spin::Mutex;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 
 const UART_CLOCK_FACTOR: usize = 16;
 const OSC_FREQ: usize = 1_843_200;
+const RX_BUF_SIZE: usize = 256;
+
+static CONSOLE: Mutex<Uart16550> = Mutex::new(Uart16550::new(0x3f8)); // COM1 by default
 
-static COM1: Mutex<Uart16550> = Mutex::new(Uart16550::new(0x3f8));
+/// Bytes received in the IRQ handler but not read yet.
+static RX_BUF: Mutex<RxBuffer> = Mutex::new(RxBuffer::new());
+
+/// The ISA IRQ of the console, receive interrupts are enabled if it's known.
+static mut CONSOLE_IRQ: Option<u8> = None;
 
 bitflags::bitflags! {
     /// Line status flags
@@ -18,6 +27,41 @@ bitflags::bitflags! {
     }
 }
 
+struct RxBuffer {
+    buf: [u8; RX_BUF_SIZE],
+    head: usize,
+    num: usize,
+}
+
+impl RxBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RX_BUF_SIZE],
+            head: 0,
+            num: 0,
+        }
+    }
+
+    /// Push a byte, the oldest one is dropped if it's full.
+    fn push(&mut self, c: u8) {
+        if self.num == RX_BUF_SIZE {
+            self.pop();
+        }
+        self.buf[(self.head + self.num) % RX_BUF_SIZE] = c;
+        self.num += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.num == 0 {
+            return None;
+        }
+        let c = self.buf[self.head];
+        self.head = (self.head + 1) % RX_BUF_SIZE;
+        self.num -= 1;
+        Some(c)
+    }
+}
+
 struct Uart16550 {
     data: Port<u8>,
     int_en: PortWriteOnly<u8>,
@@ -65,6 +109,10 @@ impl Uart16550 {
         }
     }
 
+    fn enable_rx_interrupt(&mut self) {
+        unsafe { self.int_en.write(0x01) };
+    }
+
     fn line_sts(&mut self) -> LineStsFlags {
         unsafe { LineStsFlags::from_bits_truncate(self.line_sts.read()) }
     }
@@ -84,13 +132,48 @@ impl Uart16550 {
 }
 
 pub fn console_putchar(c: u8) {
-    COM1.lock().putchar(c);
+    CONSOLE.lock().putchar(c);
 }
 
+/// Read a byte received by the IRQ handler, or from the console directly.
 pub fn console_getchar() -> Option<u8> {
-    COM1.lock().getchar()
+    let c = RX_BUF.lock().pop();
+    c.or_else(|| CONSOLE.lock().getchar())
+}
+
+/// The I/O ports of the console.
+pub fn console_ports() -> Range<u16> {
+    let port = crate::cmdline::serial().unwrap_or_default().port;
+    port..port + 8
+}
+
+/// The ISA IRQ of the console if receive interrupts are enabled.
+pub fn console_irq() -> Option<u8> {
+    unsafe { CONSOLE_IRQ }
+}
+
+/// Move all received bytes from the console to the receive buffer.
+pub fn handle_irq() {
+    let mut uart = CONSOLE.lock();
+    let mut rx_buf = RX_BUF.lock();
+    while let Some(c) = uart.getchar() {
+        rx_buf.push(c);
+    }
 }
 
 pub fn init() {
-    COM1.lock().init(115200);
+    let config = crate::cmdline::serial().unwrap_or_default();
+    let mut uart = CONSOLE.lock();
+    *uart = Uart16550::new(config.port);
+    uart.init(config.baud_rate);
+    unsafe { CONSOLE_IRQ = config.irq };
+}
+
+/// Enable receive interrupts of the console, must be called after the I/O
+/// APIC is initialized.
+pub fn init_irq() {
+    if let Some(irq) = console_irq() {
+        super::ioapic::enable_irq(irq);
+        CONSOLE.lock().enable_rx_interrupt();
+    }
 }
@@ -0,0 +1,193 @@
+//! Hypervisor command line passed by the boot loader.
+//!
+//! Arguments are separated by whitespaces, and an argument is either a flag
+//! (`key`) or an option (`key=value`). Whitespaces inside double quotes do
+//! not separate arguments, and the quotes around a value are removed, e.g.
+//! `guest="memory 0 16M rwx; entry 0x8000"`. Supported options:
+//!
+//! * `log=<directives>`: comma separated log levels, overrides the `LOG`
+//!   variable at build time. A directive is either `<level>` as the default
+//!   level, or `<target>=<level>` for a module path prefix, e.g.
+//!   `log=info,rvm::arch=warn,rvm_hypervisor::hv::vmexit=trace`.
+//! * `log.<target>=<level>`: the same as `log=<target>=<level>`, e.g. `log.rvm=trace`.
+//! * `guest=<config>`: a guest configuration module or the inline
+//!   configuration, see `hv::gconfig`.
+//! * `serial=<port>[,<baud rate>]`: the host serial console, `<port>` is an I/O
+//!   port base or one of `com1` to `com4`, e.g. `serial=com2,9600`.
+
+use alloc::vec::Vec;
+
+use log::LevelFilter;
+
+use crate::multiboot;
+
+const OPTIONS: &[&str] = &["log", "guest", "serial"];
+const TARGET_LOG_PREFIX: &str = "log.";
+
+/// A log filter directive, sets the level of a target, or the default level
+/// if the target is `None`.
+pub type LogDirective = (Option<&'static str>, LevelFilter);
+
+/// The host serial console.
+#[derive(Debug, Clone, Copy)]
+pub struct SerialConfig {
+    pub port: u16,
+    pub baud_rate: usize,
+    /// The ISA IRQ of standard COM ports, `None` for others.
+    pub irq: Option<u8>,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            port: 0x3f8, // COM1
+            baud_rate: 115200,
+            irq: Some(4),
+        }
+    }
+}
+
+/// Split `cmdline` into arguments.
+fn split_args(cmdline: &str) -> impl Iterator<Item = &str> {
+    let mut rest = cmdline.trim_start();
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut in_quotes = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                !in_quotes && c.is_whitespace()
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let arg = &rest[..end];
+        rest = rest[end..].trim_start();
+        Some(arg)
+    })
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Iterate over all arguments in the hypervisor command line as `(key, value)`
+/// pairs, the value of a flag is `None`.
+pub fn args() -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+    split_args(multiboot::cmdline()).map(|arg| match arg.split_once('=') {
+        Some((key, value)) => (key, Some(unquote(value))),
+        None => (arg, None),
+    })
+}
+
+/// The value of the last option `key=value` in the hypervisor command line.
+pub fn get(key: &str) -> Option<&'static str> {
+    args()
+        .filter(|&(k, _)| k == key)
+        .filter_map(|(_, v)| v)
+        .last()
+}
+
+fn parse_num(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_log_directive(s: &'static str) -> Option<LogDirective> {
+    match s.split_once('=') {
+        Some((target, level)) if !target.is_empty() => Some((Some(target), level.parse().ok()?)),
+        Some(_) => None,
+        None => Some((None, s.parse().ok()?)),
+    }
+}
+
+fn parse_log_directives(s: &'static str) -> Option<Vec<LogDirective>> {
+    s.split(',')
+        .filter(|d| !d.is_empty())
+        .map(parse_log_directive)
+        .collect()
+}
+
+/// Log filter directives set by all `log` and `log.<target>` options in order,
+/// invalid ones are skipped.
+pub fn log_directives() -> Vec<LogDirective> {
+    let mut directives = Vec::new();
+    for (key, value) in args() {
+        let Some(value) = value else {
+            continue;
+        };
+        if key == "log" {
+            directives.extend(parse_log_directives(value).unwrap_or_default());
+        } else if let Some(target) = key.strip_prefix(TARGET_LOG_PREFIX) {
+            if let Ok(level) = value.parse() {
+                directives.push((Some(target), level));
+            }
+        }
+    }
+    directives
+}
+
+/// The guest configuration set by the `guest` option.
+pub fn guest() -> Option<&'static str> {
+    get("guest")
+}
+
+/// The host serial console set by the `serial` option.
+pub fn serial() -> Option<SerialConfig> {
+    let value = get("serial")?;
+    let (port, baud_rate) = match value.split_once(',') {
+        Some((port, baud_rate)) => (port, Some(baud_rate)),
+        None => (value, None),
+    };
+    let port = match port {
+        "com1" => 0x3f8,
+        "com2" => 0x2f8,
+        "com3" => 0x3e8,
+        "com4" => 0x2e8,
+        _ => parse_num(port)? as u16,
+    };
+    let baud_rate = match baud_rate {
+        Some(b) => parse_num(b).filter(|&b| b > 0)?,
+        None => SerialConfig::default().baud_rate,
+    };
+    let irq = match port {
+        0x3f8 | 0x3e8 => Some(4),
+        0x2f8 | 0x2e8 => Some(3),
+        _ => None,
+    };
+    Some(SerialConfig {
+        port,
+        baud_rate,
+        irq,
+    })
+}
+
+/// Warn about options that are unknown or have invalid values, must be called
+/// after the logger is initialized.
+pub fn check() {
+    for (key, value) in args() {
+        let Some(value) = value else {
+            continue; // flags, or the kernel file name given by the boot loader
+        };
+        let valid = match key {
+            "log" => parse_log_directives(value).is_some(),
+            "serial" => serial().is_some(),
+            _ if key.starts_with(TARGET_LOG_PREFIX) => value.parse::<LevelFilter>().is_ok(),
+            _ => OPTIONS.contains(&key),
+        };
+        if !valid {
+            warn!(
+                "Ignored hypervisor command line option: {}={:?}",
+                key, value
+            );
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! Linux x86 boot protocol. (ref: https://www.kernel.org/doc/html/latest/x86/boot.html)
+
+use core::mem::size_of;
+
+use rvm::arch::LongModeState;
+use rvm::{GuestPhysAddr, RvmError, RvmResult, RvmVcpu};
+
+use super::{guest_memory_map, MemoryMapEntry, MemoryType};
+use crate::hv::gpm::GuestPhysMemorySet;
+use crate::hv::hal::RvmHalImpl;
+use crate::mm::PAGE_SIZE;
+
+type Vcpu = RvmVcpu<RvmHalImpl>;
+
+const SETUP_HEADER_OFFSET: usize = 0x1f1;
+const BOOT_FLAG_MAGIC: u16 = 0xaa55;
+const HEADER_MAGIC: u32 = 0x5372_6448; // "HdrS"
+const MIN_PROTOCOL_VERSION: u16 = 0x020c; // 2.12, for `xloadflags`
+const XLF_KERNEL_64: u16 = 1 << 0;
+const TYPE_OF_LOADER_UNDEFINED: u8 = 0xff;
+const STARTUP_64_OFFSET: usize = 0x200;
+const E820_MAX_ENTRIES: usize = 128;
+/// The kernel is never loaded below 1M.
+const KERNEL_MIN_GPA: GuestPhysAddr = 0x10_0000;
+
+const BOOT_GDT_GPA: GuestPhysAddr = 0x1000;
+const BOOT_PARAMS_GPA: GuestPhysAddr = 0x2000;
+const BOOT_CMDLINE_GPA: GuestPhysAddr = 0x3000;
+const BOOT_STACK_TOP: GuestPhysAddr = 0x8000;
+const BOOT_PAGE_TABLE_GPA: GuestPhysAddr = 0x1_0000;
+/// The boot page table identity maps the low 4G memory with 2M pages.
+const BOOT_PAGE_TABLE_PD_COUNT: usize = 4;
+
+/// Selectors of `__BOOT_CS` and `__BOOT_DS` required by the 64-bit boot protocol.
+const BOOT_CS: u16 = 0x10;
+const BOOT_DS: u16 = 0x18;
+const BOOT_GDT: [u64; 4] = [
+    0,
+    0,
+    0x00af_9b00_0000_ffff, // 0x10: 64-bit code segment
+    0x00cf_9300_0000_ffff, // 0x18: data segment
+];
+
+/// The real-mode kernel header. (ref: Documentation/x86/boot.rst)
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct SetupHeader {
+    setup_sects: u8,
+    root_flags: u16,
+    syssize: u32,
+    ram_size: u16,
+    vid_mode: u16,
+    root_dev: u16,
+    boot_flag: u16,
+    jump: u16,
+    header: u32,
+    version: u16,
+    realmode_swtch: u32,
+    start_sys_seg: u16,
+    kernel_version: u16,
+    type_of_loader: u8,
+    loadflags: u8,
+    setup_move_size: u16,
+    code32_start: u32,
+    ramdisk_image: u32,
+    ramdisk_size: u32,
+    bootsect_kludge: u32,
+    heap_end_ptr: u16,
+    ext_loader_ver: u8,
+    ext_loader_type: u8,
+    cmd_line_ptr: u32,
+    initrd_addr_max: u32,
+    kernel_alignment: u32,
+    relocatable_kernel: u8,
+    min_alignment: u8,
+    xloadflags: u16,
+    cmdline_size: u32,
+    hardware_subarch: u32,
+    hardware_subarch_data: u64,
+    payload_offset: u32,
+    payload_length: u32,
+    setup_data: u64,
+    pref_address: u64,
+    init_size: u32,
+    handover_offset: u32,
+    kernel_info_offset: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct E820Entry {
+    addr: u64,
+    size: u64,
+    mem_type: u32,
+}
+
+/// The "zero page", only fields used by the hypervisor are named.
+#[repr(C, packed)]
+struct BootParams {
+    _pad0: [u8; 0x1e8],
+    e820_entries: u8,
+    _pad1: [u8; SETUP_HEADER_OFFSET - 0x1e9],
+    hdr: SetupHeader,
+    _pad2: [u8; 0x2d0 - SETUP_HEADER_OFFSET - size_of::<SetupHeader>()],
+    e820_table: [E820Entry; E820_MAX_ENTRIES],
+    _pad3: [u8; PAGE_SIZE - 0x2d0 - E820_MAX_ENTRIES * size_of::<E820Entry>()],
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn setup_header(image: &[u8]) -> Option<SetupHeader> {
+    if image.len() < SETUP_HEADER_OFFSET + size_of::<SetupHeader>() {
+        return None;
+    }
+    let ptr = image[SETUP_HEADER_OFFSET..].as_ptr() as *const SetupHeader;
+    let hdr = unsafe { ptr.read_unaligned() };
+    if hdr.boot_flag == BOOT_FLAG_MAGIC && hdr.header == HEADER_MAGIC {
+        Some(hdr)
+    } else {
+        None
+    }
+}
+
+/// Whether `image` is a Linux kernel image (bzImage).
+pub fn is_bzimage(image: &[u8]) -> bool {
+    setup_header(image).is_some()
+}
+
+/// Find the lowest address to load a kernel that occupies `size` bytes, and
+/// the RAM entry of `mmap` that contains the whole range.
+///
+/// A relocatable kernel is placed at the first `align`-aligned address above
+/// 1M, otherwise it can only be loaded at `pref_address`.
+fn kernel_placement(
+    mmap: &[MemoryMapEntry],
+    relocatable: bool,
+    align: usize,
+    pref_address: usize,
+    size: usize,
+) -> Option<(GuestPhysAddr, &MemoryMapEntry)> {
+    let align = align.max(1);
+    mmap.iter()
+        .filter(|e| e.mem_type == MemoryType::Ram)
+        .find_map(|e| {
+            let addr = if relocatable {
+                let start = e.start.max(KERNEL_MIN_GPA);
+                (start + align - 1) / align * align
+            } else {
+                pref_address
+            };
+            let end = addr.checked_add(size)?;
+            if addr >= e.start && end <= e.start + e.size {
+                Some((addr, e))
+            } else {
+                None
+            }
+        })
+}
+
+fn setup_page_table(gpm: &GuestPhysMemorySet) -> RvmResult {
+    const PRESENT_WRITABLE: u64 = 0x3;
+    const HUGE_PAGE: u64 = 0x80;
+    let pml4 = BOOT_PAGE_TABLE_GPA;
+    let pdpt = pml4 + PAGE_SIZE;
+    let pd = pdpt + PAGE_SIZE;
+
+    let mut table = [0u64; 512];
+    table[0] = pdpt as u64 | PRESENT_WRITABLE;
+    gpm.write(pml4, as_bytes(&table))?;
+
+    let mut table = [0u64; 512];
+    for (i, entry) in table.iter_mut().take(BOOT_PAGE_TABLE_PD_COUNT).enumerate() {
+        *entry = (pd + i * PAGE_SIZE) as u64 | PRESENT_WRITABLE;
+    }
+    gpm.write(pdpt, as_bytes(&table))?;
+
+    for i in 0..BOOT_PAGE_TABLE_PD_COUNT {
+        for (j, entry) in table.iter_mut().enumerate() {
+            let paddr = ((i * 512 + j) as u64) << 21;
+            *entry = paddr | PRESENT_WRITABLE | HUGE_PAGE;
+        }
+        gpm.write(pd + i * PAGE_SIZE, as_bytes(&table))?;
+    }
+    Ok(())
+}
+
+/// Load the Linux kernel `image` and `initrd` into the guest memory, fill the
+/// boot parameters with the memory map of `gpm` and `cmdline`, then set the
+/// vCPU to start at the 64-bit entry point of the kernel.
+pub fn setup_linux_boot(
+    gpm: &GuestPhysMemorySet,
+    vcpu: &mut Vcpu,
+    image: &[u8],
+    initrd: &[u8],
+    cmdline: &str,
+) -> RvmResult {
+    let hdr = setup_header(image).ok_or(RvmError::InvalidParam)?;
+    let (version, xloadflags) = (hdr.version, hdr.xloadflags);
+    if version < MIN_PROTOCOL_VERSION || xloadflags & XLF_KERNEL_64 == 0 {
+        warn!(
+            "Unsupported Linux boot protocol {:#x}, xloadflags={:#x}",
+            version, xloadflags
+        );
+        return Err(RvmError::Unsupported);
+    }
+
+    // the protected-mode kernel follows the real-mode setup code
+    let setup_sects = if hdr.setup_sects == 0 {
+        4
+    } else {
+        hdr.setup_sects as usize
+    };
+    let kernel_offset = (setup_sects + 1) * 512;
+    let kernel_size = hdr.syssize as usize * 16;
+    if image.len() < kernel_offset + kernel_size {
+        warn!(
+            "Linux kernel image is truncated: {:#x} < {:#x}",
+            image.len(),
+            kernel_offset + kernel_size
+        );
+        return Err(RvmError::InvalidParam);
+    }
+
+    let mmap = guest_memory_map(gpm);
+    let load_size = (hdr.init_size as usize).max(kernel_size);
+    let (load_addr, ram) = kernel_placement(
+        &mmap,
+        hdr.relocatable_kernel != 0,
+        hdr.kernel_alignment as usize,
+        hdr.pref_address as usize,
+        load_size,
+    )
+    .ok_or_else(|| {
+        warn!(
+            "No guest RAM region can hold the Linux kernel ({:#x} bytes)",
+            load_size
+        );
+        RvmError::OutOfMemory
+    })?;
+    gpm.write(
+        load_addr,
+        &image[kernel_offset..kernel_offset + kernel_size],
+    )?;
+
+    let mut params: BootParams = unsafe { core::mem::zeroed() };
+    // copy the setup header as is, it ends at the offset given by the jump instruction
+    let hdr_end =
+        (0x202 + image[0x201] as usize).min(SETUP_HEADER_OFFSET + size_of::<SetupHeader>());
+    let params_bytes = unsafe {
+        core::slice::from_raw_parts_mut(&mut params as *mut _ as *mut u8, size_of::<BootParams>())
+    };
+    params_bytes[SETUP_HEADER_OFFSET..hdr_end]
+        .copy_from_slice(&image[SETUP_HEADER_OFFSET..hdr_end]);
+
+    params.hdr.type_of_loader = TYPE_OF_LOADER_UNDEFINED;
+    params.hdr.code32_start = load_addr as u32;
+
+    let cmdline_size = (hdr.cmdline_size as usize).min(PAGE_SIZE);
+    if cmdline.len() >= cmdline_size {
+        warn!("Linux command line is too long: {} bytes", cmdline.len());
+        return Err(RvmError::InvalidParam);
+    }
+    gpm.write(BOOT_CMDLINE_GPA, cmdline.as_bytes())?;
+    gpm.write(BOOT_CMDLINE_GPA + cmdline.len(), &[0])?;
+    params.hdr.cmd_line_ptr = BOOT_CMDLINE_GPA as u32;
+
+    if !initrd.is_empty() {
+        // put the initrd at the top of the kernel's RAM region below `initrd_addr_max`
+        let top = (ram.start + ram.size).min(hdr.initrd_addr_max as usize + 1);
+        let initrd_addr = top.saturating_sub(initrd.len()) & !(PAGE_SIZE - 1);
+        if initrd_addr < load_addr + load_size {
+            warn!("Guest memory is too small to load the initrd");
+            return Err(RvmError::OutOfMemory);
+        }
+        gpm.write(initrd_addr, initrd)?;
+        params.hdr.ramdisk_image = initrd_addr as u32;
+        params.hdr.ramdisk_size = initrd.len() as u32;
+    }
+
+    if mmap.len() > E820_MAX_ENTRIES {
+        return Err(RvmError::InvalidParam);
+    }
+    for (i, e) in mmap.iter().enumerate() {
+        params.e820_table[i] = E820Entry {
+            addr: e.start as u64,
+            size: e.size as u64,
+            mem_type: e.mem_type as u32,
+        };
+    }
+    params.e820_entries = mmap.len() as u8;
+    gpm.write(BOOT_PARAMS_GPA, as_bytes(&params))?;
+
+    gpm.write(BOOT_GDT_GPA, as_bytes(&BOOT_GDT))?;
+    setup_page_table(gpm)?;
+
+    vcpu.set_long_mode(&LongModeState {
+        rip: (load_addr + STARTUP_64_OFFSET) as u64,
+        rsp: BOOT_STACK_TOP as u64,
+        cr3: BOOT_PAGE_TABLE_GPA as u64,
+        gdt_base: BOOT_GDT_GPA as u64,
+        gdt_limit: (size_of::<[u64; 4]>() - 1) as u16,
+        code_selector: BOOT_CS,
+        data_selector: BOOT_DS,
+    })?;
+    vcpu.regs_mut().rsi = BOOT_PARAMS_GPA as u64;
+    info!(
+        "Linux boot protocol {:#x}: kernel @ {:#x}, initrd @ {:#x} ({:#x} bytes)",
+        version,
+        load_addr,
+        { params.hdr.ramdisk_image },
+        initrd.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! Boot protocols to pass the boot information to the guest.
+
+pub mod linux;
+pub mod multiboot;
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use rvm::{GuestPhysAddr, MemFlags};
+
+use super::gpm::GuestPhysMemorySet;
+
+/// The legacy VGA memory and BIOS ROM area, never reported as usable RAM.
+const LEGACY_HOLE: Range<GuestPhysAddr> = 0xa_0000..0x10_0000;
+
+/// Types of guest physical memory regions, the same as the E820 memory types.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Usable RAM.
+    Ram = 1,
+    /// Reserved, including device memory.
+    Reserved = 2,
+}
+
+/// An entry in the guest physical memory map.
+#[derive(Debug)]
+pub struct MemoryMapEntry {
+    pub start: GuestPhysAddr,
+    pub size: usize,
+    pub mem_type: MemoryType,
+}
+
+/// Build the guest physical memory map from mapped regions in `gpm`. Device
+/// regions and the legacy hole are reported as reserved.
+pub fn guest_memory_map(gpm: &GuestPhysMemorySet) -> Vec<MemoryMapEntry> {
+    let mut map = Vec::new();
+    let mut push = |start: usize, end: usize, mem_type| {
+        if start < end {
+            map.push(MemoryMapEntry {
+                start,
+                size: end - start,
+                mem_type,
+            });
+        }
+    };
+    for r in gpm.regions() {
+        let (start, end) = (r.start, r.start + r.size);
+        if r.flags.contains(MemFlags::DEVICE) {
+            push(start, end, MemoryType::Reserved);
+        } else {
+            let (hole_start, hole_end) = (LEGACY_HOLE.start, LEGACY_HOLE.end);
+            push(start, end.min(hole_start), MemoryType::Ram);
+            push(
+                start.max(hole_start),
+                end.min(hole_end),
+                MemoryType::Reserved,
+            );
+            push(start.max(hole_end), end, MemoryType::Ram);
+        }
+    }
+    map
+}
@@ -0,0 +1,128 @@
+//! Construct the Multiboot information structure for the guest.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use rvm::{GuestPhysAddr, RvmError, RvmResult};
+
+use super::{guest_memory_map, MemoryType};
+use crate::hv::gpm::GuestPhysMemorySet;
+use crate::mm::PAGE_SIZE;
+use crate::multiboot::{InfoFlags, MemoryMapEntry, ModuleEntry, MultibootInfo};
+
+const BOOT_LOADER_NAME: &str = "RVM";
+const LOWER_MEMORY_END: usize = 0xa_0000;
+const UPPER_MEMORY_START: usize = 0x10_0000;
+
+/// An image loaded into the guest memory and passed as a Multiboot module.
+pub struct GuestModule<'a> {
+    pub gpa: GuestPhysAddr,
+    pub size: usize,
+    pub cmdline: &'a str,
+}
+
+/// The contents of the boot information page, all pointers in it are guest
+/// physical addresses.
+struct InfoPage {
+    base: GuestPhysAddr,
+    buf: Vec<u8>,
+}
+
+impl InfoPage {
+    fn new(base: GuestPhysAddr) -> Self {
+        Self {
+            base,
+            buf: Vec::with_capacity(PAGE_SIZE),
+        }
+    }
+
+    fn push_bytes(&mut self, data: &[u8], align: usize) -> u32 {
+        let offset = (self.buf.len() + align - 1) & !(align - 1);
+        self.buf.resize(offset, 0);
+        self.buf.extend_from_slice(data);
+        (self.base + offset) as u32
+    }
+
+    fn push<T: Copy>(&mut self, value: &T) -> u32 {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        self.push_bytes(bytes, 4)
+    }
+
+    fn push_str(&mut self, s: &str) -> u32 {
+        let ret = self.push_bytes(s.as_bytes(), 1);
+        self.buf.push(0);
+        ret
+    }
+}
+
+/// Build the Multiboot information structure at `info_gpa` with the memory map
+/// of `gpm`, the command line and modules. The guest receives `info_gpa` in `EBX`.
+pub fn setup_multiboot_info(
+    gpm: &GuestPhysMemorySet,
+    info_gpa: GuestPhysAddr,
+    cmdline: &str,
+    modules: &[GuestModule],
+) -> RvmResult {
+    let mut page = InfoPage::new(info_gpa);
+    let mut info = MultibootInfo {
+        flags: (InfoFlags::MEMORY
+            | InfoFlags::CMDLINE
+            | InfoFlags::MODS
+            | InfoFlags::MEM_MAP
+            | InfoFlags::BOOT_LOADER_NAME)
+            .bits(),
+        ..Default::default()
+    };
+    page.push(&info); // fill it later
+
+    let mmap = guest_memory_map(gpm);
+    for entry in &mmap {
+        if entry.mem_type != MemoryType::Ram {
+            continue;
+        }
+        if entry.start == 0 {
+            info.mem_lower = (entry.size.min(LOWER_MEMORY_END) / 1024) as u32;
+        } else if entry.start == UPPER_MEMORY_START {
+            info.mem_upper = (entry.size / 1024) as u32;
+        }
+    }
+    for (i, entry) in mmap.iter().enumerate() {
+        let entry = MemoryMapEntry::new(entry.start as _, entry.size as _, entry.mem_type as _);
+        let addr = page.push(&entry);
+        if i == 0 {
+            info.mmap_addr = addr;
+        }
+    }
+    info.mmap_length = (mmap.len() * size_of::<MemoryMapEntry>()) as u32;
+
+    let mod_strings: Vec<u32> = modules.iter().map(|m| page.push_str(m.cmdline)).collect();
+    info.mods_count = modules.len() as u32;
+    for (i, (m, &string)) in modules.iter().zip(mod_strings.iter()).enumerate() {
+        let addr = page.push(&ModuleEntry {
+            mod_start: m.gpa as u32,
+            mod_end: (m.gpa + m.size) as u32,
+            string,
+            reserved: 0,
+        });
+        if i == 0 {
+            info.mods_addr = addr;
+        }
+    }
+
+    info.cmdline = page.push_str(cmdline);
+    info.boot_loader_name = page.push_str(BOOT_LOADER_NAME);
+
+    if page.buf.len() > PAGE_SIZE {
+        warn!(
+            "Multiboot information is too large: {:#x} > {:#x}",
+            page.buf.len(),
+            PAGE_SIZE
+        );
+        return Err(RvmError::InvalidParam);
+    }
+    page.buf[..size_of::<MultibootInfo>()].copy_from_slice(unsafe {
+        core::slice::from_raw_parts(&info as *const _ as *const u8, size_of::<MultibootInfo>())
+    });
+    gpm.write(info_gpa, &page.buf)
+}
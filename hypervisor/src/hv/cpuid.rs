@@ -0,0 +1,353 @@
+//! Per-VM CPUID table, generated from the CPUID policy and the overrides in
+//! the guest configuration.
+//!
+//! The `host` policy passes through the host leaves, except the features not
+//! virtualized by the hypervisor, such as VMX, TSC-deadline, the PMU, MPX and
+//! PKU. `XSETBV` is not emulated, so XSAVE and the features with states
+//! managed by it (AVX, AVX-512, AMX, CET, ...) are not reported either, and
+//! the guest uses `FXSAVE` for the SSE states. The `x86-64-v2` policy only
+//! reports the features of the x86-64-v2 microarchitecture level (SSE4.2,
+//! POPCNT, CMPXCHG16B, ...), to run the same guest on different hosts.
+//!
+//! The topology is always a single thread per core and a single core per
+//! package, with the local APIC ID of the vCPU reported in leaves 1, 0xB and
+//! 0x1F. Leaves not in the table return all zeros.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use raw_cpuid::{cpuid, CpuIdResult};
+
+use super::gconfig::{CpuidOverride, CpuidPolicy};
+
+const LEAF_VENDOR_INFO: u32 = 0x0;
+const LEAF_FEATURE_INFO: u32 = 0x1;
+const LEAF_CACHE_PARAMS: u32 = 0x4;
+const LEAF_THERMAL_POWER: u32 = 0x6;
+const LEAF_EXT_FEATURES: u32 = 0x7;
+const LEAF_PMU: u32 = 0xa;
+const LEAF_XSAVE: u32 = 0xd;
+const LEAF_TOPOLOGY: u32 = 0xb;
+const LEAF_V2_TOPOLOGY: u32 = 0x1f;
+const LEAF_HYPERVISOR_INFO: u32 = 0x4000_0000;
+const LEAF_HYPERVISOR_FEATURE: u32 = 0x4000_0001;
+const LEAF_EXT_MAX: u32 = 0x8000_0000;
+const LEAF_EXT_FEATURE_INFO: u32 = 0x8000_0001;
+
+/// Leaves indexed by the subleaf in `ECX`.
+const INDEXED_LEAVES: &[u32] = &[
+    0x4,
+    0x7,
+    0xb,
+    0xd,
+    0xf,
+    0x10,
+    0x12,
+    0x14,
+    0x17,
+    0x18,
+    0x1d,
+    0x1e,
+    0x1f,
+    0x20,
+    0x8000_001d,
+];
+const MAX_SUBLEAVES: u32 = 64;
+/// Leaves above are not reported by the `host` policy.
+const MAX_BASIC_LEAF: u32 = 0x1f;
+const MAX_EXT_LEAF: u32 = 0x8000_0008;
+const V2_MAX_BASIC_LEAF: u32 = 0xb;
+
+const VENDOR_STR: &[u8; 12] = b"RVMRVMRVMRVM";
+
+// Leaf 1 ECX
+const FEATURE_DTES64: u32 = 1 << 2;
+const FEATURE_MONITOR: u32 = 1 << 3;
+const FEATURE_DS_CPL: u32 = 1 << 4;
+const FEATURE_VMX: u32 = 1 << 5;
+const FEATURE_SMX: u32 = 1 << 6;
+const FEATURE_EIST: u32 = 1 << 7;
+const FEATURE_TM2: u32 = 1 << 8;
+const FEATURE_PDCM: u32 = 1 << 15;
+const FEATURE_FMA: u32 = 1 << 12;
+const FEATURE_TSC_DEADLINE: u32 = 1 << 24;
+const FEATURE_XSAVE: u32 = 1 << 26;
+const FEATURE_OSXSAVE: u32 = 1 << 27;
+const FEATURE_AVX: u32 = 1 << 28;
+const FEATURE_F16C: u32 = 1 << 29;
+const FEATURE_HYPERVISOR: u32 = 1 << 31;
+const UNSUPPORTED_FEATURES_ECX: u32 = FEATURE_DTES64
+    | FEATURE_MONITOR
+    | FEATURE_DS_CPL
+    | FEATURE_VMX
+    | FEATURE_SMX
+    | FEATURE_EIST
+    | FEATURE_TM2
+    | FEATURE_PDCM
+    | FEATURE_TSC_DEADLINE
+    | FEATURE_XSAVE
+    | FEATURE_OSXSAVE
+    | FEATURE_FMA
+    | FEATURE_AVX
+    | FEATURE_F16C;
+/// SSE3, SSSE3, CMPXCHG16B, SSE4.1, SSE4.2, x2APIC and POPCNT.
+const V2_FEATURES_ECX: u32 =
+    (1 << 0) | (1 << 9) | (1 << 13) | (1 << 19) | (1 << 20) | (1 << 21) | (1 << 23);
+
+// Leaf 1 EDX
+const FEATURE_DS: u32 = 1 << 21;
+const FEATURE_ACPI: u32 = 1 << 22;
+const FEATURE_HTT: u32 = 1 << 28;
+const FEATURE_TM: u32 = 1 << 29;
+const FEATURE_PBE: u32 = 1 << 31;
+const UNSUPPORTED_FEATURES_EDX: u32 =
+    FEATURE_DS | FEATURE_ACPI | FEATURE_HTT | FEATURE_TM | FEATURE_PBE;
+/// FPU, VME, DE, PSE, TSC, MSR, PAE, MCE, CX8, APIC, SEP, MTRR, PGE, MCA, CMOV,
+/// PAT, PSE-36, CLFSH, MMX, FXSR, SSE and SSE2.
+const V2_FEATURES_EDX: u32 = 0x078b_fbff;
+
+// Leaf 7 subleaf 0
+const EXT_FEATURE_SGX: u32 = 1 << 2;
+const EXT_FEATURE_RDT_M: u32 = 1 << 12;
+const EXT_FEATURE_MPX: u32 = 1 << 14;
+const EXT_FEATURE_RDT_A: u32 = 1 << 15;
+const EXT_FEATURE_INTEL_PT: u32 = 1 << 25;
+/// AVX2, AVX512F, AVX512DQ, AVX512_IFMA, AVX512PF, AVX512ER, AVX512CD,
+/// AVX512BW and AVX512VL.
+const EXT_FEATURES_AVX_EBX: u32 = (1 << 5)
+    | (1 << 16)
+    | (1 << 17)
+    | (1 << 21)
+    | (1 << 26)
+    | (1 << 27)
+    | (1 << 28)
+    | (1 << 30)
+    | (1 << 31);
+const UNSUPPORTED_EXT_FEATURES_EBX: u32 = EXT_FEATURE_SGX
+    | EXT_FEATURE_RDT_M
+    | EXT_FEATURE_MPX
+    | EXT_FEATURE_RDT_A
+    | EXT_FEATURE_INTEL_PT
+    | EXT_FEATURES_AVX_EBX;
+const EXT_FEATURE_PKU: u32 = 1 << 3;
+const EXT_FEATURE_OSPKE: u32 = 1 << 4;
+const EXT_FEATURE_WAITPKG: u32 = 1 << 5;
+const EXT_FEATURE_SGX_LC: u32 = 1 << 30;
+/// AVX512_VBMI, CET_SS, AVX512_VBMI2, VAES, VPCLMULQDQ, AVX512_VNNI,
+/// AVX512_BITALG and AVX512_VPOPCNTDQ.
+const EXT_FEATURES_AVX_ECX: u32 =
+    (1 << 1) | (1 << 6) | (1 << 7) | (1 << 9) | (1 << 10) | (1 << 11) | (1 << 12) | (1 << 14);
+const UNSUPPORTED_EXT_FEATURES_ECX: u32 = EXT_FEATURE_PKU
+    | EXT_FEATURE_OSPKE
+    | EXT_FEATURE_WAITPKG
+    | EXT_FEATURE_SGX_LC
+    | EXT_FEATURES_AVX_ECX;
+/// AVX512_4VNNIW, AVX512_4FMAPS, AVX512_VP2INTERSECT, CET_IBT, AMX_BF16,
+/// AVX512_FP16, AMX_TILE and AMX_INT8.
+const UNSUPPORTED_EXT_FEATURES_EDX: u32 =
+    (1 << 2) | (1 << 3) | (1 << 8) | (1 << 20) | (1 << 22) | (1 << 23) | (1 << 24) | (1 << 25);
+// Leaf 7 subleaf 1
+/// AVX_VNNI and AVX512_BF16.
+const UNSUPPORTED_EXT_FEATURES_1_EAX: u32 = (1 << 4) | (1 << 5);
+
+// Leaf 0x8000_0001
+/// XOP and FMA4.
+const UNSUPPORTED_EXT_FEATURE_INFO_ECX: u32 = (1 << 11) | (1 << 16);
+/// LAHF/SAHF.
+const V2_EXT_FEATURES_ECX: u32 = 1 << 0;
+/// SYSCALL, NX and LM.
+const V2_EXT_FEATURES_EDX: u32 = (1 << 11) | (1 << 20) | (1 << 29);
+
+/// Level types of the topology leaves.
+const TOPOLOGY_LEVEL_SMT: u32 = 1;
+const TOPOLOGY_LEVEL_CORE: u32 = 2;
+
+const ZERO: CpuIdResult = CpuIdResult {
+    eax: 0,
+    ebx: 0,
+    ecx: 0,
+    edx: 0,
+};
+
+fn is_indexed(leaf: u32) -> bool {
+    INDEXED_LEAVES.contains(&leaf)
+}
+
+pub struct CpuidTable {
+    /// Results keyed by the leaf and subleaf, the subleaf is 0 if the leaf is
+    /// not indexed.
+    entries: BTreeMap<(u32, u32), CpuIdResult>,
+    overrides: Vec<CpuidOverride>,
+}
+
+impl CpuidTable {
+    /// Generate the table from the host CPUID by `policy`, the `overrides` are
+    /// applied when the leaves are queried.
+    pub fn new(policy: CpuidPolicy, overrides: &[CpuidOverride]) -> Self {
+        let mut table = Self {
+            entries: BTreeMap::new(),
+            overrides: overrides.to_vec(),
+        };
+        let max_basic = match policy {
+            CpuidPolicy::Host => MAX_BASIC_LEAF,
+            CpuidPolicy::X86_64V2 => V2_MAX_BASIC_LEAF,
+        };
+        let max_basic = cpuid!(LEAF_VENDOR_INFO).eax.min(max_basic);
+        let max_ext = cpuid!(LEAF_EXT_MAX).eax.min(MAX_EXT_LEAF);
+        for leaf in (0..=max_basic).chain(LEAF_EXT_MAX..=max_ext) {
+            if is_indexed(leaf) {
+                for subleaf in 0..MAX_SUBLEAVES {
+                    table.insert_host(leaf, subleaf);
+                }
+            } else {
+                table.insert_host(leaf, 0);
+            }
+        }
+        table.entry(LEAF_VENDOR_INFO, 0).eax = max_basic;
+        table.entry(LEAF_EXT_MAX, 0).eax = max_ext;
+
+        table.mask_unsupported();
+        if policy == CpuidPolicy::X86_64V2 {
+            table.mask_to_v2();
+        }
+        table.set_topology(max_basic);
+
+        let mut vendor_regs = VENDOR_STR
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        table.entries.insert(
+            (LEAF_HYPERVISOR_INFO, 0),
+            CpuIdResult {
+                eax: LEAF_HYPERVISOR_FEATURE,
+                ebx: vendor_regs.next().unwrap(),
+                ecx: vendor_regs.next().unwrap(),
+                edx: vendor_regs.next().unwrap(),
+            },
+        );
+        table.entries.insert((LEAF_HYPERVISOR_FEATURE, 0), ZERO);
+        table
+    }
+
+    /// The result of CPUID(`leaf`, `subleaf`) on the vCPU with the local APIC
+    /// ID `apic_id`.
+    pub fn lookup(&self, leaf: u32, subleaf: u32, apic_id: u32) -> CpuIdResult {
+        let subleaf = if is_indexed(leaf) { subleaf } else { 0 };
+        let mut res = self.entries.get(&(leaf, subleaf)).copied().unwrap_or(ZERO);
+        match leaf {
+            LEAF_FEATURE_INFO => {
+                res.ebx = (res.ebx & 0x00ff_ffff) | (apic_id & 0xff) << 24;
+            }
+            LEAF_TOPOLOGY | LEAF_V2_TOPOLOGY if self.entries.contains_key(&(leaf, 0)) => {
+                // levels after the last one are invalid, with the subleaf in ECX[7:0]
+                res.ecx |= subleaf & 0xff;
+                res.edx = apic_id;
+            }
+            _ => {}
+        }
+        // the last override matching the leaf and subleaf
+        if let Some(o) = self
+            .overrides
+            .iter()
+            .rev()
+            .find(|c| c.leaf == leaf && c.subleaf.map_or(true, |s| s == subleaf))
+        {
+            let dst = [&mut res.eax, &mut res.ebx, &mut res.ecx, &mut res.edx];
+            for (i, reg) in dst.into_iter().enumerate() {
+                if let Some(value) = o.regs[i] {
+                    *reg = value;
+                }
+                *reg = (*reg & !o.clear[i]) | o.set[i];
+            }
+        }
+        res
+    }
+
+    fn insert_host(&mut self, leaf: u32, subleaf: u32) {
+        let res = cpuid!(leaf, subleaf);
+        if subleaf == 0 || res != ZERO {
+            self.entries.insert((leaf, subleaf), res);
+        }
+    }
+
+    fn entry(&mut self, leaf: u32, subleaf: u32) -> &mut CpuIdResult {
+        self.entries.entry((leaf, subleaf)).or_insert(ZERO)
+    }
+
+    /// Remove the indexed leaf, or make it all zeros.
+    fn clear_leaf(&mut self, leaf: u32) {
+        self.entries.retain(|&(l, _), _| l != leaf);
+        if leaf
+            <= self
+                .entries
+                .get(&(LEAF_VENDOR_INFO, 0))
+                .map_or(0, |r| r.eax)
+        {
+            self.entries.insert((leaf, 0), ZERO);
+        }
+    }
+
+    /// Hide the host features not virtualized by the hypervisor.
+    fn mask_unsupported(&mut self) {
+        let res = self.entry(LEAF_FEATURE_INFO, 0);
+        res.ecx &= !UNSUPPORTED_FEATURES_ECX;
+        res.ecx |= FEATURE_HYPERVISOR;
+        res.edx &= !UNSUPPORTED_FEATURES_EDX;
+        if let Some(res) = self.entries.get_mut(&(LEAF_EXT_FEATURES, 0)) {
+            res.ebx &= !UNSUPPORTED_EXT_FEATURES_EBX;
+            res.ecx &= !UNSUPPORTED_EXT_FEATURES_ECX;
+            res.edx &= !UNSUPPORTED_EXT_FEATURES_EDX;
+        }
+        if let Some(res) = self.entries.get_mut(&(LEAF_EXT_FEATURES, 1)) {
+            res.eax &= !UNSUPPORTED_EXT_FEATURES_1_EAX;
+        }
+        if let Some(res) = self.entries.get_mut(&(LEAF_EXT_FEATURE_INFO, 0)) {
+            res.ecx &= !UNSUPPORTED_EXT_FEATURE_INFO_ECX;
+        }
+        for leaf in [LEAF_THERMAL_POWER, LEAF_PMU, LEAF_XSAVE, 0x12, 0x14] {
+            self.clear_leaf(leaf);
+        }
+    }
+
+    /// Only keep the features of the x86-64-v2 level.
+    fn mask_to_v2(&mut self) {
+        let res = self.entry(LEAF_FEATURE_INFO, 0);
+        res.ecx &= V2_FEATURES_ECX | FEATURE_HYPERVISOR;
+        res.edx &= V2_FEATURES_EDX;
+        self.clear_leaf(LEAF_EXT_FEATURES);
+        if let Some(res) = self.entries.get_mut(&(LEAF_EXT_FEATURE_INFO, 0)) {
+            res.ecx &= V2_EXT_FEATURES_ECX;
+            res.edx &= V2_EXT_FEATURES_EDX;
+        }
+    }
+
+    /// Report a single thread per core and a single core per package.
+    fn set_topology(&mut self, max_basic: u32) {
+        let res = self.entry(LEAF_FEATURE_INFO, 0);
+        res.ebx = (res.ebx & !0x00ff_0000) | 1 << 16; // logical processors per package
+        for res in self
+            .entries
+            .range_mut((LEAF_CACHE_PARAMS, 0)..(LEAF_CACHE_PARAMS + 1, 0))
+            .map(|(_, r)| r)
+        {
+            res.eax &= 0x3fff; // cores and threads sharing the cache
+        }
+        for leaf in [LEAF_TOPOLOGY, LEAF_V2_TOPOLOGY] {
+            if leaf > max_basic {
+                continue;
+            }
+            self.entries.retain(|&(l, _), _| l != leaf);
+            let levels = [TOPOLOGY_LEVEL_SMT, TOPOLOGY_LEVEL_CORE];
+            for (subleaf, level) in levels.into_iter().enumerate() {
+                let subleaf = subleaf as u32;
+                self.entries.insert(
+                    (leaf, subleaf),
+                    CpuIdResult {
+                        eax: 0, // no bits to shift for the next level
+                        ebx: 1, // one logical processor at this level
+                        ecx: level << 8 | subleaf,
+                        edx: 0, // the x2APIC ID, set on lookups
+                    },
+                );
+            }
+        }
+    }
+}
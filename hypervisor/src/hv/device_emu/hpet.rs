@@ -0,0 +1,319 @@
+//! Emulated High Precision Event Timer. (ref: IA-PC HPET Specification 1.0a)
+//!
+//! The main counter runs at 100 MHz and is computed from the host time. The
+//! comparators are checked on VM exits, so interrupts may be delivered later
+//! than the exact time, and missed periods are coalesced.
+
+use alloc::sync::Arc;
+
+use rvm::{GuestPhysAddr, RvmError, RvmHal, RvmResult};
+use spin::Mutex;
+
+use super::{IsaIrqLines, MmioDevice};
+use crate::hv::hal::RvmHalImpl;
+
+pub const HPET_BASE: GuestPhysAddr = 0xfed0_0000;
+const HPET_MMIO_SIZE: usize = 0x1000;
+const HPET_NUM_TIMERS: usize = 3;
+/// Period of the main counter in femtoseconds (10 ns).
+const HPET_PERIOD_FS: u64 = 10_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+
+const GCAP_ID: usize = 0x000;
+const GEN_CONF: usize = 0x010;
+const GINTR_STA: usize = 0x020;
+const MAIN_COUNTER: usize = 0x0f0;
+const TIMER_BASE: usize = 0x100;
+const TIMER_SIZE: usize = 0x20;
+const TN_CONF_CAP: usize = 0x00;
+const TN_COMPARATOR: usize = 0x08;
+
+const GCAP_REV_ID: u64 = 0x01;
+const GCAP_COUNT_SIZE_64: u64 = 1 << 13;
+const GCAP_LEG_RT: u64 = 1 << 15;
+const GCAP_VENDOR_ID: u64 = 0x8086 << 16;
+
+const CONF_ENABLE: u64 = 1 << 0;
+const CONF_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TN_INT_LEVEL: u64 = 1 << 1;
+const TN_INT_ENABLE: u64 = 1 << 2;
+const TN_PERIODIC: u64 = 1 << 3;
+const TN_PERIODIC_CAP: u64 = 1 << 4;
+const TN_SIZE_64_CAP: u64 = 1 << 5;
+const TN_VAL_SET: u64 = 1 << 6;
+const TN_32BIT_MODE: u64 = 1 << 8;
+const TN_INT_ROUTE_SHIFT: u64 = 9;
+const TN_INT_ROUTE_MASK: u64 = 0x1f << TN_INT_ROUTE_SHIFT;
+/// ISA IRQs that can be routed to, all except 0-2 and 8 used by the PIT,
+/// the cascade and the RTC.
+const TN_INT_ROUTE_CAP: u64 = 0xfef8 << 32;
+const TN_WRITABLE_BITS: u64 =
+    TN_INT_LEVEL | TN_INT_ENABLE | TN_PERIODIC | TN_VAL_SET | TN_32BIT_MODE | TN_INT_ROUTE_MASK;
+
+/// IRQs of timer 0 and 1 in the legacy replacement mode, replacing the PIT
+/// and the RTC.
+const LEGACY_IRQS: [u8; 2] = [0, 8];
+
+fn nanos_to_ticks(nanos: u64) -> u64 {
+    (nanos as u128 * FS_PER_NS as u128 / HPET_PERIOD_FS as u128) as u64
+}
+
+#[derive(Default)]
+struct HpetTimer {
+    config: u64,
+    comparator: u64,
+    period: u64,
+    /// A one-shot timer has fired since the comparator was set.
+    fired: bool,
+    /// The level of the IRQ line driven by a level triggered timer.
+    irq_asserted: bool,
+}
+
+impl HpetTimer {
+    fn counter_mask(&self) -> u64 {
+        if self.config & TN_32BIT_MODE != 0 {
+            0xffff_ffff
+        } else {
+            u64::MAX
+        }
+    }
+
+    /// Whether `counter` has reached the comparator, the counter may wrap
+    /// around in the 32-bit mode.
+    fn reached(&self, counter: u64) -> bool {
+        let mask = self.counter_mask();
+        (counter.wrapping_sub(self.comparator) & mask) <= mask >> 1
+    }
+
+    /// Check the comparator, and advance it in the periodic mode. Returns
+    /// whether the timer fires.
+    fn check(&mut self, counter: u64) -> bool {
+        if !self.reached(counter) {
+            return false;
+        }
+        if self.config & TN_PERIODIC != 0 {
+            if self.period != 0 {
+                let mask = self.counter_mask();
+                let periods = ((counter.wrapping_sub(self.comparator) & mask) / self.period) + 1;
+                self.comparator = self.comparator.wrapping_add(periods * self.period) & mask;
+            }
+            true
+        } else if !self.fired {
+            self.fired = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn write_config(&mut self, value: u64) {
+        self.config = (self.config & !TN_WRITABLE_BITS) | (value & TN_WRITABLE_BITS);
+        if self.config & TN_32BIT_MODE != 0 {
+            self.comparator &= 0xffff_ffff;
+            self.period &= 0xffff_ffff;
+        }
+    }
+
+    fn write_comparator(&mut self, value: u64) {
+        let value = value & self.counter_mask();
+        if self.config & TN_PERIODIC == 0 || self.config & TN_VAL_SET != 0 {
+            self.comparator = value;
+        }
+        self.period = value;
+        self.config &= !TN_VAL_SET;
+        self.fired = false;
+    }
+}
+
+struct HpetState {
+    config: u64,
+    /// Level triggered interrupts status.
+    int_status: u64,
+    /// The main counter when it's stopped, or when it was last started.
+    counter_base: u64,
+    /// The host time in nanoseconds when the main counter was last started.
+    start_time: u64,
+    timers: [HpetTimer; HPET_NUM_TIMERS],
+}
+
+impl HpetState {
+    fn is_timer_reg(offset: usize) -> bool {
+        (TIMER_BASE..TIMER_BASE + TIMER_SIZE * HPET_NUM_TIMERS).contains(&offset)
+    }
+
+    fn counter(&self, now: u64) -> u64 {
+        if self.config & CONF_ENABLE != 0 {
+            self.counter_base + nanos_to_ticks(now.saturating_sub(self.start_time))
+        } else {
+            self.counter_base
+        }
+    }
+
+    fn timer_irq(&self, index: usize) -> u8 {
+        if self.config & CONF_LEGACY_ROUTE != 0 && index < LEGACY_IRQS.len() {
+            LEGACY_IRQS[index]
+        } else {
+            ((self.timers[index].config & TN_INT_ROUTE_MASK) >> TN_INT_ROUTE_SHIFT) as u8
+        }
+    }
+
+    fn read_reg(&self, offset: usize, now: u64) -> u64 {
+        match offset {
+            GCAP_ID => {
+                HPET_PERIOD_FS << 32
+                    | GCAP_VENDOR_ID
+                    | GCAP_LEG_RT
+                    | GCAP_COUNT_SIZE_64
+                    | ((HPET_NUM_TIMERS as u64 - 1) << 8)
+                    | GCAP_REV_ID
+            }
+            GEN_CONF => self.config,
+            GINTR_STA => self.int_status,
+            MAIN_COUNTER => self.counter(now),
+            _ if Self::is_timer_reg(offset) => {
+                let timer = &self.timers[(offset - TIMER_BASE) / TIMER_SIZE];
+                match (offset - TIMER_BASE) % TIMER_SIZE {
+                    TN_CONF_CAP => {
+                        timer.config | TN_INT_ROUTE_CAP | TN_SIZE_64_CAP | TN_PERIODIC_CAP
+                    }
+                    TN_COMPARATOR => timer.comparator,
+                    _ => 0, // FSB interrupts are not supported
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: usize, value: u64, now: u64) {
+        match offset {
+            GEN_CONF => {
+                // the counter is kept when started or stopped
+                self.counter_base = self.counter(now);
+                self.start_time = now;
+                self.config = value & (CONF_ENABLE | CONF_LEGACY_ROUTE);
+            }
+            GINTR_STA => self.int_status &= !value, // write 1 to clear
+            MAIN_COUNTER => {
+                if self.config & CONF_ENABLE != 0 {
+                    warn!("HPET main counter written while running");
+                }
+                self.counter_base = value;
+                self.start_time = now;
+            }
+            _ if Self::is_timer_reg(offset) => {
+                let timer = &mut self.timers[(offset - TIMER_BASE) / TIMER_SIZE];
+                match (offset - TIMER_BASE) % TIMER_SIZE {
+                    TN_CONF_CAP => timer.write_config(value),
+                    TN_COMPARATOR => timer.write_comparator(value),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct Hpet {
+    base: GuestPhysAddr,
+    state: Mutex<HpetState>,
+    irq_lines: Arc<IsaIrqLines>,
+}
+
+impl MmioDevice for Hpet {
+    fn mmio_range(&self) -> core::ops::Range<GuestPhysAddr> {
+        self.base..self.base + HPET_MMIO_SIZE
+    }
+
+    fn read(&self, addr: GuestPhysAddr, access_size: u8) -> RvmResult<u64> {
+        let offset = addr - self.base;
+        if !matches!(access_size, 4 | 8) || offset % access_size as usize != 0 {
+            error!("Invalid HPET read @ {:#x}, size {}", offset, access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        let now = RvmHalImpl::current_time_nanos();
+        let value = self.state.lock().read_reg(offset & !7, now);
+        Ok(match (access_size, offset & 4) {
+            (8, _) => value,
+            (_, 0) => value & 0xffff_ffff,
+            _ => value >> 32,
+        })
+    }
+
+    fn write(&self, addr: GuestPhysAddr, access_size: u8, value: u64) -> RvmResult {
+        let offset = addr - self.base;
+        if !matches!(access_size, 4 | 8) || offset % access_size as usize != 0 {
+            error!("Invalid HPET write @ {:#x}, size {}", offset, access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        let now = RvmHalImpl::current_time_nanos();
+        let mut state = self.state.lock();
+        let value = match (access_size, offset & 4) {
+            (8, _) => value,
+            (_, shift) => {
+                // merge a 32-bit write into the 64-bit register, except for the
+                // write-1-to-clear status register
+                let old = match offset & !7 {
+                    GINTR_STA => 0,
+                    reg => state.read_reg(reg, now),
+                };
+                let shift = shift * 8;
+                (old & !(0xffff_ffff << shift)) | (value & 0xffff_ffff) << shift
+            }
+        };
+        state.write_reg(offset & !7, value, now);
+        drop(state);
+        self.update_irq();
+        Ok(())
+    }
+}
+
+impl Hpet {
+    pub fn new(base: GuestPhysAddr, irq_lines: Arc<IsaIrqLines>) -> Self {
+        Self {
+            base,
+            state: Mutex::new(HpetState {
+                config: 0,
+                int_status: 0,
+                counter_base: 0,
+                start_time: 0,
+                timers: Default::default(),
+            }),
+            irq_lines,
+        }
+    }
+
+    /// Whether timer 0 and 1 replace the PIT and RTC interrupts.
+    pub fn legacy_mode(&self) -> bool {
+        self.state.lock().config & CONF_LEGACY_ROUTE != 0
+    }
+
+    /// Check the comparators against the main counter, and drive the IRQ
+    /// lines of the timers that fired.
+    pub fn update_irq(&self) {
+        let now = RvmHalImpl::current_time_nanos();
+        let mut state = self.state.lock();
+        let counter = state.counter(now);
+        let enabled = state.config & CONF_ENABLE != 0;
+        for i in 0..HPET_NUM_TIMERS {
+            let irq = state.timer_irq(i);
+            let timer = &mut state.timers[i];
+            let fired = enabled && timer.check(counter);
+            let config = timer.config;
+            if config & TN_INT_LEVEL != 0 {
+                if fired {
+                    state.int_status |= 1 << i;
+                }
+                let level = state.int_status & (1 << i) != 0 && config & TN_INT_ENABLE != 0;
+                let timer = &mut state.timers[i];
+                if level != timer.irq_asserted {
+                    timer.irq_asserted = level;
+                    self.irq_lines.set_level(irq, level);
+                }
+            } else if fired && config & TN_INT_ENABLE != 0 {
+                self.irq_lines.set_level(irq, true);
+                self.irq_lines.set_level(irq, false);
+            }
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! Emulated Intel 8254 Programmable Interval Timer. (ref: https://wiki.osdev.org/Programmable_Interval_Timer)
+//!
+//! The counters are computed from the host time when read, rather than
+//! decremented on each input clock. Modes 0, 2 and 3 are supported, and the
+//! BCD counting flag is kept but ignored. The gate of channel 2 and its output
+//! are accessed via the system control port B (0x61), which is used by guests
+//! to calibrate their TSC.
+
+use alloc::sync::Arc;
+
+use super::{IrqLine, PortIoDevice};
+use crate::hv::hal::RvmHalImpl;
+use rvm::{RvmError, RvmHal, RvmResult};
+use spin::Mutex;
+
+pub const PIT_PORT_BASE: u16 = 0x40;
+pub const SYSTEM_CONTROL_PORT: u16 = 0x61;
+
+/// Frequency of the input clock.
+const PIT_FREQ_HZ: u64 = 1_193_182;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// Period of the DRAM refresh toggle bit in port 0x61.
+const REFRESH_PERIOD_NANOS: u64 = 15_085;
+
+const MODE_CONTROL_PORT: u16 = 3;
+const SELECT_READ_BACK: u8 = 3;
+const READ_BACK_NO_COUNT: u8 = 1 << 5;
+const READ_BACK_NO_STATUS: u8 = 1 << 4;
+const STATUS_OUTPUT: u8 = 1 << 7;
+const STATUS_NULL_COUNT: u8 = 1 << 6;
+
+const PORT61_GATE2: u8 = 1;
+const PORT61_SPEAKER_DATA: u8 = 1 << 1;
+const PORT61_REFRESH: u8 = 1 << 4;
+const PORT61_OUT2: u8 = 1 << 5;
+
+/// Which bytes of the counter are accessed next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AccessState {
+    Lsb,
+    Msb,
+    /// The LSB of a 16-bit access.
+    Word0,
+    /// The MSB of a 16-bit access.
+    Word1,
+}
+
+impl AccessState {
+    fn from_bits(rw: u8) -> Self {
+        match rw {
+            1 => Self::Lsb,
+            2 => Self::Msb,
+            _ => Self::Word0,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Self::Lsb => 1,
+            Self::Msb => 2,
+            Self::Word0 | Self::Word1 => 3,
+        }
+    }
+}
+
+struct Channel {
+    /// The reload value, 0 is treated as 0x10000.
+    count: u32,
+    /// The host time in nanoseconds when counting started.
+    load_time: u64,
+    mode: u8,
+    bcd: bool,
+    gate: bool,
+    access: AccessState,
+    read_state: AccessState,
+    write_state: AccessState,
+    /// The LSB written in a 16-bit access.
+    write_latch: u8,
+    latched_count: u16,
+    count_latched: Option<AccessState>,
+    latched_status: Option<u8>,
+    null_count: bool,
+    /// Periods already signaled to the IRQ line.
+    irq_periods: u64,
+}
+
+impl Channel {
+    const fn new(gate: bool) -> Self {
+        Self {
+            count: 0x10000,
+            load_time: 0,
+            mode: 0,
+            bcd: false,
+            gate,
+            access: AccessState::Word0,
+            read_state: AccessState::Word0,
+            write_state: AccessState::Word0,
+            write_latch: 0,
+            latched_count: 0,
+            count_latched: None,
+            latched_status: None,
+            null_count: true,
+            irq_periods: 0,
+        }
+    }
+
+    /// Input clocks elapsed since counting started.
+    fn elapsed(&self, now: u64) -> u64 {
+        let nanos = now.saturating_sub(self.load_time);
+        (nanos as u128 * PIT_FREQ_HZ as u128 / NANOS_PER_SEC as u128) as u64
+    }
+
+    fn current_count(&self, now: u64) -> u16 {
+        let d = self.elapsed(now);
+        let count = self.count as u64;
+        let value = match self.mode {
+            2 => count - d % count,
+            3 => count - (2 * d) % count,
+            _ => count.wrapping_sub(d),
+        };
+        value as u16
+    }
+
+    fn output(&self, now: u64) -> bool {
+        let d = self.elapsed(now);
+        let count = self.count as u64;
+        match self.mode {
+            2 => d % count != count - 1,
+            3 => d % count < (count + 1) / 2,
+            _ => !self.null_count && d >= count,
+        }
+    }
+
+    fn status(&self, now: u64) -> u8 {
+        let mut status = self.access.bits() << 4 | self.mode << 1 | self.bcd as u8;
+        if self.output(now) {
+            status |= STATUS_OUTPUT;
+        }
+        if self.null_count {
+            status |= STATUS_NULL_COUNT;
+        }
+        status
+    }
+
+    fn load_count(&mut self, value: u16, now: u64) {
+        self.count = if value == 0 { 0x10000 } else { value as u32 };
+        self.load_time = now;
+        self.null_count = false;
+        self.irq_periods = 0;
+    }
+
+    fn latch_count(&mut self, now: u64) {
+        if self.count_latched.is_none() {
+            self.latched_count = self.current_count(now);
+            self.count_latched = Some(self.access);
+        }
+    }
+
+    fn latch_status(&mut self, now: u64) {
+        if self.latched_status.is_none() {
+            self.latched_status = Some(self.status(now));
+        }
+    }
+
+    fn set_mode(&mut self, access: u8, mode: u8, bcd: bool) -> RvmResult {
+        // modes 6 and 7 are aliases of 2 and 3
+        let mode = if mode > 5 { mode & 3 } else { mode };
+        if !matches!(mode, 0 | 2 | 3) {
+            warn!("Unsupported 8254 PIT mode: {}", mode);
+            return Err(RvmError::Unsupported);
+        }
+        self.access = AccessState::from_bits(access);
+        self.read_state = self.access;
+        self.write_state = self.access;
+        self.mode = mode;
+        self.bcd = bcd;
+        self.null_count = true;
+        self.count_latched = None;
+        Ok(())
+    }
+
+    fn set_gate(&mut self, gate: bool, now: u64) {
+        // a rising edge restarts counting in the periodic modes
+        if !self.gate && gate && matches!(self.mode, 2 | 3) {
+            self.load_time = now;
+            self.irq_periods = 0;
+        }
+        self.gate = gate;
+    }
+
+    fn read(&mut self, now: u64) -> u8 {
+        if let Some(status) = self.latched_status.take() {
+            return status;
+        }
+        if let Some(state) = self.count_latched {
+            let [lsb, msb] = self.latched_count.to_le_bytes();
+            return match state {
+                AccessState::Lsb => {
+                    self.count_latched = None;
+                    lsb
+                }
+                AccessState::Msb => {
+                    self.count_latched = None;
+                    msb
+                }
+                AccessState::Word0 => {
+                    self.count_latched = Some(AccessState::Word1);
+                    lsb
+                }
+                AccessState::Word1 => {
+                    self.count_latched = None;
+                    msb
+                }
+            };
+        }
+        let [lsb, msb] = self.current_count(now).to_le_bytes();
+        match self.read_state {
+            AccessState::Lsb => lsb,
+            AccessState::Msb => msb,
+            AccessState::Word0 => {
+                self.read_state = AccessState::Word1;
+                lsb
+            }
+            AccessState::Word1 => {
+                self.read_state = AccessState::Word0;
+                msb
+            }
+        }
+    }
+
+    fn write(&mut self, value: u8, now: u64) {
+        match self.write_state {
+            AccessState::Lsb => self.load_count(value as u16, now),
+            AccessState::Msb => self.load_count((value as u16) << 8, now),
+            AccessState::Word0 => {
+                self.write_latch = value;
+                self.write_state = AccessState::Word1;
+            }
+            AccessState::Word1 => {
+                self.load_count(u16::from_le_bytes([self.write_latch, value]), now);
+                self.write_state = AccessState::Word0;
+            }
+        }
+    }
+}
+
+/// The three channels of the PIT, and the speaker enable bit of port 0x61.
+pub struct PitState {
+    channels: [Channel; 3],
+    speaker_data: bool,
+}
+
+impl PitState {
+    pub const fn new() -> Self {
+        Self {
+            // only the gate of channel 2 is controllable
+            channels: [Channel::new(true), Channel::new(true), Channel::new(false)],
+            speaker_data: false,
+        }
+    }
+
+    fn write_control(&mut self, value: u8, now: u64) -> RvmResult {
+        let select = value >> 6;
+        if select == SELECT_READ_BACK {
+            for (i, ch) in self.channels.iter_mut().enumerate() {
+                if value & (2 << i) == 0 {
+                    continue;
+                }
+                if value & READ_BACK_NO_COUNT == 0 {
+                    ch.latch_count(now);
+                }
+                if value & READ_BACK_NO_STATUS == 0 {
+                    ch.latch_status(now);
+                }
+            }
+            return Ok(());
+        }
+        let ch = &mut self.channels[select as usize];
+        match (value >> 4) & 3 {
+            0 => ch.latch_count(now),
+            access => ch.set_mode(access, (value >> 1) & 7, value & 1 != 0)?,
+        }
+        Ok(())
+    }
+
+    fn read_port61(&self, now: u64) -> u8 {
+        let ch2 = &self.channels[2];
+        let mut value = 0;
+        if ch2.gate {
+            value |= PORT61_GATE2;
+        }
+        if self.speaker_data {
+            value |= PORT61_SPEAKER_DATA;
+        }
+        if (now / REFRESH_PERIOD_NANOS) & 1 != 0 {
+            value |= PORT61_REFRESH;
+        }
+        if ch2.output(now) {
+            value |= PORT61_OUT2;
+        }
+        value
+    }
+
+    fn write_port61(&mut self, value: u8, now: u64) {
+        self.channels[2].set_gate(value & PORT61_GATE2 != 0, now);
+        self.speaker_data = value & PORT61_SPEAKER_DATA != 0;
+    }
+}
+
+/// The I/O ports of the PIT channels or the system control port B.
+pub struct I8254Pit {
+    port_base: u16,
+    state: Arc<Mutex<PitState>>,
+    /// Connected to the output of channel 0.
+    irq: Option<IrqLine>,
+}
+
+impl PortIoDevice for I8254Pit {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        match self.port_base {
+            PIT_PORT_BASE => PIT_PORT_BASE..PIT_PORT_BASE + 4,
+            _ => self.port_base..self.port_base + 1,
+        }
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> RvmResult<u32> {
+        if access_size != 1 {
+            error!("Invalid 8254 PIT I/O read size: {} != 1", access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        let now = RvmHalImpl::current_time_nanos();
+        let mut state = self.state.lock();
+        let ret = match (self.port_base, port - self.port_base) {
+            (PIT_PORT_BASE, MODE_CONTROL_PORT) => 0xff, // write only
+            (PIT_PORT_BASE, ch) => state.channels[ch as usize].read(now),
+            (SYSTEM_CONTROL_PORT, 0) => state.read_port61(now),
+            _ => return Err(RvmError::InvalidParam),
+        };
+        Ok(ret as u32)
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> RvmResult {
+        if access_size != 1 {
+            error!("Invalid 8254 PIT I/O write size: {} != 1", access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        let value = value as u8;
+        let now = RvmHalImpl::current_time_nanos();
+        let mut state = self.state.lock();
+        match (self.port_base, port - self.port_base) {
+            (PIT_PORT_BASE, MODE_CONTROL_PORT) => state.write_control(value, now)?,
+            (PIT_PORT_BASE, ch) => state.channels[ch as usize].write(value, now),
+            (SYSTEM_CONTROL_PORT, 0) => state.write_port61(value, now),
+            _ => return Err(RvmError::InvalidParam),
+        }
+        drop(state);
+        self.update_irq();
+        Ok(())
+    }
+}
+
+impl I8254Pit {
+    pub const fn new(port_base: u16, state: Arc<Mutex<PitState>>, irq: Option<IrqLine>) -> Self {
+        Self {
+            port_base,
+            state,
+            irq,
+        }
+    }
+
+    /// Drive the IRQ line by the output of channel 0. In the periodic modes, a
+    /// pulse is generated if any period elapsed since the last call, so the
+    /// missed ones are coalesced.
+    pub fn update_irq(&self) {
+        let Some(irq) = &self.irq else {
+            return;
+        };
+        let now = RvmHalImpl::current_time_nanos();
+        let mut state = self.state.lock();
+        let ch = &mut state.channels[0];
+        if ch.null_count {
+            irq.set_level(false);
+            return;
+        }
+        match ch.mode {
+            2 | 3 => {
+                let periods = ch.elapsed(now) / ch.count as u64;
+                if periods > ch.irq_periods {
+                    ch.irq_periods = periods;
+                    irq.set_level(true);
+                    irq.set_level(false);
+                }
+            }
+            _ => irq.set_level(ch.output(now)),
+        }
+    }
+}
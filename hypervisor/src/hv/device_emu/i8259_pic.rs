@@ -1,10 +1,351 @@
 //! Emulated Intel 8259 Programmable Interrupt Controller. (ref: https://wiki.osdev.org/8259_PIC)
+//!
+//! The master and slave PICs are cascaded via IRQ2 of the master, and the
+//! ELCR registers select edge or level triggered mode of each IRQ.
+
+use alloc::sync::Arc;
 
 use super::PortIoDevice;
 use rvm::{RvmError, RvmResult};
+use spin::Mutex;
+
+pub const MASTER_PORT_BASE: u16 = 0x20;
+pub const SLAVE_PORT_BASE: u16 = 0xa0;
+pub const ELCR_PORT_BASE: u16 = 0x4d0;
+
+/// The IRQ of the master where the slave is connected.
+const CASCADE_IRQ: u8 = 2;
+/// Bits of ELCR that can be set, IRQ 0, 1, 2, 8 and 13 are always edge triggered.
+const MASTER_ELCR_MASK: u8 = 0xf8;
+const SLAVE_ELCR_MASK: u8 = 0xde;
+
+const ICW1_ICW4_NEEDED: u8 = 1;
+const ICW1_SINGLE: u8 = 1 << 1;
+const ICW1_LEVEL_TRIGGERED: u8 = 1 << 3;
+const ICW1_INIT: u8 = 1 << 4;
+const ICW4_AUTO_EOI: u8 = 1 << 1;
+const ICW4_SPECIAL_FULLY_NESTED: u8 = 1 << 4;
+const OCW3_SELECT: u8 = 1 << 3;
+const OCW3_READ_ISR: u8 = 1;
+const OCW3_READ_REG: u8 = 1 << 1;
+const OCW3_POLL: u8 = 1 << 2;
+const OCW3_SPECIAL_MASK: u8 = 1 << 5;
+const OCW3_SET_SPECIAL_MASK: u8 = 1 << 6;
+
+/// Steps of the initialization sequence, waiting for the next ICW.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InitState {
+    Ready,
+    Icw2,
+    Icw3,
+    Icw4,
+}
+
+/// A single 8259 chip.
+struct Pic {
+    is_master: bool,
+    irr: u8,
+    isr: u8,
+    imr: u8,
+    /// Line levels in the last `set_irq`, to detect rising edges.
+    last_levels: u8,
+    /// Level triggered IRQs.
+    elcr: u8,
+    elcr_mask: u8,
+    vector_base: u8,
+    /// The IRQ with the highest priority is `priority_add`.
+    priority_add: u8,
+    init_state: InitState,
+    icw4_needed: bool,
+    single: bool,
+    auto_eoi: bool,
+    rotate_on_auto_eoi: bool,
+    special_fully_nested: bool,
+    special_mask: bool,
+    read_isr: bool,
+    poll: bool,
+}
+
+impl Pic {
+    const fn new(is_master: bool) -> Self {
+        Self {
+            is_master,
+            irr: 0,
+            isr: 0,
+            imr: 0,
+            last_levels: 0,
+            elcr: 0,
+            elcr_mask: if is_master {
+                MASTER_ELCR_MASK
+            } else {
+                SLAVE_ELCR_MASK
+            },
+            vector_base: 0,
+            priority_add: 0,
+            init_state: InitState::Ready,
+            icw4_needed: false,
+            single: false,
+            auto_eoi: false,
+            rotate_on_auto_eoi: false,
+            special_fully_nested: false,
+            special_mask: false,
+            read_isr: false,
+            poll: false,
+        }
+    }
+
+    /// Reset by ICW1, keeps ELCR and pending level triggered IRQs.
+    fn init_reset(&mut self) {
+        *self = Self {
+            irr: self.irr & self.elcr,
+            elcr: self.elcr,
+            ..Self::new(self.is_master)
+        };
+    }
+
+    fn set_irq(&mut self, irq: u8, level: bool) {
+        let mask = 1 << irq;
+        if self.elcr & mask != 0 {
+            if level {
+                self.irr |= mask;
+            } else {
+                self.irr &= !mask;
+            }
+        } else if level && self.last_levels & mask == 0 {
+            self.irr |= mask;
+        }
+        if level {
+            self.last_levels |= mask;
+        } else {
+            self.last_levels &= !mask;
+        }
+    }
+
+    /// The priority of the highest priority IRQ in `mask`, 0 is the highest
+    /// and 8 means none.
+    fn priority(&self, mask: u8) -> u8 {
+        if mask == 0 {
+            return 8;
+        }
+        let mut priority = 0;
+        while mask & (1 << ((priority + self.priority_add) & 7)) == 0 {
+            priority += 1;
+        }
+        priority
+    }
+
+    /// The IRQ to be requested to the CPU, if it has higher priority than
+    /// the ones in service.
+    fn pending_irq(&self) -> Option<u8> {
+        let priority = self.priority(self.irr & !self.imr);
+        if priority == 8 {
+            return None;
+        }
+        let mut in_service = self.isr;
+        if self.special_mask {
+            in_service &= !self.imr;
+        }
+        if self.is_master && self.special_fully_nested {
+            // the slave can interrupt even if IRQ2 is in service
+            in_service &= !(1 << CASCADE_IRQ);
+        }
+        if priority < self.priority(in_service) {
+            Some((priority + self.priority_add) & 7)
+        } else {
+            None
+        }
+    }
+
+    /// The CPU acknowledges `irq`.
+    fn ack(&mut self, irq: u8) {
+        let mask = 1 << irq;
+        if self.auto_eoi {
+            if self.rotate_on_auto_eoi {
+                self.priority_add = (irq + 1) & 7;
+            }
+        } else {
+            self.isr |= mask;
+        }
+        if self.elcr & mask == 0 {
+            self.irr &= !mask;
+        }
+    }
+
+    fn eoi(&mut self, irq: u8, rotate: bool) {
+        self.isr &= !(1 << irq);
+        if rotate {
+            self.priority_add = (irq + 1) & 7;
+        }
+    }
+
+    fn write_command(&mut self, value: u8) {
+        if value & ICW1_INIT != 0 {
+            self.init_reset();
+            self.init_state = InitState::Icw2;
+            self.icw4_needed = value & ICW1_ICW4_NEEDED != 0;
+            self.single = value & ICW1_SINGLE != 0;
+            if value & ICW1_LEVEL_TRIGGERED != 0 {
+                warn!("8259 PIC: level triggered mode by ICW1 is not supported, use ELCR");
+            }
+        } else if value & OCW3_SELECT != 0 {
+            if value & OCW3_POLL != 0 {
+                self.poll = true;
+            }
+            if value & OCW3_READ_REG != 0 {
+                self.read_isr = value & OCW3_READ_ISR != 0;
+            }
+            if value & OCW3_SET_SPECIAL_MASK != 0 {
+                self.special_mask = value & OCW3_SPECIAL_MASK != 0;
+            }
+        } else {
+            // OCW2
+            match value >> 5 {
+                0 | 4 => self.rotate_on_auto_eoi = value >> 7 != 0,
+                1 | 5 => {
+                    // non-specific EOI, clear the highest priority IRQ in service
+                    let priority = self.priority(self.isr);
+                    if priority != 8 {
+                        self.eoi((priority + self.priority_add) & 7, value >> 5 == 5);
+                    }
+                }
+                3 => self.eoi(value & 7, false),
+                6 => self.priority_add = (value + 1) & 7,
+                7 => self.eoi(value & 7, true),
+                _ => {} // no operation
+            }
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        match self.init_state {
+            InitState::Ready => self.imr = value, // OCW1
+            InitState::Icw2 => {
+                self.vector_base = value & 0xf8;
+                self.init_state = match (self.single, self.icw4_needed) {
+                    (false, _) => InitState::Icw3,
+                    (true, true) => InitState::Icw4,
+                    (true, false) => InitState::Ready,
+                };
+            }
+            InitState::Icw3 => {
+                // the cascade wiring is fixed
+                self.init_state = if self.icw4_needed {
+                    InitState::Icw4
+                } else {
+                    InitState::Ready
+                };
+            }
+            InitState::Icw4 => {
+                self.special_fully_nested = value & ICW4_SPECIAL_FULLY_NESTED != 0;
+                self.auto_eoi = value & ICW4_AUTO_EOI != 0;
+                self.init_state = InitState::Ready;
+            }
+        }
+    }
+
+    /// Poll command, acknowledges the pending IRQ as an interrupt does.
+    fn poll_read(&mut self) -> u8 {
+        self.poll = false;
+        match self.pending_irq() {
+            Some(irq) => {
+                self.ack(irq);
+                0x80 | irq
+            }
+            None => 0,
+        }
+    }
+
+    fn read_command(&mut self) -> u8 {
+        if self.poll {
+            self.poll_read()
+        } else if self.read_isr {
+            self.isr
+        } else {
+            self.irr
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        if self.poll {
+            self.poll_read()
+        } else {
+            self.imr
+        }
+    }
+}
+
+/// The cascaded master and slave PICs.
+pub struct I8259PicPair {
+    master: Pic,
+    slave: Pic,
+}
+
+impl I8259PicPair {
+    pub const fn new() -> Self {
+        Self {
+            master: Pic::new(true),
+            slave: Pic::new(false),
+        }
+    }
+
+    /// Propagate the slave output to the cascade IRQ of the master.
+    fn update_cascade(&mut self) {
+        let level = self.slave.pending_irq().is_some();
+        self.master.set_irq(CASCADE_IRQ, level);
+    }
+
+    /// Set the level of ISA `irq` (0 to 15).
+    pub fn set_irq(&mut self, irq: u8, level: bool) {
+        if irq < 8 {
+            self.master.set_irq(irq, level);
+        } else {
+            self.slave.set_irq(irq - 8, level);
+        }
+        self.update_cascade();
+    }
+
+    /// Update IRQ levels from the lines driven by devices, with rising edges
+    /// since the last sync.
+    pub fn sync_irq_lines(&mut self, edges: u16, levels: u16) {
+        for irq in 0..16 {
+            let mask = 1 << irq;
+            if edges & mask != 0 {
+                self.set_irq(irq, false);
+                self.set_irq(irq, true);
+            }
+            self.set_irq(irq, levels & mask != 0);
+        }
+    }
+
+    /// Whether the INTR output to the CPU is asserted.
+    pub fn has_interrupt(&self) -> bool {
+        self.master.pending_irq().is_some()
+    }
+
+    /// The CPU acknowledges the interrupt (INTA cycle), returns its vector.
+    pub fn ack_interrupt(&mut self) -> Option<u8> {
+        let irq = self.master.pending_irq()?;
+        self.master.ack(irq);
+        let vector = if irq == CASCADE_IRQ {
+            match self.slave.pending_irq() {
+                Some(irq) => {
+                    self.slave.ack(irq);
+                    self.slave.vector_base + irq
+                }
+                None => self.slave.vector_base + 7, // spurious IRQ15
+            }
+        } else {
+            self.master.vector_base + irq
+        };
+        self.update_cascade();
+        Some(vector)
+    }
+}
 
+/// The I/O ports of the master, slave or ELCR registers.
 pub struct I8259Pic {
     port_base: u16,
+    pair: Arc<Mutex<I8259PicPair>>,
 }
 
 impl PortIoDevice for I8259Pic {
@@ -12,17 +353,48 @@ impl PortIoDevice for I8259Pic {
         self.port_base..self.port_base + 2
     }
 
-    fn read(&self, _port: u16, _access_size: u8) -> RvmResult<u32> {
-        Err(RvmError::Unsupported) // report error for read
+    fn read(&self, port: u16, access_size: u8) -> RvmResult<u32> {
+        if access_size != 1 {
+            error!("Invalid 8259 PIC I/O read size: {} != 1", access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        let mut pair = self.pair.lock();
+        let ret = match (self.port_base, port - self.port_base) {
+            (MASTER_PORT_BASE, 0) => pair.master.read_command(),
+            (MASTER_PORT_BASE, 1) => pair.master.read_data(),
+            (SLAVE_PORT_BASE, 0) => pair.slave.read_command(),
+            (SLAVE_PORT_BASE, 1) => pair.slave.read_data(),
+            (ELCR_PORT_BASE, 0) => pair.master.elcr,
+            (ELCR_PORT_BASE, 1) => pair.slave.elcr,
+            _ => return Err(RvmError::InvalidParam),
+        };
+        pair.update_cascade();
+        Ok(ret as u32)
     }
 
-    fn write(&self, _port: u16, _access_size: u8, _value: u32) -> RvmResult {
-        Ok(()) // ignore write
+    fn write(&self, port: u16, access_size: u8, value: u32) -> RvmResult {
+        if access_size != 1 {
+            error!("Invalid 8259 PIC I/O write size: {} != 1", access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        let value = value as u8;
+        let mut pair = self.pair.lock();
+        match (self.port_base, port - self.port_base) {
+            (MASTER_PORT_BASE, 0) => pair.master.write_command(value),
+            (MASTER_PORT_BASE, 1) => pair.master.write_data(value),
+            (SLAVE_PORT_BASE, 0) => pair.slave.write_command(value),
+            (SLAVE_PORT_BASE, 1) => pair.slave.write_data(value),
+            (ELCR_PORT_BASE, 0) => pair.master.elcr = value & pair.master.elcr_mask,
+            (ELCR_PORT_BASE, 1) => pair.slave.elcr = value & pair.slave.elcr_mask,
+            _ => return Err(RvmError::InvalidParam),
+        }
+        pair.update_cascade();
+        Ok(())
     }
 }
 
 impl I8259Pic {
-    pub const fn new(port_base: u16) -> Self {
-        Self { port_base }
+    pub const fn new(port_base: u16, pair: Arc<Mutex<I8259PicPair>>) -> Self {
+        Self { port_base, pair }
     }
 }
@@ -0,0 +1,211 @@
+//! Emulated I/O APIC (82093AA). (ref: https://wiki.osdev.org/IOAPIC)
+//!
+//! The ISA IRQs are connected to the pins with the same numbers, as there are
+//! no interrupt source overrides reported to the guest. Interrupts are sent to
+//! the virtual local APIC of the only vCPU, whatever the destination is.
+
+use rvm::{GuestPhysAddr, RvmError, RvmResult};
+use spin::Mutex;
+
+use super::MmioDevice;
+
+pub const IOAPIC_BASE: GuestPhysAddr = 0xfec0_0000;
+const IOAPIC_MMIO_SIZE: usize = 0x1000;
+const IOAPIC_NUM_PINS: usize = 24;
+const IOAPIC_VERSION: u32 = 0x11;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOAPICARB: u32 = 0x02;
+const IOREDTBL: u32 = 0x10;
+
+const REDIR_VECTOR_MASK: u64 = 0xff;
+const REDIR_DELIVERY_MODE_SHIFT: u64 = 8;
+const REDIR_DELIVERY_STATUS: u64 = 1 << 12;
+const REDIR_REMOTE_IRR: u64 = 1 << 14;
+const REDIR_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+/// Bits that are not writable by the guest.
+const REDIR_RO_BITS: u64 = REDIR_DELIVERY_STATUS | REDIR_REMOTE_IRR;
+
+const DELIVERY_MODE_FIXED: u64 = 0;
+const DELIVERY_MODE_LOWEST_PRIORITY: u64 = 1;
+
+struct IoApicState {
+    ioregsel: u32,
+    id: u32,
+    redirtbl: [u64; IOAPIC_NUM_PINS],
+    /// Pending interrupts, the asserted lines for level triggered pins.
+    irr: u32,
+}
+
+impl IoApicState {
+    const fn new() -> Self {
+        Self {
+            ioregsel: 0,
+            id: 0,
+            redirtbl: [REDIR_MASKED; IOAPIC_NUM_PINS],
+            irr: 0,
+        }
+    }
+
+    fn read_reg(&self) -> u32 {
+        match self.ioregsel {
+            IOAPICID => self.id << 24,
+            IOAPICVER => ((IOAPIC_NUM_PINS as u32 - 1) << 16) | IOAPIC_VERSION,
+            IOAPICARB => self.id << 24,
+            reg if (IOREDTBL..IOREDTBL + 2 * IOAPIC_NUM_PINS as u32).contains(&reg) => {
+                let entry = self.redirtbl[((reg - IOREDTBL) / 2) as usize];
+                if reg % 2 == 0 {
+                    entry as u32
+                } else {
+                    (entry >> 32) as u32
+                }
+            }
+            reg => {
+                warn!("Read unknown I/O APIC register {:#x}", reg);
+                0
+            }
+        }
+    }
+
+    fn write_reg(&mut self, value: u32) {
+        match self.ioregsel {
+            IOAPICID => self.id = (value >> 24) & 0xf,
+            IOAPICVER | IOAPICARB => {} // read only
+            reg if (IOREDTBL..IOREDTBL + 2 * IOAPIC_NUM_PINS as u32).contains(&reg) => {
+                let entry = &mut self.redirtbl[((reg - IOREDTBL) / 2) as usize];
+                *entry = if reg % 2 == 0 {
+                    (*entry & (0xffff_ffff_0000_0000 | REDIR_RO_BITS))
+                        | (value as u64 & !REDIR_RO_BITS)
+                } else {
+                    (*entry & 0xffff_ffff) | (value as u64) << 32
+                };
+                if *entry & REDIR_LEVEL_TRIGGERED == 0 {
+                    *entry &= !REDIR_REMOTE_IRR;
+                }
+            }
+            reg => warn!("Write unknown I/O APIC register {:#x}", reg),
+        }
+    }
+
+    /// Update the pins from the ISA IRQ lines.
+    fn set_irq_lines(&mut self, edges: u16, levels: u16) {
+        for pin in 0..16 {
+            let bit = 1 << pin;
+            if self.redirtbl[pin] & REDIR_LEVEL_TRIGGERED != 0 {
+                if levels as u32 & bit != 0 {
+                    self.irr |= bit;
+                } else {
+                    self.irr &= !bit;
+                }
+            } else if edges as u32 & bit != 0 {
+                self.irr |= bit;
+            }
+        }
+    }
+
+    /// Send the pending interrupts of unmasked pins by `deliver`. A level
+    /// triggered pin is not sent again until its remote IRR is cleared by an
+    /// EOI.
+    fn service(&mut self, mut deliver: impl FnMut(u8, bool)) {
+        for pin in 0..IOAPIC_NUM_PINS {
+            let entry = &mut self.redirtbl[pin];
+            let bit = 1 << pin;
+            if self.irr & bit == 0 || *entry & REDIR_MASKED != 0 {
+                continue;
+            }
+            let level_triggered = *entry & REDIR_LEVEL_TRIGGERED != 0;
+            if level_triggered {
+                if *entry & REDIR_REMOTE_IRR != 0 {
+                    continue;
+                }
+                *entry |= REDIR_REMOTE_IRR;
+            } else {
+                self.irr &= !bit;
+            }
+            let vector = (*entry & REDIR_VECTOR_MASK) as u8;
+            match (*entry >> REDIR_DELIVERY_MODE_SHIFT) & 7 {
+                DELIVERY_MODE_FIXED | DELIVERY_MODE_LOWEST_PRIORITY => {
+                    deliver(vector, level_triggered)
+                }
+                mode => warn!("Unsupported I/O APIC delivery mode {} of pin {}", mode, pin),
+            }
+        }
+    }
+
+    /// Handle an EOI broadcast from the local APIC, clear the remote IRR of
+    /// level triggered pins with the vector.
+    fn eoi(&mut self, vector: u8) {
+        for entry in self.redirtbl.iter_mut() {
+            if *entry & REDIR_LEVEL_TRIGGERED != 0 && *entry & REDIR_VECTOR_MASK == vector as u64 {
+                *entry &= !REDIR_REMOTE_IRR;
+            }
+        }
+    }
+}
+
+pub struct VirtIoApic {
+    base: GuestPhysAddr,
+    state: Mutex<IoApicState>,
+}
+
+impl MmioDevice for VirtIoApic {
+    fn mmio_range(&self) -> core::ops::Range<GuestPhysAddr> {
+        self.base..self.base + IOAPIC_MMIO_SIZE
+    }
+
+    fn read(&self, addr: GuestPhysAddr, access_size: u8) -> RvmResult<u64> {
+        if access_size != 4 {
+            error!("Invalid I/O APIC read size: {} != 4", access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        let state = self.state.lock();
+        match addr - self.base {
+            IOREGSEL => Ok(state.ioregsel as u64),
+            IOWIN => Ok(state.read_reg() as u64),
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&self, addr: GuestPhysAddr, access_size: u8, value: u64) -> RvmResult {
+        if access_size != 4 {
+            error!("Invalid I/O APIC write size: {} != 4", access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        let mut state = self.state.lock();
+        match addr - self.base {
+            IOREGSEL => state.ioregsel = value as u32 & 0xff,
+            IOWIN => state.write_reg(value as u32),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl VirtIoApic {
+    pub const fn new(base: GuestPhysAddr) -> Self {
+        Self {
+            base,
+            state: Mutex::new(IoApicState::new()),
+        }
+    }
+
+    /// Update the pins from the ISA IRQ lines, and send the pending interrupts
+    /// to the local APIC by `deliver` with their vectors and whether they are
+    /// level triggered.
+    pub fn sync_irq_lines(&self, edges: u16, levels: u16, deliver: impl FnMut(u8, bool)) {
+        let mut state = self.state.lock();
+        state.set_irq_lines(edges, levels);
+        state.service(deliver);
+    }
+
+    /// Handle an EOI broadcast from the local APIC for a level triggered
+    /// interrupt `vector`.
+    pub fn eoi(&self, vector: u8) {
+        self.state.lock().eoi(vector);
+    }
+}
@@ -1,8 +1,15 @@
 //! Emulated Local APIC. (SDM Vol. 3A, Chapter 10)
+//!
+//! The registers are accessed via the MMIO page at `IA32_APIC_BASE` in the
+//! xAPIC mode, or via MSRs 0x800-0x83f in the x2APIC mode.
 
-#![allow(dead_code)]
+use alloc::sync::Arc;
 
-use rvm::{RvmError, RvmResult, RvmVcpu};
+use rvm::{GuestPhysAddr, RvmError, RvmResult, RvmVcpu};
+use spin::Mutex;
+
+use super::ioapic::VirtIoApic;
+use super::MsrDevice;
 
 type Vcpu = RvmVcpu<crate::hv::hal::RvmHalImpl>;
 
@@ -10,20 +17,28 @@ type Vcpu = RvmVcpu<crate::hv::hal::RvmHalImpl>;
 const APICID: u32 = 0x2;
 /// Version register.
 const VERSION: u32 = 0x3;
+/// Task Priority Register.
+const TPR: u32 = 0x8;
+/// Processor Priority Register.
+const PPR: u32 = 0xA;
 /// EOI register.
 const EOI: u32 = 0xB;
 /// Logical Destination Register.
 const LDR: u32 = 0xD;
+/// Destination Format Register, only in the xAPIC mode.
+const DFR: u32 = 0xE;
 /// Spurious Interrupt Vector register.
 const SIVR: u32 = 0xF;
+/// In-Service Register (bits 0-31).
+const ISR0: u32 = 0x10;
+/// Interrupt Request Register (bits 224-255).
+const IRR7: u32 = 0x27;
+/// Error Status Register.
+const ESR: u32 = 0x28;
 /// Interrupt Command register.
 const ICR: u32 = 0x30;
 /// LVT Timer Interrupt register.
 const LVT_TIMER: u32 = 0x32;
-/// LVT Thermal Sensor Interrupt register.
-const LVT_THERMAL: u32 = 0x33;
-/// LVT Performance Monitor register.
-const LVT_PMI: u32 = 0x34;
 /// LVT LINT0 register.
 const LVT_LINT0: u32 = 0x35;
 /// LVT LINT1 register.
@@ -36,59 +51,327 @@ const INIT_COUNT: u32 = 0x38;
 const CUR_COUNT: u32 = 0x39;
 /// Divide Configuration register.
 const DIV_CONF: u32 = 0x3E;
+/// Self IPI register, only in the x2APIC mode.
+const SELF_IPI: u32 = 0x3F;
+
+/// Interrupt Command register (bits 32-63), only in the xAPIC mode.
+const ICR_HIGH: u32 = 0x31;
+
+const XAPIC_MMIO_SIZE: usize = 0x1000;
+
+const SIVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_MODE_MASK: u32 = 0x700;
+const LVT_DELIVERY_NMI: u32 = 0x400;
+const LVT_DELIVERY_EXTINT: u32 = 0x700;
+
+const APIC_BASE_BSP: u64 = 1 << 8;
+const APIC_BASE_EXTD: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const DEFAULT_APIC_BASE: u64 = 0xfee0_0000 | APIC_BASE_ENABLE | APIC_BASE_BSP;
+
+/// Fields of the Interrupt Command Register. (SDM Vol. 3A, Section 10.6.1)
+const ICR_DELIVERY_MODE_SHIFT: u32 = 8;
+const ICR_DEST_LOGICAL: u32 = 1 << 11;
+const ICR_SHORTHAND_SHIFT: u32 = 18;
+
+const DELIVERY_MODE_FIXED: u32 = 0;
+const DELIVERY_MODE_LOWEST_PRIORITY: u32 = 1;
+const DELIVERY_MODE_NMI: u32 = 4;
+
+const SHORTHAND_NONE: u32 = 0;
+const SHORTHAND_SELF: u32 = 1;
+const SHORTHAND_ALL_INCLUDING_SELF: u32 = 2;
+
+/// Operating modes selected by `IA32_APIC_BASE`. (SDM Vol. 3A, Section 10.12.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    Disabled,
+    XApic,
+    X2Apic,
+}
 
-pub struct VirtLocalApic;
+impl ApicMode {
+    fn from_apic_base(apic_base: u64) -> Option<Self> {
+        match (
+            apic_base & APIC_BASE_ENABLE != 0,
+            apic_base & APIC_BASE_EXTD != 0,
+        ) {
+            (false, false) => Some(Self::Disabled),
+            (true, false) => Some(Self::XApic),
+            (true, true) => Some(Self::X2Apic),
+            (false, true) => None,
+        }
+    }
+}
+
+pub struct VirtLocalApic {
+    apic_base: Mutex<u64>,
+    /// Receives EOI broadcasts.
+    ioapic: Option<Arc<VirtIoApic>>,
+}
 
 impl VirtLocalApic {
     pub const fn msr_range() -> core::ops::Range<u32> {
         0x800..0x840
     }
 
-    pub fn rdmsr(vcpu: &mut Vcpu, msr: u32) -> RvmResult<u64> {
-        Self::read(vcpu, msr - 0x800)
+    pub fn new(ioapic: Option<Arc<VirtIoApic>>) -> Self {
+        Self {
+            apic_base: Mutex::new(DEFAULT_APIC_BASE),
+            ioapic,
+        }
     }
 
-    pub fn wrmsr(vcpu: &mut Vcpu, msr: u32, value: u64) -> RvmResult {
-        Self::write(vcpu, msr - 0x800, value)
+    pub fn apic_base(&self) -> u64 {
+        *self.apic_base.lock()
     }
-}
 
-impl VirtLocalApic {
-    fn read(vcpu: &mut Vcpu, offset: u32) -> RvmResult<u64> {
-        let apic_timer = vcpu.apic_timer_mut();
-        match offset {
-            SIVR => Ok(0x1ff), // SDM Vol. 3A, Section 10.9, Figure 10-23 (with Software Enable bit)
-            LVT_THERMAL | LVT_PMI | LVT_LINT0 | LVT_LINT1 | LVT_ERR => {
-                Ok(0x1_0000) // SDM Vol. 3A, Section 10.5.1, Figure 10-8 (with Mask bit)
+    pub fn mode(&self) -> ApicMode {
+        ApicMode::from_apic_base(self.apic_base()).unwrap()
+    }
+
+    /// Write `IA32_APIC_BASE`, returns an error if it should cause #GP for
+    /// reserved bits or invalid mode transitions. (SDM Vol. 3A, Section 10.12.5)
+    pub fn set_apic_base(&self, vcpu: &mut Vcpu, value: u64) -> RvmResult {
+        let mut apic_base = self.apic_base.lock();
+        if value & !(APIC_BASE_ADDR_MASK | APIC_BASE_ENABLE | APIC_BASE_EXTD | APIC_BASE_BSP) != 0 {
+            return Err(RvmError::InvalidParam);
+        }
+        let old = ApicMode::from_apic_base(*apic_base).unwrap();
+        let new = ApicMode::from_apic_base(value).ok_or(RvmError::InvalidParam)?;
+        match (old, new) {
+            (ApicMode::X2Apic, ApicMode::XApic) | (ApicMode::Disabled, ApicMode::X2Apic) => {
+                return Err(RvmError::InvalidParam);
             }
-            LVT_TIMER => Ok(apic_timer.lvt_timer() as u64),
-            INIT_COUNT => Ok(apic_timer.initial_count() as u64),
-            DIV_CONF => Ok(apic_timer.divide() as u64),
-            CUR_COUNT => Ok(apic_timer.current_counter() as u64),
-            _ => Err(RvmError::Unsupported),
+            _ => {}
+        }
+        if old != new {
+            debug!("Local APIC mode: {:?} -> {:?}", old, new);
+            if old == ApicMode::X2Apic || new == ApicMode::X2Apic {
+                vcpu.set_x2apic_mode(new == ApicMode::X2Apic)?;
+            }
+        }
+        // the BSP flag is read only
+        *apic_base = (value & !APIC_BASE_BSP) | (*apic_base & APIC_BASE_BSP);
+        Ok(())
+    }
+
+    /// The register page in the xAPIC mode.
+    pub fn xapic_mmio_range(&self) -> Option<core::ops::Range<GuestPhysAddr>> {
+        let apic_base = self.apic_base();
+        if ApicMode::from_apic_base(apic_base) == Some(ApicMode::XApic) {
+            let base = (apic_base & APIC_BASE_ADDR_MASK) as usize;
+            Some(base..base + XAPIC_MMIO_SIZE)
+        } else {
+            None
+        }
+    }
+
+    /// Read a register in the xAPIC page, which is at the offset of the
+    /// x2APIC MSR index multiplied by 16.
+    pub fn mmio_read(
+        &self,
+        vcpu: &mut Vcpu,
+        gpa: GuestPhysAddr,
+        access_size: u8,
+    ) -> RvmResult<u64> {
+        let reg = Self::mmio_reg(gpa, access_size)?;
+        self.read(vcpu, reg, false)
+    }
+
+    pub fn mmio_write(
+        &self,
+        vcpu: &mut Vcpu,
+        gpa: GuestPhysAddr,
+        access_size: u8,
+        value: u64,
+    ) -> RvmResult {
+        let reg = Self::mmio_reg(gpa, access_size)?;
+        self.write(vcpu, reg, value, false)
+    }
+
+    /// Handle an APIC-write VM exit, the value has been written to the
+    /// register at `offset` of the virtual-APIC page, only the side effects
+    /// are emulated.
+    pub fn apic_write(&self, vcpu: &mut Vcpu, offset: usize) -> RvmResult {
+        let x2apic = self.mode() == ApicMode::X2Apic;
+        let reg = (offset >> 4) as u32;
+        let lapic = vcpu.lapic();
+        let value = if reg == ICR && x2apic {
+            lapic.icr()
+        } else {
+            lapic.read_reg(reg) as u64
+        };
+        self.write(vcpu, reg, value, x2apic)
+    }
+
+    /// Enable the local APIC in the virtual wire mode, as set by the firmware:
+    /// the 8259 PIC is connected to LINT0 as ExtINT, and the NMI to LINT1.
+    /// (MultiProcessor Specification, Section 3.6.2.2)
+    pub fn setup_virtual_wire(&self, vcpu: &mut Vcpu) -> RvmResult {
+        let lapic = vcpu.lapic_mut();
+        lapic.set_svr(SIVR_ENABLE | 0xff);
+        lapic.set_lvt(LVT_LINT0, LVT_DELIVERY_EXTINT)?;
+        lapic.set_lvt(LVT_LINT1, LVT_DELIVERY_NMI)
+    }
+
+    /// Whether the INTR of the 8259 PIC reaches the vCPU: directly if the
+    /// local APIC is disabled, otherwise by an unmasked LINT0 in the ExtINT
+    /// delivery mode.
+    pub fn accepts_ext_int(&self, vcpu: &Vcpu) -> bool {
+        if self.mode() == ApicMode::Disabled {
+            return true;
+        }
+        let lvt = vcpu.lapic().lvt(LVT_LINT0);
+        lvt & LVT_MASKED == 0 && lvt & LVT_DELIVERY_MODE_MASK == LVT_DELIVERY_EXTINT
+    }
+
+    /// Notify the I/O APIC of the EOI of a level triggered interrupt.
+    pub fn eoi_broadcast(&self, vector: u8) {
+        if let Some(ioapic) = &self.ioapic {
+            ioapic.eoi(vector);
+        }
+    }
+}
+
+/// `IA32_APIC_BASE`, and the registers in the x2APIC mode.
+impl MsrDevice for VirtLocalApic {
+    fn rdmsr(&self, vcpu: &mut Vcpu, msr: u32) -> RvmResult<u64> {
+        if msr == x86::msr::IA32_APIC_BASE {
+            return Ok(self.apic_base());
+        }
+        if self.mode() != ApicMode::X2Apic {
+            // x2APIC MSRs are not accessible in other modes
+            return Err(RvmError::InvalidParam);
         }
+        self.read(vcpu, msr - 0x800, true)
     }
 
-    fn write(vcpu: &mut Vcpu, offset: u32, value: u64) -> RvmResult {
-        if offset != ICR && (value >> 32) != 0 {
+    fn wrmsr(&self, vcpu: &mut Vcpu, msr: u32, value: u64) -> RvmResult {
+        if msr == x86::msr::IA32_APIC_BASE {
+            return self.set_apic_base(vcpu, value);
+        }
+        if self.mode() != ApicMode::X2Apic {
+            return Err(RvmError::InvalidParam);
+        }
+        if msr - 0x800 != ICR && (value >> 32) != 0 {
             return Err(RvmError::InvalidParam); // all registers except ICR are 32-bits
         }
-        let apic_timer = vcpu.apic_timer_mut();
+        self.write(vcpu, msr - 0x800, value, true)
+    }
+}
+
+impl VirtLocalApic {
+    fn mmio_reg(gpa: GuestPhysAddr, access_size: u8) -> RvmResult<u32> {
+        let offset = gpa & (XAPIC_MMIO_SIZE - 1);
+        if access_size != 4 || offset & 0xf != 0 {
+            error!("Invalid xAPIC access @ {:#x}, size {}", offset, access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        Ok((offset >> 4) as u32)
+    }
+
+    fn read(&self, vcpu: &mut Vcpu, offset: u32, x2apic: bool) -> RvmResult<u64> {
+        let lapic = vcpu.lapic_mut();
+        let value = match offset {
+            ICR if x2apic => return Ok(lapic.icr()),
+            DFR | ICR_HIGH if x2apic => return Err(RvmError::InvalidParam),
+            APICID | VERSION | TPR | PPR | LDR | DFR | SIVR | ESR | ICR | ICR_HIGH => {
+                lapic.read_reg(offset)
+            }
+            ISR0..=IRR7 => lapic.read_reg(offset), // ISR, TMR and IRR
+            LVT_TIMER..=LVT_ERR => lapic.lvt(offset),
+            INIT_COUNT => lapic.timer().initial_count(),
+            DIV_CONF => lapic.timer().divide(),
+            CUR_COUNT => lapic.timer().current_counter(),
+            _ => return Err(RvmError::Unsupported),
+        };
+        Ok(value as u64)
+    }
+
+    fn write(&self, vcpu: &mut Vcpu, offset: u32, value: u64, x2apic: bool) -> RvmResult {
+        let lapic = vcpu.lapic_mut();
         match offset {
+            TPR => lapic.set_tpr(value as u32),
             EOI => {
                 if value != 0 {
-                    Err(RvmError::InvalidParam) // write a non-zero value causes #GP
+                    return Err(RvmError::InvalidParam); // write a non-zero value causes #GP
+                }
+                if let Some((vector, true)) = lapic.eoi() {
+                    self.eoi_broadcast(vector);
+                }
+            }
+            LDR | DFR | ICR_HIGH if x2apic => return Err(RvmError::InvalidParam),
+            LDR => lapic.set_ldr(value as u32),
+            DFR => lapic.set_dfr(value as u32),
+            SIVR => lapic.set_svr(value as u32),
+            ESR => lapic.update_esr(),
+            ICR => {
+                let high = if x2apic {
+                    (value >> 32) as u32
                 } else {
-                    Ok(())
+                    (lapic.icr() >> 32) as u32
+                };
+                lapic.set_icr(value as u32, high);
+                Self::send_ipi(vcpu, x2apic);
+            }
+            ICR_HIGH => {
+                let low = lapic.icr() as u32;
+                lapic.set_icr(low, value as u32 & 0xff00_0000);
+            }
+            SELF_IPI if x2apic => lapic.request_interrupt(value as u8, false),
+            LVT_TIMER..=LVT_ERR => lapic.set_lvt(offset, value as u32)?,
+            INIT_COUNT => lapic.timer_mut().set_initial_count(value as u32)?,
+            DIV_CONF => lapic.timer_mut().set_divide(value as u32)?,
+            _ => return Err(RvmError::Unsupported),
+        }
+        Ok(())
+    }
+
+    /// Send the IPI in ICR. Only fixed interrupts and NMIs to the vCPU itself are
+    /// supported, as there is only one vCPU.
+    fn send_ipi(vcpu: &mut Vcpu, x2apic: bool) {
+        let lapic = vcpu.lapic_mut();
+        let icr_full = lapic.icr();
+        let icr = icr_full as u32;
+        let vector = icr as u8;
+        let to_self = match (icr >> ICR_SHORTHAND_SHIFT) & 3 {
+            SHORTHAND_NONE => {
+                let dest = (icr_full >> 32) as u32;
+                let (dest, broadcast) = if x2apic {
+                    (dest, u32::MAX)
+                } else {
+                    (dest >> 24, 0xff)
+                };
+                if icr & ICR_DEST_LOGICAL == 0 {
+                    dest == broadcast || dest == lapic.id()
+                } else if x2apic {
+                    // cluster ID in bits 16-31, and a bitmap of the cluster in bits 0-15
+                    let ldr = lapic.read_reg(LDR);
+                    dest >> 16 == ldr >> 16 && dest & ldr & 0xffff != 0
+                } else {
+                    // only the flat model is supported
+                    dest & (lapic.read_reg(LDR) >> 24) != 0
                 }
             }
-            SIVR | LVT_THERMAL | LVT_PMI | LVT_LINT0 | LVT_LINT1 | LVT_ERR => {
-                Ok(()) // ignore these register writes
+            SHORTHAND_SELF | SHORTHAND_ALL_INCLUDING_SELF => true,
+            _ => false, // all excluding self
+        };
+        if !to_self {
+            return;
+        }
+        match (icr >> ICR_DELIVERY_MODE_SHIFT) & 7 {
+            DELIVERY_MODE_FIXED | DELIVERY_MODE_LOWEST_PRIORITY => {
+                trace!("Local APIC: self IPI vector {:#x}", vector);
+                lapic.request_interrupt(vector, false);
+            }
+            DELIVERY_MODE_NMI => {
+                trace!("Local APIC: self NMI");
+                vcpu.inject_nmi();
             }
-            LVT_TIMER => apic_timer.set_lvt_timer(value as u32),
-            INIT_COUNT => apic_timer.set_initial_count(value as u32),
-            DIV_CONF => apic_timer.set_divide(value as u32),
-            _ => Err(RvmError::Unsupported),
+            mode => warn!("Unsupported IPI delivery mode {}: ICR={:#x}", mode, icr),
         }
     }
 }
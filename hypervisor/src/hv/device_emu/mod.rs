@@ -1,10 +1,24 @@
+mod hpet;
+mod i8254_pit;
 mod i8259_pic;
+mod ioapic;
 mod lapic;
+mod power;
 mod uart16550;
 
 use alloc::{sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
 
-pub use self::lapic::VirtLocalApic;
+use rvm::{GuestPhysAddr, RvmError, RvmResult, RvmVcpu};
+use spin::Mutex;
+
+use super::gconfig::{DeviceConfig, DeviceKind};
+use super::msr::MsrTable;
+
+pub use self::lapic::{ApicMode, VirtLocalApic};
+pub use self::power::{PowerEvent, DEBUG_EXIT_PORT};
+
+type Vcpu = RvmVcpu<super::hal::RvmHalImpl>;
 
 pub trait PortIoDevice: Send + Sync {
     fn port_range(&self) -> core::ops::Range<u16>;
@@ -12,28 +26,329 @@ pub trait PortIoDevice: Send + Sync {
     fn write(&self, port: u16, access_size: u8, value: u32) -> rvm::RvmResult;
 }
 
+pub trait MmioDevice: Send + Sync {
+    fn mmio_range(&self) -> core::ops::Range<GuestPhysAddr>;
+    fn read(&self, addr: GuestPhysAddr, access_size: u8) -> RvmResult<u64>;
+    fn write(&self, addr: GuestPhysAddr, access_size: u8, value: u64) -> RvmResult;
+}
+
+/// A device emulating MSRs. Returns [`RvmError::InvalidParam`] if the access
+/// should cause `#GP(0)`.
+pub trait MsrDevice: Send + Sync {
+    fn rdmsr(&self, vcpu: &mut Vcpu, msr: u32) -> RvmResult<u64>;
+    fn wrmsr(&self, vcpu: &mut Vcpu, msr: u32, value: u64) -> RvmResult;
+}
+
+/// Levels of the legacy ISA IRQ lines (0 to 15) driven by emulated devices.
+#[derive(Default)]
+pub struct IsaIrqLines {
+    levels: AtomicU16,
+    /// Rising edges not yet seen by the interrupt controllers.
+    edges: AtomicU16,
+}
+
+impl IsaIrqLines {
+    pub fn set_level(&self, irq: u8, level: bool) {
+        let bit = 1 << irq;
+        if level {
+            if self.levels.fetch_or(bit, Ordering::SeqCst) & bit == 0 {
+                self.edges.fetch_or(bit, Ordering::SeqCst);
+            }
+        } else {
+            self.levels.fetch_and(!bit, Ordering::SeqCst);
+        }
+    }
+
+    pub fn levels(&self) -> u16 {
+        self.levels.load(Ordering::SeqCst)
+    }
+
+    /// Returns and clears the rising edges since the last call.
+    pub fn take_edges(&self) -> u16 {
+        self.edges.swap(0, Ordering::SeqCst)
+    }
+}
+
+/// An ISA IRQ line connected to a device.
+#[derive(Clone)]
+pub struct IrqLine {
+    lines: Arc<IsaIrqLines>,
+    irq: u8,
+}
+
+impl IrqLine {
+    pub fn set_level(&self, level: bool) {
+        self.lines.set_level(self.irq, level);
+    }
+}
+
 pub struct VirtDeviceList {
     port_io_devices: Vec<Arc<dyn PortIoDevice>>,
+    mmio_devices: Vec<Arc<dyn MmioDevice>>,
+    /// The serial port connected to the host console.
+    console: Option<Arc<uart16550::Uart16550>>,
+    pic: Option<Arc<Mutex<i8259_pic::I8259PicPair>>>,
+    pit: Option<Arc<i8254_pit::I8254Pit>>,
+    ioapic: Option<Arc<ioapic::VirtIoApic>>,
+    hpet: Option<Arc<hpet::Hpet>>,
+    lapic: Arc<VirtLocalApic>,
+    irq_lines: Arc<IsaIrqLines>,
+    power: Arc<power::PowerControl>,
 }
 
 impl VirtDeviceList {
+    /// Create emulated devices from the guest configuration.
+    pub fn new(configs: &[DeviceConfig]) -> RvmResult<Self> {
+        let mut port_io_devices: Vec<Arc<dyn PortIoDevice>> = Vec::new();
+        let mut mmio_devices: Vec<Arc<dyn MmioDevice>> = Vec::new();
+        let mut console = None;
+        let mut pic = None;
+        let mut pit = None;
+        let mut ioapic = None;
+        let mut hpet = None;
+        let irq_lines = Arc::new(IsaIrqLines::default());
+        let power = Arc::new(power::PowerControl::default());
+        for config in configs {
+            let irq = match config.irq {
+                Some(irq) if irq < 16 => Some(IrqLine {
+                    lines: irq_lines.clone(),
+                    irq,
+                }),
+                Some(irq) => {
+                    warn!("Invalid ISA IRQ {} for {:?}", irq, config.kind);
+                    return Err(RvmError::InvalidParam);
+                }
+                None => None,
+            };
+            let port = || {
+                config.port.ok_or_else(|| {
+                    warn!("No I/O port configured for {:?}", config.kind);
+                    RvmError::InvalidParam
+                })
+            };
+            let devs: Vec<Arc<dyn PortIoDevice>> = match config.kind {
+                DeviceKind::Uart16550 => {
+                    let backend = match config.backend.as_deref() {
+                        None | Some("console") => uart16550::SerialBackend::Console,
+                        Some("null") => uart16550::SerialBackend::Null,
+                        Some(name) => {
+                            warn!("Unknown serial port backend {:?}", name);
+                            return Err(RvmError::InvalidParam);
+                        }
+                    };
+                    let uart = Arc::new(uart16550::Uart16550::new(port()?, backend, irq));
+                    if backend == uart16550::SerialBackend::Console && console.is_none() {
+                        console = Some(uart.clone());
+                    }
+                    vec![uart]
+                }
+                DeviceKind::I8259Pic => {
+                    // the master, slave and ELCR ports are fixed
+                    if pic.is_some() {
+                        warn!("Only one pair of 8259 PICs is supported");
+                        return Err(RvmError::InvalidParam);
+                    }
+                    let pair = Arc::new(Mutex::new(i8259_pic::I8259PicPair::new()));
+                    pic = Some(pair.clone());
+                    [
+                        i8259_pic::MASTER_PORT_BASE,
+                        i8259_pic::SLAVE_PORT_BASE,
+                        i8259_pic::ELCR_PORT_BASE,
+                    ]
+                    .into_iter()
+                    .map(|base| {
+                        Arc::new(i8259_pic::I8259Pic::new(base, pair.clone()))
+                            as Arc<dyn PortIoDevice>
+                    })
+                    .collect()
+                }
+                DeviceKind::IoApic | DeviceKind::Hpet => {
+                    let dev: Arc<dyn MmioDevice> = if config.kind == DeviceKind::IoApic {
+                        if ioapic.is_some() {
+                            warn!("Only one I/O APIC is supported");
+                            return Err(RvmError::InvalidParam);
+                        }
+                        let base = config.addr.unwrap_or(ioapic::IOAPIC_BASE);
+                        let dev = Arc::new(ioapic::VirtIoApic::new(base));
+                        ioapic = Some(dev.clone());
+                        dev
+                    } else {
+                        if hpet.is_some() {
+                            warn!("Only one HPET is supported");
+                            return Err(RvmError::InvalidParam);
+                        }
+                        let base = config.addr.unwrap_or(hpet::HPET_BASE);
+                        let dev = Arc::new(hpet::Hpet::new(base, irq_lines.clone()));
+                        hpet = Some(dev.clone());
+                        dev
+                    };
+                    let range = dev.mmio_range();
+                    if mmio_devices.iter().any(|d| {
+                        let r = d.mmio_range();
+                        r.start < range.end && range.start < r.end
+                    }) {
+                        warn!("MMIO region {:#x?} of {:?} overlapped", range, config.kind);
+                        return Err(RvmError::InvalidParam);
+                    }
+                    debug!("Emulated device {:?}: mmio={:#x?}", config.kind, range);
+                    mmio_devices.push(dev);
+                    continue;
+                }
+                DeviceKind::I8254Pit => {
+                    // the ports are fixed, and channel 0 is connected to IRQ0 by default
+                    if pit.is_some() {
+                        warn!("Only one 8254 PIT is supported");
+                        return Err(RvmError::InvalidParam);
+                    }
+                    let irq = irq.or_else(|| {
+                        Some(IrqLine {
+                            lines: irq_lines.clone(),
+                            irq: 0,
+                        })
+                    });
+                    let state = Arc::new(Mutex::new(i8254_pit::PitState::new()));
+                    let timer = Arc::new(i8254_pit::I8254Pit::new(
+                        i8254_pit::PIT_PORT_BASE,
+                        state.clone(),
+                        irq,
+                    ));
+                    pit = Some(timer.clone());
+                    vec![
+                        timer,
+                        Arc::new(i8254_pit::I8254Pit::new(
+                            i8254_pit::SYSTEM_CONTROL_PORT,
+                            state,
+                            None,
+                        )),
+                    ]
+                }
+                DeviceKind::ResetControl => {
+                    // the reset control register and the keyboard controller
+                    vec![
+                        Arc::new(power::ResetControl::new(power.clone())) as Arc<dyn PortIoDevice>,
+                        Arc::new(power::KbcResetLine::new(power.clone())),
+                    ]
+                }
+                DeviceKind::AcpiPm => {
+                    let port = config.port.unwrap_or(power::PM1A_CONTROL_PORT);
+                    vec![Arc::new(power::AcpiPmControl::new(port, power.clone()))]
+                }
+                DeviceKind::DebugExit => {
+                    let port = config.port.unwrap_or(power::DEBUG_EXIT_PORT);
+                    vec![Arc::new(power::DebugExit::new(port, power.clone()))]
+                }
+            };
+            for dev in devs {
+                let range = dev.port_range();
+                if port_io_devices.iter().any(|d| {
+                    let r = d.port_range();
+                    r.start < range.end && range.start < r.end
+                }) {
+                    warn!("I/O ports {:#x?} of {:?} overlapped", range, config.kind);
+                    return Err(RvmError::InvalidParam);
+                }
+                debug!(
+                    "Emulated device {:?}: ports={:#x?}, irq={:?}",
+                    config.kind, range, config.irq
+                );
+                port_io_devices.push(dev);
+            }
+        }
+        Ok(Self {
+            port_io_devices,
+            mmio_devices,
+            console,
+            pic,
+            pit,
+            lapic: Arc::new(VirtLocalApic::new(ioapic.clone())),
+            ioapic,
+            hpet,
+            irq_lines,
+            power,
+        })
+    }
+
+    /// Deliver the host console input to the serial port connected to it.
+    pub fn handle_console_input(&self) {
+        if let Some(uart) = &self.console {
+            uart.handle_backend_input();
+        }
+    }
+
+    /// Update the IRQ lines driven by timers, and inject pending interrupts of
+    /// the I/O APIC and the 8259 PICs (as the ExtINT delivery mode) to the vCPU.
+    ///
+    /// The interrupt of the PICs is acknowledged only when it's injected on the
+    /// next VM entry, so at most one is in flight, otherwise interrupt-window
+    /// exiting is enabled to try again.
+    pub fn inject_interrupts(&self, vcpu: &mut Vcpu) -> RvmResult {
+        // the PIT is disconnected from IRQ0 in the HPET legacy replacement mode
+        let hpet_legacy = match &self.hpet {
+            Some(hpet) => {
+                hpet.update_irq();
+                hpet.legacy_mode()
+            }
+            None => false,
+        };
+        if let Some(pit) = &self.pit {
+            if !hpet_legacy {
+                pit.update_irq();
+            }
+        }
+        let edges = self.irq_lines.take_edges();
+        let levels = self.irq_lines.levels();
+        if let Some(ioapic) = &self.ioapic {
+            ioapic.sync_irq_lines(edges, levels, |vector, level_triggered| {
+                trace!("I/O APIC: send vector {:#x}", vector);
+                vcpu.lapic_mut().request_interrupt(vector, level_triggered);
+            });
+        }
+        if let Some(pic) = &self.pic {
+            let mut pic = pic.lock();
+            pic.sync_irq_lines(edges, levels);
+            if pic.has_interrupt() && self.lapic.accepts_ext_int(vcpu) {
+                if !vcpu.can_inject_interrupt() {
+                    vcpu.set_interrupt_window(true)?;
+                } else if let Some(vector) = pic.ack_interrupt() {
+                    trace!("8259 PIC: inject vector {:#x}", vector);
+                    vcpu.inject_interrupt(vector);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Request to reset or power off the machine.
+    pub fn request_power_event(&self, event: PowerEvent) {
+        self.power.request(event);
+    }
+
+    /// Returns and clears the reset or power-off requested by the guest.
+    pub fn take_power_event(&self) -> Option<PowerEvent> {
+        self.power.take_event()
+    }
+
+    /// The local APIC of the only vCPU.
+    pub fn lapic(&self) -> &VirtLocalApic {
+        &self.lapic
+    }
+
+    /// Register the MSRs emulated by the devices.
+    pub fn register_msrs(&self, msrs: &mut MsrTable) {
+        let apic_base = x86::msr::IA32_APIC_BASE;
+        msrs.register_device(apic_base..apic_base + 1, self.lapic.clone());
+        msrs.register_device(VirtLocalApic::msr_range(), self.lapic.clone());
+    }
+
     pub fn find_port_io_device(&self, port: u16) -> Option<&Arc<dyn PortIoDevice>> {
         self.port_io_devices
             .iter()
             .find(|dev| dev.port_range().contains(&port))
     }
-}
-
-lazy_static::lazy_static! {
-    static ref VIRT_DEVICES : VirtDeviceList = VirtDeviceList {
-        port_io_devices: vec![
-            Arc::new(uart16550::Uart16550::new(0x3f8)), // COM1
-            Arc::new(i8259_pic::I8259Pic::new(0x20)), // PIC1
-            Arc::new(i8259_pic::I8259Pic::new(0xA0)), // PIC2
-        ],
-    };
-}
 
-pub fn all_virt_devices() -> &'static VirtDeviceList {
-    &VIRT_DEVICES
+    pub fn find_mmio_device(&self, gpa: GuestPhysAddr) -> Option<&Arc<dyn MmioDevice>> {
+        self.mmio_devices
+            .iter()
+            .find(|dev| dev.mmio_range().contains(&gpa))
+    }
 }
@@ -0,0 +1,222 @@
+//! Emulated registers for the guest to reset or power off the machine.
+//!
+//! * The reset control register (0xcf9) of the PCI chipset, which resets the
+//!   machine when the reset CPU bit is set.
+//! * The command port (0x64) of the 8042 keyboard controller, the command 0xfe
+//!   pulses the reset line, and the other commands are ignored.
+//! * The ACPI PM1a control register, entering the sleep type 0 (S5) powers off
+//!   the machine, and the other sleep states are not supported. There are no
+//!   ACPI tables, so the guest must know the port from its own configuration.
+//! * The debug exit port, like the `isa-debug-exit` device of QEMU, a write
+//!   stops the VM with the value as the exit status.
+//!
+//! The requests are recorded in [`PowerControl`] and handled after the VM exit.
+
+use alloc::sync::Arc;
+
+use rvm::{RvmError, RvmResult};
+use spin::Mutex;
+
+use super::PortIoDevice;
+
+pub const RESET_CONTROL_PORT: u16 = 0xcf9;
+pub const KBC_COMMAND_PORT: u16 = 0x64;
+pub const PM1A_CONTROL_PORT: u16 = 0x604;
+pub const DEBUG_EXIT_PORT: u16 = 0x501;
+
+const RCR_SYS_RESET: u8 = 1 << 1;
+const RCR_RESET_CPU: u8 = 1 << 2;
+
+const KBC_CMD_PULSE_RESET: u8 = 0xfe;
+
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+const SLP_TYP_SOFT_OFF: u16 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEvent {
+    Reset,
+    PowerOff,
+    /// Stop the VM with the exit status.
+    Exit(u32),
+}
+
+/// The reset or power-off requested by the guest.
+#[derive(Default)]
+pub struct PowerControl {
+    event: Mutex<Option<PowerEvent>>,
+}
+
+impl PowerControl {
+    pub fn request(&self, event: PowerEvent) {
+        let mut pending = self.event.lock();
+        // powering off takes precedence over resetting
+        if !matches!(*pending, Some(PowerEvent::PowerOff | PowerEvent::Exit(_))) {
+            *pending = Some(event);
+        }
+    }
+
+    /// Returns and clears the requested event.
+    pub fn take_event(&self) -> Option<PowerEvent> {
+        self.event.lock().take()
+    }
+}
+
+pub struct ResetControl {
+    rcr: Mutex<u8>,
+    power: Arc<PowerControl>,
+}
+
+impl ResetControl {
+    pub fn new(power: Arc<PowerControl>) -> Self {
+        Self {
+            rcr: Mutex::new(0),
+            power,
+        }
+    }
+}
+
+impl PortIoDevice for ResetControl {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        RESET_CONTROL_PORT..RESET_CONTROL_PORT + 1
+    }
+
+    fn read(&self, _port: u16, access_size: u8) -> RvmResult<u32> {
+        if access_size != 1 {
+            error!("Invalid reset control read size: {} != 1", access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        Ok(*self.rcr.lock() as u32)
+    }
+
+    fn write(&self, _port: u16, access_size: u8, value: u32) -> RvmResult {
+        if access_size != 1 {
+            error!("Invalid reset control write size: {} != 1", access_size);
+            return Err(RvmError::InvalidParam);
+        }
+        let value = value as u8;
+        if value & RCR_RESET_CPU != 0 {
+            info!("Reset requested by the reset control register");
+            self.power.request(PowerEvent::Reset);
+        }
+        *self.rcr.lock() = value & RCR_SYS_RESET;
+        Ok(())
+    }
+}
+
+pub struct KbcResetLine {
+    power: Arc<PowerControl>,
+}
+
+impl KbcResetLine {
+    pub fn new(power: Arc<PowerControl>) -> Self {
+        Self { power }
+    }
+}
+
+impl PortIoDevice for KbcResetLine {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        KBC_COMMAND_PORT..KBC_COMMAND_PORT + 1
+    }
+
+    fn read(&self, _port: u16, _access_size: u8) -> RvmResult<u32> {
+        Ok(0) // the status: both the input and output buffers are empty
+    }
+
+    fn write(&self, _port: u16, _access_size: u8, value: u32) -> RvmResult {
+        if value as u8 == KBC_CMD_PULSE_RESET {
+            info!("Reset requested by the keyboard controller");
+            self.power.request(PowerEvent::Reset);
+        } else {
+            debug!("Ignored keyboard controller command {:#x}", value);
+        }
+        Ok(())
+    }
+}
+
+pub struct AcpiPmControl {
+    port: u16,
+    pm1_cnt: Mutex<u16>,
+    power: Arc<PowerControl>,
+}
+
+impl AcpiPmControl {
+    pub fn new(port: u16, power: Arc<PowerControl>) -> Self {
+        Self {
+            port,
+            pm1_cnt: Mutex::new(PM1_CNT_SCI_EN),
+            power,
+        }
+    }
+}
+
+impl PortIoDevice for AcpiPmControl {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port..self.port + 2
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> RvmResult<u32> {
+        if access_size != 2 || port != self.port {
+            error!(
+                "Invalid PM1a control read @ {:#x}, size {}",
+                port, access_size
+            );
+            return Err(RvmError::InvalidParam);
+        }
+        Ok(*self.pm1_cnt.lock() as u32)
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> RvmResult {
+        if access_size != 2 || port != self.port {
+            error!(
+                "Invalid PM1a control write @ {:#x}, size {}",
+                port, access_size
+            );
+            return Err(RvmError::InvalidParam);
+        }
+        let value = value as u16;
+        if value & PM1_CNT_SLP_EN != 0 {
+            match (value >> PM1_CNT_SLP_TYP_SHIFT) & 7 {
+                SLP_TYP_SOFT_OFF => {
+                    info!("Power-off requested by ACPI");
+                    self.power.request(PowerEvent::PowerOff);
+                }
+                typ => warn!("Unsupported ACPI sleep type {}", typ),
+            }
+        }
+        // SLP_EN is write-only, and the SCI is always enabled
+        *self.pm1_cnt.lock() = (value & !PM1_CNT_SLP_EN) | PM1_CNT_SCI_EN;
+        Ok(())
+    }
+}
+
+pub struct DebugExit {
+    port: u16,
+    power: Arc<PowerControl>,
+}
+
+impl DebugExit {
+    pub fn new(port: u16, power: Arc<PowerControl>) -> Self {
+        Self { port, power }
+    }
+}
+
+impl PortIoDevice for DebugExit {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port..self.port + 4
+    }
+
+    fn read(&self, _port: u16, _access_size: u8) -> RvmResult<u32> {
+        Ok(0)
+    }
+
+    fn write(&self, port: u16, _access_size: u8, value: u32) -> RvmResult {
+        info!(
+            "Exit requested by the debug exit port {:#x}: {:#x}",
+            port, value
+        );
+        self.power.request(PowerEvent::Exit(value));
+        Ok(())
+    }
+}
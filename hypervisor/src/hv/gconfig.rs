@@ -1,12 +1,571 @@
-use rvm::{GuestPhysAddr, HostPhysAddr};
+//! Guest configuration, parsed from a text description at boot.
+//!
+//! The description has one directive per line (or separated by `;`), and `#`
+//! starts a comment. Numbers are decimal or hexadecimal (`0x`), with an
+//! optional `K`, `M` or `G` suffix.
+//!
+//! ```text
+//! vcpus <count>
+//! entry <gpa>
+//! boot-info <gpa>
+//! cmdline <guest command line>
+//! memory <gpa> <size> <flags: r|w|x|d> [hpa=<hpa>]
+//! image <gpa> <source>
+//! kernel <gpa> <source>
+//! initrd <source>
+//! module <gpa> <source> [<module command line>]
+//! device <uart16550|i8259|i8254|ioapic|hpet|reset|acpi-pm|debug-exit> [port=<port>] [addr=<gpa>] [irq=<irq>] [backend=<name>]
+//! io-passthrough <port> <count>
+//! cpuid-policy <host|x86-64-v2>
+//! cpuid <leaf>[.<subleaf>] [<reg>=<value>] [clear-<reg>=<bits>] [set-<reg>=<bits>]
+//! msr <index> <passthrough|fixed|shadow> [value=<value>]
+//! fault-policy <strict|lenient>
+//! reset-policy <reboot|halt|dump>
+//! ```
+//!
+//! Memory regions without `hpa=` are allocated by the hypervisor, the others
+//! are passed through to the host physical memory. An image `<source>` is
+//! either `file=<name>` for a Multiboot module of the hypervisor, or
+//! `paddr=<hpa> size=<size>` for a range of the host physical memory.
+//!
+//! A Linux `kernel` is booted directly, otherwise the vCPU starts at `entry`
+//! with the Multiboot information at `boot-info`.
+//!
+//! The `io-passthrough` ports are accessed by the guest directly, for a
+//! physical device owned by the guest. They must not be used by the hypervisor,
+//! like the host serial console and the exit ports, or emulated devices.
+//!
+//! The CPUID leaves are generated by the `cpuid-policy`, `host` (the default)
+//! for the host features supported by the hypervisor, or `x86-64-v2` for the
+//! baseline features of the level. A `cpuid` directive replaces registers
+//! (`eax`, `ebx`, `ecx` or `edx`) of a leaf, or clears and sets bits in them.
+//!
+//! An `msr` directive makes the guest access the host MSR (`passthrough`),
+//! read a `fixed` value with writes ignored, or read back the value written
+//! (`shadow`) starting with `value`. Only the MSRs switched on VM entries and
+//! exits, or not used by the hypervisor, such as the `SYSCALL` MSRs, can be
+//! passed through.
+//!
+//! The `fault-policy` decides what happens when the guest does something the
+//! hypervisor can not handle, like accessing unknown MSRs or I/O ports. The VM
+//! is stopped if `strict` (the default), or the guest sees a fault if `lenient`.
+//!
+//! The `reset-policy` decides what happens when the guest resets the machine
+//! or triple faults: `reboot` (the default) starts the guest again with fresh
+//! memory and devices, `halt` stops the VM, and `dump` stops the VM after
+//! printing the vCPU state.
 
-pub const BIOS_PADDR: HostPhysAddr = 0x400_0000;
-pub const BIOS_SIZE: usize = 0x1000;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::ops::Range;
 
-pub const GUEST_IMAGE_PADDR: HostPhysAddr = 0x400_1000;
-pub const GUEST_IMAGE_SIZE: usize = 0x10_0000; // 1M
+use rvm::{GuestPhysAddr, HostPhysAddr, MemFlags, RvmError, RvmResult};
 
-pub const GUEST_PHYS_MEMORY_BASE: GuestPhysAddr = 0;
-pub const BIOS_ENTRY: GuestPhysAddr = 0x8000;
-pub const GUEST_ENTRY: GuestPhysAddr = 0x20_0000;
-pub const GUEST_PHYS_MEMORY_SIZE: usize = 0x100_0000; // 16M
+use super::device_emu::DEBUG_EXIT_PORT;
+use crate::{cmdline, multiboot};
+
+/// The Multiboot module used as the guest configuration if not specified in
+/// the hypervisor command line.
+const DEFAULT_CONFIG_MODULE: &str = "guest.cfg";
+
+/// The configuration used if neither the hypervisor command line nor Multiboot
+/// modules give one.
+const DEFAULT_CONFIG: &str = "
+vcpus 1
+entry 0x8000
+boot-info 0x9000
+
+memory 0x0 16M rwx
+
+image 0x8000 paddr=0x4000000 size=4K        # BIOS
+kernel 0x200000 paddr=0x4001000 size=1M
+
+device uart16550 port=0x3f8 irq=4 backend=console   # COM1
+device i8259                                        # PIC1 and PIC2
+device i8254                                        # PIT
+device ioapic                                       # IO APIC at 0xfec00000
+device hpet                                         # HPET at 0xfed00000
+device reset                                        # ports 0xcf9 and 0x64
+";
+
+#[derive(Debug, Clone)]
+pub struct MemoryConfig {
+    pub gpa: GuestPhysAddr,
+    pub size: usize,
+    pub flags: MemFlags,
+    /// The host physical address to pass through, or `None` to allocate.
+    pub hpa: Option<HostPhysAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageSource {
+    /// A Multiboot module of the hypervisor with the file name.
+    Module(String),
+    /// A range of the host physical memory.
+    Phys { paddr: HostPhysAddr, size: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    /// Copied to the guest memory as is.
+    Raw,
+    /// The guest kernel, booted directly if it is a Linux kernel.
+    Kernel,
+    /// The initial ramdisk of a Linux kernel.
+    Initrd,
+    /// Copied to the guest memory and passed as a Multiboot module.
+    Module,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageConfig {
+    pub kind: ImageKind,
+    pub gpa: GuestPhysAddr,
+    pub source: ImageSource,
+    pub cmdline: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Uart16550,
+    I8259Pic,
+    I8254Pit,
+    IoApic,
+    Hpet,
+    /// The reset control register and the reset line of the keyboard controller.
+    ResetControl,
+    /// The ACPI PM1a control register.
+    AcpiPm,
+    /// The port to stop the VM with an exit status.
+    DebugExit,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub kind: DeviceKind,
+    pub port: Option<u16>,
+    /// The base address of MMIO registers.
+    pub addr: Option<GuestPhysAddr>,
+    pub irq: Option<u8>,
+    pub backend: Option<String>,
+}
+
+/// Override registers of a CPUID leaf, the others are kept.
+#[derive(Debug, Clone)]
+pub struct CpuidOverride {
+    pub leaf: u32,
+    /// Match all subleaves if `None`.
+    pub subleaf: Option<u32>,
+    /// Values of `EAX`, `EBX`, `ECX` and `EDX`.
+    pub regs: [Option<u32>; 4],
+    /// Bits to clear in the registers, after replaced by `regs`.
+    pub clear: [u32; 4],
+    /// Bits to set in the registers, after cleared.
+    pub set: [u32; 4],
+}
+
+/// How to generate the CPUID leaves of the guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CpuidPolicy {
+    /// The host features, except the ones not supported by the hypervisor.
+    #[default]
+    Host,
+    /// The features of the x86-64-v2 microarchitecture level.
+    X86_64V2,
+}
+
+/// How the guest accesses an MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrEmulation {
+    /// Access the MSR of the host.
+    Passthrough,
+    /// Read the value, and ignore writes.
+    Fixed(u64),
+    /// Read the last value written, starting with the value.
+    Shadow(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct MsrConfig {
+    pub msr: u32,
+    pub emulation: MsrEmulation,
+}
+
+/// How to handle the guest operations not supported by the hypervisor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FaultPolicy {
+    /// Stop the VM.
+    #[default]
+    Strict,
+    /// Reflect to the guest as what the hardware does without the device or
+    /// feature: `#GP(0)` for MSRs, all-ones reads and dropped writes for I/O
+    /// ports and MMIO, and `#UD` for other instructions.
+    Lenient,
+}
+
+/// What to do when the guest resets the machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResetPolicy {
+    /// Start the guest again.
+    #[default]
+    Reboot,
+    /// Stop the VM.
+    Halt,
+    /// Print the vCPU state, then stop the VM.
+    Dump,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GuestConfig {
+    pub vcpus: usize,
+    pub entry: GuestPhysAddr,
+    pub boot_info: GuestPhysAddr,
+    pub cmdline: String,
+    pub memory: Vec<MemoryConfig>,
+    pub images: Vec<ImageConfig>,
+    pub devices: Vec<DeviceConfig>,
+    pub io_passthrough: Vec<Range<u16>>,
+    pub cpuid_policy: CpuidPolicy,
+    pub cpuid: Vec<CpuidOverride>,
+    pub msrs: Vec<MsrConfig>,
+    pub fault_policy: FaultPolicy,
+    pub reset_policy: ResetPolicy,
+}
+
+fn parse_num(s: &str) -> Option<usize> {
+    let (s, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let n = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        usize::from_str_radix(hex, 16).ok()?
+    } else {
+        s.parse().ok()?
+    };
+    n.checked_mul(1 << shift)
+}
+
+fn parse_flags(s: &str) -> Option<MemFlags> {
+    s.chars().try_fold(MemFlags::empty(), |flags, c| match c {
+        'r' => Some(flags | MemFlags::READ),
+        'w' => Some(flags | MemFlags::WRITE),
+        'x' => Some(flags | MemFlags::EXECUTE),
+        'd' => Some(flags | MemFlags::DEVICE),
+        _ => None,
+    })
+}
+
+/// Tokens of a directive after the keyword.
+struct Args<'a> {
+    tokens: Vec<&'a str>,
+}
+
+impl<'a> Args<'a> {
+    fn new(s: &'a str) -> Self {
+        Self {
+            tokens: s.split_whitespace().collect(),
+        }
+    }
+
+    /// Take the next positional argument.
+    fn next(&mut self) -> Option<&'a str> {
+        let idx = self.tokens.iter().position(|t| !t.contains('='))?;
+        Some(self.tokens.remove(idx))
+    }
+
+    fn next_num(&mut self) -> Option<usize> {
+        self.next().and_then(parse_num)
+    }
+
+    /// Take the value of the option `key=value`.
+    fn option(&mut self, key: &str) -> Option<&'a str> {
+        let idx = self
+            .tokens
+            .iter()
+            .position(|t| t.split_once('=').map(|(k, _)| k) == Some(key))?;
+        self.tokens.remove(idx).split_once('=').map(|(_, v)| v)
+    }
+
+    fn option_num(&mut self, key: &str) -> RvmResult<Option<usize>> {
+        self.option(key)
+            .map(|v| parse_num(v).ok_or(RvmError::InvalidParam))
+            .transpose()
+    }
+
+    fn source(&mut self) -> RvmResult<ImageSource> {
+        if let Some(name) = self.option("file") {
+            Ok(ImageSource::Module(name.into()))
+        } else {
+            let paddr = self.option_num("paddr")?.ok_or(RvmError::InvalidParam)?;
+            let size = self.option_num("size")?.ok_or(RvmError::InvalidParam)?;
+            Ok(ImageSource::Phys { paddr, size })
+        }
+    }
+
+    /// Join the remaining tokens.
+    fn rest(&mut self) -> String {
+        let rest = self.tokens.join(" ");
+        self.tokens.clear();
+        rest
+    }
+
+    fn finish(self) -> RvmResult {
+        if self.tokens.is_empty() {
+            Ok(())
+        } else {
+            warn!("Unknown arguments: {:?}", self.tokens);
+            Err(RvmError::InvalidParam)
+        }
+    }
+}
+
+impl GuestConfig {
+    /// Load the guest configuration from the Multiboot module named by the
+    /// hypervisor command line option `guest=`, the inline value of it, the
+    /// Multiboot module `guest.cfg`, or the default configuration.
+    pub fn load() -> RvmResult<Self> {
+        let text = if let Some(value) = cmdline::guest() {
+            match multiboot::find_module(value) {
+                Some(m) => core::str::from_utf8(m.data()).map_err(|_| RvmError::InvalidParam)?,
+                None => value,
+            }
+        } else if let Some(m) = multiboot::find_module(DEFAULT_CONFIG_MODULE) {
+            core::str::from_utf8(m.data()).map_err(|_| RvmError::InvalidParam)?
+        } else {
+            DEFAULT_CONFIG
+        };
+        Self::parse(text)
+    }
+
+    /// Parse the guest configuration from the text description.
+    pub fn parse(text: &str) -> RvmResult<Self> {
+        let mut config = Self {
+            vcpus: 1,
+            ..Default::default()
+        };
+        for directive in text.lines().flat_map(|line| {
+            let line = line.split_once('#').map_or(line, |(s, _)| s);
+            line.split(';')
+        }) {
+            let directive = directive.trim();
+            if directive.is_empty() {
+                continue;
+            }
+            if let Err(e) = config.parse_directive(directive) {
+                warn!("Invalid guest config directive {:?}: {:?}", directive, e);
+                return Err(e);
+            }
+        }
+        config.check()?;
+        Ok(config)
+    }
+
+    fn parse_directive(&mut self, directive: &str) -> RvmResult {
+        let (keyword, rest) = directive
+            .split_once(char::is_whitespace)
+            .unwrap_or((directive, ""));
+        if keyword == "cmdline" {
+            self.cmdline = rest.trim().into();
+            return Ok(());
+        }
+
+        let mut args = Args::new(rest);
+        let num = |n: Option<usize>| n.ok_or(RvmError::InvalidParam);
+        match keyword {
+            "vcpus" => self.vcpus = num(args.next_num())?,
+            "entry" => self.entry = num(args.next_num())?,
+            "boot-info" => self.boot_info = num(args.next_num())?,
+            "memory" => {
+                let gpa = num(args.next_num())?;
+                let size = num(args.next_num())?;
+                let flags = args
+                    .next()
+                    .and_then(parse_flags)
+                    .ok_or(RvmError::InvalidParam)?;
+                let hpa = args.option_num("hpa")?;
+                self.memory.push(MemoryConfig {
+                    gpa,
+                    size,
+                    flags,
+                    hpa,
+                });
+            }
+            "image" | "kernel" | "module" => {
+                let kind = match keyword {
+                    "image" => ImageKind::Raw,
+                    "kernel" => ImageKind::Kernel,
+                    _ => ImageKind::Module,
+                };
+                let gpa = num(args.next_num())?;
+                let source = args.source()?;
+                self.images.push(ImageConfig {
+                    kind,
+                    gpa,
+                    source,
+                    cmdline: args.rest(),
+                });
+            }
+            "initrd" => {
+                let source = args.source()?;
+                self.images.push(ImageConfig {
+                    kind: ImageKind::Initrd,
+                    gpa: 0,
+                    source,
+                    cmdline: String::new(),
+                });
+            }
+            "device" => {
+                let kind = match args.next() {
+                    Some("uart16550") => DeviceKind::Uart16550,
+                    Some("i8259") => DeviceKind::I8259Pic,
+                    Some("i8254") => DeviceKind::I8254Pit,
+                    Some("ioapic") => DeviceKind::IoApic,
+                    Some("hpet") => DeviceKind::Hpet,
+                    Some("reset") => DeviceKind::ResetControl,
+                    Some("acpi-pm") => DeviceKind::AcpiPm,
+                    Some("debug-exit") => DeviceKind::DebugExit,
+                    _ => return Err(RvmError::InvalidParam),
+                };
+                let port = args.option_num("port")?.map(|p| p as u16);
+                let addr = args.option_num("addr")?;
+                let irq = args.option_num("irq")?.map(|i| i as u8);
+                let backend = args.option("backend").map(|b| b.to_string());
+                self.devices.push(DeviceConfig {
+                    kind,
+                    port,
+                    addr,
+                    irq,
+                    backend,
+                });
+            }
+            "io-passthrough" => {
+                let port = num(args.next_num())?;
+                let count = num(args.next_num())?;
+                if count == 0 || port + count > 0xffff {
+                    return Err(RvmError::InvalidParam);
+                }
+                self.io_passthrough.push(port as u16..(port + count) as u16);
+            }
+            "cpuid" => {
+                let (leaf, subleaf) = match args.next().ok_or(RvmError::InvalidParam)? {
+                    s if s.contains('.') => {
+                        let (leaf, subleaf) = s.split_once('.').unwrap();
+                        (num(parse_num(leaf))?, Some(num(parse_num(subleaf))?))
+                    }
+                    s => (num(parse_num(s))?, None),
+                };
+                let mut regs = [None; 4];
+                let mut clear = [0; 4];
+                let mut set = [0; 4];
+                for (i, name) in ["eax", "ebx", "ecx", "edx"].into_iter().enumerate() {
+                    regs[i] = args.option_num(name)?.map(|v| v as u32);
+                    clear[i] = args.option_num(&format!("clear-{}", name))?.unwrap_or(0) as u32;
+                    set[i] = args.option_num(&format!("set-{}", name))?.unwrap_or(0) as u32;
+                }
+                self.cpuid.push(CpuidOverride {
+                    leaf: leaf as u32,
+                    subleaf: subleaf.map(|s| s as u32),
+                    regs,
+                    clear,
+                    set,
+                });
+            }
+            "cpuid-policy" => {
+                self.cpuid_policy = match args.next() {
+                    Some("host") => CpuidPolicy::Host,
+                    Some("x86-64-v2") => CpuidPolicy::X86_64V2,
+                    _ => return Err(RvmError::InvalidParam),
+                };
+            }
+            "msr" => {
+                let msr = num(args.next_num())? as u32;
+                let kind = args.next();
+                let value = args.option_num("value")?.unwrap_or(0) as u64;
+                let emulation = match kind {
+                    Some("passthrough") => MsrEmulation::Passthrough,
+                    Some("fixed") => MsrEmulation::Fixed(value),
+                    Some("shadow") => MsrEmulation::Shadow(value),
+                    _ => return Err(RvmError::InvalidParam),
+                };
+                self.msrs.push(MsrConfig { msr, emulation });
+            }
+            "fault-policy" => {
+                self.fault_policy = match args.next() {
+                    Some("strict") => FaultPolicy::Strict,
+                    Some("lenient") => FaultPolicy::Lenient,
+                    _ => return Err(RvmError::InvalidParam),
+                };
+            }
+            "reset-policy" => {
+                self.reset_policy = match args.next() {
+                    Some("reboot") => ResetPolicy::Reboot,
+                    Some("halt") => ResetPolicy::Halt,
+                    Some("dump") => ResetPolicy::Dump,
+                    _ => return Err(RvmError::InvalidParam),
+                };
+            }
+            _ => return Err(RvmError::InvalidParam),
+        }
+        args.finish()
+    }
+
+    fn check(&self) -> RvmResult {
+        if self.vcpus != 1 {
+            warn!("Only one vCPU per guest is supported: vcpus={}", self.vcpus);
+            return Err(RvmError::Unsupported);
+        }
+        if self.memory.is_empty() {
+            warn!("No guest memory configured");
+            return Err(RvmError::InvalidParam);
+        }
+        if self
+            .images
+            .iter()
+            .filter(|i| i.kind == ImageKind::Kernel)
+            .count()
+            > 1
+            || self
+                .images
+                .iter()
+                .filter(|i| i.kind == ImageKind::Initrd)
+                .count()
+                > 1
+        {
+            warn!("Multiple guest kernels or initrds configured");
+            return Err(RvmError::InvalidParam);
+        }
+
+        // the host console and exit ports, and the debug exit port of the guest
+        let mut reserved_ports = Vec::from(crate::arch::power::exit_ports());
+        reserved_ports.push(crate::arch::uart::console_ports());
+        for dev in self
+            .devices
+            .iter()
+            .filter(|d| d.kind == DeviceKind::DebugExit)
+        {
+            let port = dev.port.unwrap_or(DEBUG_EXIT_PORT);
+            reserved_ports.push(port..port + 4);
+        }
+        for ports in &self.io_passthrough {
+            if let Some(r) = reserved_ports
+                .iter()
+                .find(|r| r.start < ports.end && ports.start < r.end)
+            {
+                warn!(
+                    "Passthrough I/O ports {:#x?} overlap the reserved ports {:#x?}",
+                    ports, r
+                );
+                return Err(RvmError::InvalidParam);
+            }
+        }
+        Ok(())
+    }
+
+    /// The image of the given kind, for the kernel and initrd.
+    pub fn image(&self, kind: ImageKind) -> Option<&ImageConfig> {
+        self.images.iter().find(|i| i.kind == kind)
+    }
+}
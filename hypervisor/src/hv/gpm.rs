@@ -1,22 +1,26 @@
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, vec::Vec};
 use core::fmt::{Debug, Formatter, Result};
 
 use rvm::{GuestPhysAddr, HostPhysAddr, MemFlags, NestedPageTable, RvmError, RvmResult};
+use spin::Mutex;
 
 use super::hal::RvmHalImpl;
-use crate::mm::{address::is_aligned, PAGE_SIZE};
+use crate::mm::address::{is_aligned, phys_to_virt};
+use crate::mm::{frame, PAGE_SIZE};
 
-#[derive(Debug)]
 enum Mapper {
     Offset(usize),
+    /// Each page is mapped to a frame allocated by the hypervisor.
+    Framed(Vec<HostPhysAddr>),
 }
 
-#[derive(Debug)]
-pub struct GuestMemoryRegion {
-    pub gpa: GuestPhysAddr,
-    pub hpa: HostPhysAddr,
-    pub size: usize,
-    pub flags: MemFlags,
+impl Debug for Mapper {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            Self::Offset(off) => f.debug_tuple("Offset").field(off).finish(),
+            Self::Framed(frames) => write!(f, "Framed({} frames)", frames.len()),
+        }
+    }
 }
 
 pub struct MapRegion {
@@ -45,6 +49,31 @@ impl MapRegion {
         }
     }
 
+    /// Create a region backed by zeroed frames allocated from the hypervisor.
+    pub fn new_framed(start_gpa: GuestPhysAddr, size: usize, flags: MemFlags) -> RvmResult<Self> {
+        assert!(is_aligned(start_gpa));
+        assert!(is_aligned(size));
+        let mut frames = Vec::with_capacity(size / PAGE_SIZE);
+        for _ in 0..size / PAGE_SIZE {
+            let Some(paddr) = (unsafe { frame::alloc_page() }) else {
+                frames.into_iter().for_each(|f| unsafe { frame::dealloc_page(f) });
+                return Err(RvmError::OutOfMemory);
+            };
+            unsafe { core::ptr::write_bytes(phys_to_virt(paddr) as *mut u8, 0, PAGE_SIZE) };
+            frames.push(paddr);
+        }
+        Ok(Self {
+            start: start_gpa,
+            size,
+            flags,
+            mapper: Mapper::Framed(frames),
+        })
+    }
+
+    pub fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        (self.start..self.start + self.size).contains(&gpa)
+    }
+
     fn is_overlap_with(&self, other: &Self) -> bool {
         let s0 = self.start;
         let e0 = s0 + self.size;
@@ -54,8 +83,11 @@ impl MapRegion {
     }
 
     fn target(&self, gpa: GuestPhysAddr) -> HostPhysAddr {
-        match self.mapper {
-            Mapper::Offset(off) => gpa.wrapping_sub(off),
+        match &self.mapper {
+            Mapper::Offset(off) => gpa.wrapping_sub(*off),
+            Mapper::Framed(frames) => {
+                frames[(gpa - self.start) / PAGE_SIZE] + (gpa & (PAGE_SIZE - 1))
+            }
         }
     }
 
@@ -70,6 +102,21 @@ impl MapRegion {
         Ok(())
     }
 
+    /// Update the flags of each page with `page_flags(gpa, self.flags)`.
+    fn update_to(
+        &self,
+        npt: &mut NestedPageTable<RvmHalImpl>,
+        page_flags: &impl Fn(GuestPhysAddr, MemFlags) -> MemFlags,
+    ) -> RvmResult {
+        let mut start = self.start;
+        let end = start + self.size;
+        while start < end {
+            npt.update(start, None, Some(page_flags(start, self.flags)))?;
+            start += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
     fn unmap_to(&self, npt: &mut NestedPageTable<RvmHalImpl>) -> RvmResult {
         let mut start = self.start;
         let end = start + self.size;
@@ -92,27 +139,45 @@ impl Debug for MapRegion {
     }
 }
 
-impl From<GuestMemoryRegion> for MapRegion {
-    fn from(r: GuestMemoryRegion) -> Self {
-        Self::new_offset(r.gpa, r.hpa, r.size, r.flags)
+impl Drop for MapRegion {
+    fn drop(&mut self) {
+        if let Mapper::Framed(frames) = &self.mapper {
+            frames
+                .iter()
+                .for_each(|&f| unsafe { frame::dealloc_page(f) });
+        }
     }
 }
 
 pub struct GuestPhysMemorySet {
     regions: BTreeMap<GuestPhysAddr, MapRegion>,
-    npt: NestedPageTable<RvmHalImpl>,
+    /// Locked to update the memory types while the guest is running.
+    npt: Mutex<NestedPageTable<RvmHalImpl>>,
 }
 
 impl GuestPhysMemorySet {
     pub fn new() -> RvmResult<Self> {
         Ok(Self {
-            npt: NestedPageTable::new()?,
+            npt: Mutex::new(NestedPageTable::new()?),
             regions: BTreeMap::new(),
         })
     }
 
     pub fn nest_page_table_root(&self) -> HostPhysAddr {
-        self.npt.root_paddr()
+        self.npt.lock().root_paddr()
+    }
+
+    /// Iterate over all mapped regions in ascending order of the start address.
+    pub fn regions(&self) -> impl Iterator<Item = &MapRegion> {
+        self.regions.values()
+    }
+
+    fn find_region(&self, gpa: GuestPhysAddr) -> Option<&MapRegion> {
+        self.regions
+            .range(..=gpa)
+            .next_back()
+            .map(|(_, r)| r)
+            .filter(|r| r.contains(gpa))
     }
 
     fn test_free_area(&self, other: &MapRegion) -> bool {
@@ -142,14 +207,72 @@ impl GuestPhysMemorySet {
             );
             return Err(RvmError::InvalidParam);
         }
-        region.map_to(&mut self.npt)?;
+        region.map_to(self.npt.get_mut())?;
         self.regions.insert(region.start, region);
         Ok(())
     }
 
+    /// Update the memory types of all mapped pages, `page_flags` returns the
+    /// flags of a page from its address and the flags of the region. The
+    /// vCPU must flush the nested page table after the update.
+    pub fn update_mem_types(
+        &self,
+        page_flags: impl Fn(GuestPhysAddr, MemFlags) -> MemFlags,
+    ) -> RvmResult {
+        let mut npt = self.npt.lock();
+        for region in self.regions.values() {
+            region.update_to(&mut npt, &page_flags)?;
+        }
+        Ok(())
+    }
+
+    /// The host virtual address of `gpa` in the guest normal memory, and the
+    /// bytes to the end of its page.
+    fn host_vaddr(&self, gpa: GuestPhysAddr) -> RvmResult<(usize, usize)> {
+        match self.find_region(gpa) {
+            // framed regions are not contiguous in the host memory
+            Some(r) if !r.flags.contains(MemFlags::DEVICE) => Ok((
+                phys_to_virt(r.target(gpa)),
+                PAGE_SIZE - (gpa & (PAGE_SIZE - 1)),
+            )),
+            _ => {
+                warn!("Access to unmapped or device guest memory {:#x}", gpa);
+                Err(RvmError::InvalidParam)
+            }
+        }
+    }
+
+    /// Copy `data` to the guest normal memory starting at `gpa`.
+    pub fn write(&self, gpa: GuestPhysAddr, data: &[u8]) -> RvmResult {
+        let mut gpa = gpa;
+        let mut data = data;
+        while !data.is_empty() {
+            let (dst, len) = self.host_vaddr(gpa)?;
+            let len = len.min(data.len());
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dst as *mut u8, len) };
+            gpa = gpa.checked_add(len).ok_or(RvmError::InvalidParam)?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Copy from the guest normal memory starting at `gpa` to `buf`.
+    pub fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> RvmResult {
+        let mut gpa = gpa;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let (src, len) = self.host_vaddr(gpa)?;
+            let len = len.min(buf.len());
+            unsafe { core::ptr::copy_nonoverlapping(src as *const u8, buf.as_mut_ptr(), len) };
+            gpa = gpa.checked_add(len).ok_or(RvmError::InvalidParam)?;
+            buf = &mut buf[len..];
+        }
+        Ok(())
+    }
+
     pub fn clear(&mut self) {
         for region in self.regions.values() {
-            region.unmap_to(&mut self.npt).unwrap();
+            region.unmap_to(self.npt.get_mut()).unwrap();
         }
         self.regions.clear();
     }
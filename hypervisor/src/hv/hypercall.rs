@@ -0,0 +1,142 @@
+//! Hypercalls from the guest by `VMCALL`.
+//!
+//! The hypercall number is passed in `RAX`, and up to 5 arguments in `RDI`,
+//! `RSI`, `RDX`, `RCX` and `R8`. The result is returned in `RAX`, a negative
+//! value is one of the error codes in [`HypercallError`]. Other registers are
+//! preserved. Only the guest kernel (CPL 0) can make hypercalls, the calls
+//! from other privilege levels fail with `PermissionDenied`.
+//!
+//! | Number | Name            | Arguments               | Result                          |
+//! |--------|-----------------|-------------------------|---------------------------------|
+//! | 0      | `VERSION`       |                         | ABI version, major in bits 16.. |
+//! | 1      | `SET_LOG_LEVEL` | level 0 (off) - 5       | 0                               |
+//! | 2      | `SHUTDOWN`      | exit status             | 0, the VM stops after the call  |
+//! | 3      | `FEATURES`      |                         | bitmap of available hypercalls  |
+//! | 4      | `GET_VM_ID`     |                         | the VM ID                       |
+//! | 5      | `CONSOLE_WRITE` | guest paddr, length     | bytes written                   |
+//! | 6      | `YIELD`         |                         | 0                               |
+//! | 7      | `GET_TIME`      |                         | host time in nanoseconds        |
+
+use alloc::collections::BTreeMap;
+
+use rvm::{RvmHal, RvmVcpu};
+use spin::Mutex;
+
+use super::device_emu::PowerEvent;
+use super::hal::RvmHalImpl;
+use super::vm;
+
+type Vcpu = RvmVcpu<RvmHalImpl>;
+
+const ABI_VERSION: u64 = 0x1_0000; // 1.0
+
+const HYPERCALL_VERSION: u64 = 0;
+const HYPERCALL_SET_LOG_LEVEL: u64 = 1;
+const HYPERCALL_SHUTDOWN: u64 = 2;
+const HYPERCALL_FEATURES: u64 = 3;
+const HYPERCALL_GET_VM_ID: u64 = 4;
+const HYPERCALL_CONSOLE_WRITE: u64 = 5;
+const HYPERCALL_YIELD: u64 = 6;
+const HYPERCALL_GET_TIME: u64 = 7;
+
+/// Max bytes written to the console by one hypercall.
+const CONSOLE_WRITE_MAX: usize = 0x1000;
+const CONSOLE_CHUNK_SIZE: usize = 256;
+
+/// Error codes of hypercalls, returned as negative values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HypercallError {
+    InvalidParam = -1,
+    Unsupported = -2,
+    BadAddress = -3,
+    PermissionDenied = -4,
+}
+
+pub type HypercallResult = Result<u64, HypercallError>;
+
+/// Handles a hypercall with the arguments.
+pub type HypercallHandler = fn(&mut Vcpu, [u64; 5]) -> HypercallResult;
+
+static HYPERCALLS: Mutex<BTreeMap<u64, HypercallHandler>> = Mutex::new(BTreeMap::new());
+
+/// Register the handler of hypercall `nr`, replacing the old one.
+pub fn register(nr: u64, handler: HypercallHandler) {
+    HYPERCALLS.lock().insert(nr, handler);
+}
+
+/// Register the standard hypercalls.
+pub fn init() {
+    register(HYPERCALL_VERSION, |_, _| Ok(ABI_VERSION));
+    register(HYPERCALL_SET_LOG_LEVEL, set_log_level);
+    register(HYPERCALL_SHUTDOWN, shutdown);
+    register(HYPERCALL_FEATURES, |_, _| Ok(features()));
+    register(HYPERCALL_GET_VM_ID, |_, _| Ok(vm::current().id() as u64));
+    register(HYPERCALL_CONSOLE_WRITE, console_write);
+    register(HYPERCALL_YIELD, |_, _| Ok(0)); // the only vCPU runs again
+    register(HYPERCALL_GET_TIME, |_, _| {
+        Ok(RvmHalImpl::current_time_nanos())
+    });
+}
+
+/// Dispatch the hypercall by `RAX`, and set the result to `RAX`.
+pub fn handle(vcpu: &mut Vcpu) {
+    let regs = vcpu.regs();
+    let nr = regs.rax;
+    let args = [regs.rdi, regs.rsi, regs.rdx, regs.rcx, regs.r8];
+    // the lock is released before calling the handler
+    let handler = HYPERCALLS.lock().get(&nr).copied();
+    let res = match handler {
+        _ if vcpu.guest_cpl().map_or(true, |cpl| cpl != 0) => Err(HypercallError::PermissionDenied),
+        Some(handler) => handler(vcpu, args),
+        None => Err(HypercallError::Unsupported),
+    };
+    debug!("VM exit: VMCALL({:#x}): {:#x?} -> {:x?}", nr, args, res);
+    vcpu.regs_mut().rax = match res {
+        Ok(value) => value,
+        Err(e) => e as i64 as u64,
+    };
+}
+
+/// Bitmap of the available hypercalls with numbers less than 64.
+fn features() -> u64 {
+    HYPERCALLS
+        .lock()
+        .keys()
+        .filter(|&&nr| nr < 64)
+        .fold(0, |bits, nr| bits | 1 << nr)
+}
+
+fn set_log_level(_vcpu: &mut Vcpu, args: [u64; 5]) -> HypercallResult {
+    let level = crate::logging::LEVELS
+        .get(args[0] as usize)
+        .copied()
+        .ok_or(HypercallError::InvalidParam)?;
+    crate::logging::set_level(level);
+    Ok(0)
+}
+
+fn shutdown(_vcpu: &mut Vcpu, args: [u64; 5]) -> HypercallResult {
+    let status = args[0] as u32;
+    vm::current()
+        .devices()
+        .request_power_event(PowerEvent::Exit(status));
+    Ok(0)
+}
+
+fn console_write(_vcpu: &mut Vcpu, args: [u64; 5]) -> HypercallResult {
+    let (gpa, len) = (args[0] as usize, args[1] as usize);
+    let len = len.min(CONSOLE_WRITE_MAX);
+    let vm = vm::current();
+    let mut buf = [0; CONSOLE_CHUNK_SIZE];
+    for offset in (0..len).step_by(buf.len()) {
+        let chunk = &mut buf[..(len - offset).min(CONSOLE_CHUNK_SIZE)];
+        let chunk_gpa = gpa.checked_add(offset).ok_or(HypercallError::BadAddress)?;
+        vm.gpm()
+            .read(chunk_gpa, chunk)
+            .map_err(|_| HypercallError::BadAddress)?;
+        chunk
+            .iter()
+            .for_each(|&c| crate::arch::uart::console_putchar(c));
+    }
+    Ok(len as u64)
+}
@@ -0,0 +1,421 @@
+//! Emulate guest MMIO accesses to unmapped guest physical memory, which cause
+//! EPT violations.
+//!
+//! The faulting instruction is fetched from the guest memory and decoded, only
+//! the `MOV` family of instructions with a memory operand is supported, which
+//! is what guest drivers use to access device registers.
+
+use rvm::arch::GuestModeState;
+use rvm::{GuestPhysAddr, RvmError, RvmResult, RvmVcpu};
+
+use super::device_emu::MmioDevice;
+use super::gpm::GuestPhysMemorySet;
+use super::hal::RvmHalImpl;
+use crate::mm::PAGE_SIZE;
+
+type Vcpu = RvmVcpu<RvmHalImpl>;
+
+const MAX_INSTR_LEN: usize = 15;
+const PTE_PRESENT: u64 = 1;
+const PTE_HUGE_PAGE: u64 = 1 << 7;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const EFER_LMA: u64 = 1 << 10;
+const CS_LONG_MODE: u32 = 1 << 13;
+const CS_DEFAULT_SIZE: u32 = 1 << 14;
+
+fn read_pte(gpm: &GuestPhysMemorySet, paddr: GuestPhysAddr, size: usize) -> RvmResult<u64> {
+    let mut buf = [0; 8];
+    gpm.read(paddr, &mut buf[..size])?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Translate the guest virtual (linear) address by walking the guest page
+/// tables, in the current paging mode of the guest.
+fn guest_virt_to_phys(
+    gpm: &GuestPhysMemorySet,
+    state: &GuestModeState,
+    vaddr: usize,
+) -> RvmResult<GuestPhysAddr> {
+    if state.cr0 & CR0_PG == 0 {
+        return Ok(vaddr);
+    }
+    let vaddr = vaddr as u64;
+    if state.cr4 & CR4_PAE == 0 {
+        // 32-bit paging, with 4M pages if CR4.PSE = 1
+        let pde_addr = (state.cr3 & 0xffff_f000) + (vaddr >> 22 & 0x3ff) * 4;
+        let pde = read_pte(gpm, pde_addr as _, 4)?;
+        if pde & PTE_PRESENT == 0 {
+            return Err(RvmError::BadState);
+        }
+        if pde & PTE_HUGE_PAGE != 0 && state.cr4 & CR4_PSE != 0 {
+            return Ok(((pde & 0xffc0_0000) | (vaddr & 0x3f_ffff)) as _);
+        }
+        let pte_addr = (pde & 0xffff_f000) + (vaddr >> 12 & 0x3ff) * 4;
+        let pte = read_pte(gpm, pte_addr as _, 4)?;
+        if pte & PTE_PRESENT == 0 {
+            return Err(RvmError::BadState);
+        }
+        return Ok(((pte & 0xffff_f000) | (vaddr & 0xfff)) as _);
+    }
+
+    // PAE paging with 3 levels, or 4-level paging in IA-32e mode
+    let (mut table, levels) = if state.efer & EFER_LMA != 0 {
+        (state.cr3 & PTE_ADDR_MASK, 4)
+    } else {
+        let pdpte = read_pte(gpm, ((state.cr3 & !0x1f) + (vaddr >> 30 & 3) * 8) as _, 8)?;
+        if pdpte & PTE_PRESENT == 0 {
+            return Err(RvmError::BadState);
+        }
+        (pdpte & PTE_ADDR_MASK, 2)
+    };
+    for level in (0..levels).rev() {
+        let shift = 12 + level * 9;
+        let entry = read_pte(gpm, (table + (vaddr >> shift & 0x1ff) * 8) as _, 8)?;
+        if entry & PTE_PRESENT == 0 {
+            return Err(RvmError::BadState);
+        }
+        let page_mask = (1 << shift) - 1;
+        if level == 0 || (level <= 2 && entry & PTE_HUGE_PAGE != 0) {
+            return Ok(((entry & PTE_ADDR_MASK & !page_mask) | (vaddr & page_mask)) as _);
+        }
+        table = entry & PTE_ADDR_MASK;
+    }
+    unreachable!()
+}
+
+/// Fetch the instruction bytes at the guest `RIP`, which may cross a page
+/// boundary or be shorter than `MAX_INSTR_LEN` at the end of the memory.
+fn fetch_instruction(
+    gpm: &GuestPhysMemorySet,
+    state: &GuestModeState,
+    rip: usize,
+) -> RvmResult<([u8; MAX_INSTR_LEN], usize)> {
+    let mut buf = [0; MAX_INSTR_LEN];
+    let mut len = 0;
+    let mut vaddr = state.cs_base as usize + rip;
+    while len < MAX_INSTR_LEN {
+        let paddr = guest_virt_to_phys(gpm, state, vaddr)?;
+        let n = (MAX_INSTR_LEN - len).min(PAGE_SIZE - (vaddr & (PAGE_SIZE - 1)));
+        if gpm.read(paddr, &mut buf[len..len + n]).is_err() {
+            break;
+        }
+        len += n;
+        vaddr += n;
+    }
+    if len == 0 {
+        return Err(RvmError::BadState);
+    }
+    Ok((buf, len))
+}
+
+/// The source of an MMIO write.
+#[derive(Debug, Clone, Copy)]
+enum WriteSource {
+    /// A general-purpose register, with the `REX` prefix present or not.
+    Reg(u8, bool),
+    Imm(u64),
+}
+
+/// The MMIO access performed by a decoded instruction.
+#[derive(Debug, Clone, Copy)]
+enum MmioOp {
+    /// Read `size` bytes and zero-extend to `dst_size` bytes in the register.
+    Read {
+        reg: u8,
+        rex: bool,
+        size: u8,
+        dst_size: u8,
+    },
+    Write {
+        src: WriteSource,
+        size: u8,
+    },
+}
+
+#[derive(Debug)]
+struct MmioInstr {
+    op: MmioOp,
+    len: u8,
+}
+
+/// Bytes used by the ModR/M addressing (the SIB byte and displacement),
+/// following the ModR/M byte.
+fn modrm_extra_len(modrm: u8, bytes: &[u8], addr_size: u8) -> RvmResult<usize> {
+    let mode = modrm >> 6;
+    let rm = modrm & 7;
+    if mode == 3 {
+        return Err(RvmError::InvalidParam); // register operand, not a memory access
+    }
+    if addr_size == 2 {
+        return Ok(match (mode, rm) {
+            (0, 6) | (2, _) => 2,
+            (1, _) => 1,
+            _ => 0,
+        });
+    }
+    let mut len = 0;
+    let mut base = rm;
+    if rm == 4 {
+        let sib = *bytes.first().ok_or(RvmError::InvalidParam)?;
+        base = sib & 7;
+        len += 1;
+    }
+    len += match mode {
+        0 if base == 5 => 4, // disp32, or RIP-relative in 64-bit mode
+        1 => 1,
+        2 => 4,
+        _ => 0,
+    };
+    Ok(len)
+}
+
+fn decode(bytes: &[u8], state: &GuestModeState) -> RvmResult<MmioInstr> {
+    let long_mode = state.efer & EFER_LMA != 0 && state.cs_access_rights & CS_LONG_MODE != 0;
+    let default_32 = long_mode || state.cs_access_rights & CS_DEFAULT_SIZE != 0;
+
+    let mut pos = 0;
+    let mut opsize_prefix = false;
+    let mut addrsize_prefix = false;
+    loop {
+        match bytes.get(pos) {
+            Some(0x66) => opsize_prefix = true,
+            Some(0x67) => addrsize_prefix = true,
+            Some(0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0) => {}
+            Some(_) => break,
+            None => return Err(RvmError::InvalidParam),
+        }
+        pos += 1;
+    }
+    let mut rex = 0;
+    if long_mode {
+        if let Some(&b @ 0x40..=0x4f) = bytes.get(pos) {
+            rex = b;
+            pos += 1;
+        }
+    }
+    let rex_w = rex & 0x8 != 0;
+    let rex_r = (rex & 0x4) << 1;
+
+    let opsize = match (rex_w, opsize_prefix, default_32) {
+        (true, _, _) => 8,
+        (false, true, true) | (false, false, false) => 2,
+        _ => 4,
+    };
+    let addr_size = match (long_mode, addrsize_prefix, default_32) {
+        (true, false, _) => 8,
+        (true, true, _) | (false, false, true) | (false, true, false) => 4,
+        _ => 2,
+    };
+
+    let byte = |i: usize| bytes.get(i).copied().ok_or(RvmError::InvalidParam);
+    let mut opcode = byte(pos)? as u16;
+    pos += 1;
+    if opcode == 0x0f {
+        opcode = 0x0f00 | byte(pos)? as u16;
+        pos += 1;
+    }
+    let modrm = byte(pos)?;
+    pos += 1;
+    pos += modrm_extra_len(modrm, &bytes[pos..], addr_size)?;
+    let reg = ((modrm >> 3) & 7) | rex_r;
+    let has_rex = rex != 0;
+
+    let op = match opcode {
+        0x88 => MmioOp::Write {
+            src: WriteSource::Reg(reg, has_rex),
+            size: 1,
+        },
+        0x89 => MmioOp::Write {
+            src: WriteSource::Reg(reg, has_rex),
+            size: opsize,
+        },
+        0x8a => MmioOp::Read {
+            reg,
+            rex: has_rex,
+            size: 1,
+            dst_size: 1,
+        },
+        0x8b => MmioOp::Read {
+            reg,
+            rex: has_rex,
+            size: opsize,
+            dst_size: opsize,
+        },
+        0xc6 if reg & 7 == 0 => {
+            let imm = byte(pos)? as u64;
+            pos += 1;
+            MmioOp::Write {
+                src: WriteSource::Imm(imm),
+                size: 1,
+            }
+        }
+        0xc7 if reg & 7 == 0 => {
+            let imm_size = opsize.min(4) as usize;
+            let mut imm = [0; 8];
+            imm[..imm_size].copy_from_slice(
+                bytes
+                    .get(pos..pos + imm_size)
+                    .ok_or(RvmError::InvalidParam)?,
+            );
+            pos += imm_size;
+            // imm32 is sign-extended to 64 bits
+            let imm = match imm_size {
+                2 => u16::from_le_bytes([imm[0], imm[1]]) as u64,
+                _ => i32::from_le_bytes([imm[0], imm[1], imm[2], imm[3]]) as i64 as u64,
+            };
+            MmioOp::Write {
+                src: WriteSource::Imm(imm),
+                size: opsize,
+            }
+        }
+        0x0fb6 | 0x0fb7 => MmioOp::Read {
+            reg,
+            rex: has_rex,
+            size: if opcode == 0x0fb6 { 1 } else { 2 },
+            dst_size: opsize,
+        },
+        _ => {
+            warn!("Unsupported MMIO instruction: {:02x?}", &bytes[..pos]);
+            return Err(RvmError::Unsupported);
+        }
+    };
+    Ok(MmioInstr { op, len: pos as u8 })
+}
+
+fn read_reg(vcpu: &Vcpu, reg: u8) -> u64 {
+    let regs = vcpu.regs();
+    match reg {
+        0 => regs.rax,
+        1 => regs.rcx,
+        2 => regs.rdx,
+        3 => regs.rbx,
+        4 => vcpu.stack_pointer() as u64,
+        5 => regs.rbp,
+        6 => regs.rsi,
+        7 => regs.rdi,
+        8 => regs.r8,
+        9 => regs.r9,
+        10 => regs.r10,
+        11 => regs.r11,
+        12 => regs.r12,
+        13 => regs.r13,
+        14 => regs.r14,
+        15 => regs.r15,
+        _ => unreachable!(),
+    }
+}
+
+fn write_reg(vcpu: &mut Vcpu, reg: u8, value: u64) {
+    if reg == 4 {
+        vcpu.set_stack_pointer(value as usize);
+        return;
+    }
+    let regs = vcpu.regs_mut();
+    let r = match reg {
+        0 => &mut regs.rax,
+        1 => &mut regs.rcx,
+        2 => &mut regs.rdx,
+        3 => &mut regs.rbx,
+        5 => &mut regs.rbp,
+        6 => &mut regs.rsi,
+        7 => &mut regs.rdi,
+        8 => &mut regs.r8,
+        9 => &mut regs.r9,
+        10 => &mut regs.r10,
+        11 => &mut regs.r11,
+        12 => &mut regs.r12,
+        13 => &mut regs.r13,
+        14 => &mut regs.r14,
+        15 => &mut regs.r15,
+        _ => unreachable!(),
+    };
+    *r = value;
+}
+
+/// Read the low `size` bytes of a register. Without the `REX` prefix, the
+/// byte registers 4 to 7 are `AH`, `CH`, `DH` and `BH`.
+fn read_operand(vcpu: &Vcpu, reg: u8, rex: bool, size: u8) -> u64 {
+    if size == 1 && !rex && (4..8).contains(&reg) {
+        return (read_reg(vcpu, reg - 4) >> 8) & 0xff;
+    }
+    match size {
+        8 => read_reg(vcpu, reg),
+        _ => read_reg(vcpu, reg) & ((1 << (size * 8)) - 1),
+    }
+}
+
+/// Write the low `size` bytes of a register, a 32-bit write clears the upper
+/// 32 bits, and 8-bit or 16-bit writes keep the other bits.
+fn write_operand(vcpu: &mut Vcpu, reg: u8, rex: bool, size: u8, value: u64) {
+    if size == 1 && !rex && (4..8).contains(&reg) {
+        let old = read_reg(vcpu, reg - 4);
+        write_reg(vcpu, reg - 4, (old & !0xff00) | (value & 0xff) << 8);
+        return;
+    }
+    let value = match size {
+        1 | 2 => {
+            let mask = (1 << (size * 8)) - 1;
+            (read_reg(vcpu, reg) & !mask) | (value & mask)
+        }
+        4 => value & 0xffff_ffff,
+        _ => value,
+    };
+    write_reg(vcpu, reg, value);
+}
+
+/// Decode the guest instruction that caused the MMIO exit, perform the access
+/// by `read` or `write` with the access size, and advance the guest `RIP` past
+/// the instruction.
+pub fn emulate_mmio(
+    vcpu: &mut Vcpu,
+    gpm: &GuestPhysMemorySet,
+    read: impl FnOnce(&mut Vcpu, u8) -> RvmResult<u64>,
+    write: impl FnOnce(&mut Vcpu, u8, u64) -> RvmResult,
+) -> RvmResult {
+    let state = vcpu.guest_mode_state()?;
+    let (bytes, len) = fetch_instruction(gpm, &state, vcpu.rip())?;
+    let instr = decode(&bytes[..len], &state)?;
+    trace!("MMIO access: {:x?}", instr);
+    match instr.op {
+        MmioOp::Read {
+            reg,
+            rex,
+            size,
+            dst_size,
+        } => {
+            let value = read(vcpu, size)?;
+            write_operand(vcpu, reg, rex, dst_size, value);
+        }
+        MmioOp::Write { src, size } => {
+            let value = match src {
+                WriteSource::Reg(reg, rex) => read_operand(vcpu, reg, rex, size),
+                WriteSource::Imm(imm) => imm,
+            };
+            let value = match size {
+                8 => value,
+                _ => value & ((1 << (size * 8)) - 1),
+            };
+            write(vcpu, size, value)?;
+        }
+    }
+    vcpu.advance_rip(instr.len)
+}
+
+/// Emulate the guest instruction accessing `dev` at `gpa`.
+pub fn handle_mmio(
+    vcpu: &mut Vcpu,
+    gpm: &GuestPhysMemorySet,
+    dev: &dyn MmioDevice,
+    gpa: GuestPhysAddr,
+) -> RvmResult {
+    emulate_mmio(
+        vcpu,
+        gpm,
+        |_, size| dev.read(gpa, size),
+        |_, size, value| dev.write(gpa, size, value),
+    )
+}
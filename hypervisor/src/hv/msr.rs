@@ -0,0 +1,170 @@
+//! Model-specific registers of the guest.
+//!
+//! The MSRs in the [`MsrTable`] of the VM are handled by their [`MsrHandler`],
+//! and the others in the MSR bitmap are accessed by the guest directly. Other
+//! MSRs are not supported, which are handled by the fault policy of the VM.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use rvm::{RvmError, RvmResult, RvmVcpu};
+
+use super::device_emu::{MsrDevice, VirtDeviceList};
+use super::gconfig::{MsrConfig, MsrEmulation};
+use super::hal::RvmHalImpl;
+use super::mtrr::GuestMtrrs;
+use crate::arch::instructions::{rdmsr_safe, wrmsr_safe};
+
+type Vcpu = RvmVcpu<RvmHalImpl>;
+
+const MISC_ENABLE_FAST_STRINGS: u64 = 1 << 0;
+const MISC_ENABLE_BTS_UNAVAILABLE: u64 = 1 << 11;
+const MISC_ENABLE_PEBS_UNAVAILABLE: u64 = 1 << 12;
+
+/// The MSRs that can be passed through: switched by the VMCS on VM entries
+/// and exits, or not used by the hypervisor.
+const PASSTHROUGH_MSRS: &[u32] = &[
+    x86::msr::IA32_SYSENTER_CS,
+    x86::msr::IA32_SYSENTER_ESP,
+    x86::msr::IA32_SYSENTER_EIP,
+    x86::msr::IA32_PAT,
+    x86::msr::IA32_EFER,
+    x86::msr::IA32_FS_BASE,
+    x86::msr::IA32_GS_BASE,
+    x86::msr::IA32_STAR,
+    x86::msr::IA32_LSTAR,
+    x86::msr::IA32_CSTAR,
+    x86::msr::IA32_FMASK,
+    x86::msr::IA32_KERNEL_GSBASE,
+    x86::msr::IA32_TSC_AUX,
+];
+
+/// How accesses to an MSR are handled.
+pub enum MsrHandler {
+    /// Access the MSR of the host, without VM exits if it is in the MSR bitmap.
+    /// Faults of the host access cause `#GP(0)` in the guest.
+    Passthrough,
+    /// Reads return the value, and writes are ignored.
+    Fixed(u64),
+    /// Reads return the last value written, starting with the initial value.
+    Shadow(AtomicU64),
+    /// Emulated by a device.
+    Device(Arc<dyn MsrDevice>),
+}
+
+impl MsrHandler {
+    fn read(&self, vcpu: &mut Vcpu, msr: u32) -> RvmResult<u64> {
+        match self {
+            Self::Passthrough => rdmsr_safe(msr).ok_or(RvmError::InvalidParam),
+            Self::Fixed(value) => Ok(*value),
+            Self::Shadow(value) => Ok(value.load(Ordering::Relaxed)),
+            Self::Device(dev) => dev.rdmsr(vcpu, msr),
+        }
+    }
+
+    fn write(&self, vcpu: &mut Vcpu, msr: u32, value: u64) -> RvmResult {
+        match self {
+            Self::Passthrough => {
+                if !wrmsr_safe(msr, value) {
+                    return Err(RvmError::InvalidParam);
+                }
+            }
+            Self::Fixed(fixed) => {
+                if value != *fixed {
+                    debug!("Ignored WRMSR({:#x}) <- {:#x}", msr, value);
+                }
+            }
+            Self::Shadow(shadow) => shadow.store(value, Ordering::Relaxed),
+            Self::Device(dev) => return dev.wrmsr(vcpu, msr, value),
+        }
+        Ok(())
+    }
+}
+
+/// The MSRs handled by the hypervisor, keyed by the MSR index.
+pub struct MsrTable {
+    handlers: BTreeMap<u32, MsrHandler>,
+}
+
+impl MsrTable {
+    /// Build the table from the MTRRs, the MSRs of the devices and the MSRs in
+    /// the guest configuration, which take precedence. Returns an error if the
+    /// configuration passes through an MSR not in [`PASSTHROUGH_MSRS`].
+    pub fn new(
+        devices: &VirtDeviceList,
+        mtrrs: &Arc<GuestMtrrs>,
+        configs: &[MsrConfig],
+    ) -> RvmResult<Self> {
+        let mut table = Self {
+            handlers: BTreeMap::new(),
+        };
+        table.register(
+            x86::msr::IA32_MISC_ENABLE,
+            MsrHandler::Fixed(
+                MISC_ENABLE_FAST_STRINGS
+                    | MISC_ENABLE_BTS_UNAVAILABLE
+                    | MISC_ENABLE_PEBS_UNAVAILABLE,
+            ),
+        );
+        // `RDTSCP` reads it without VM exits
+        table.register(x86::msr::IA32_TSC_AUX, MsrHandler::Passthrough);
+        for range in GuestMtrrs::msr_ranges() {
+            table.register_device(range, mtrrs.clone());
+        }
+        devices.register_msrs(&mut table);
+        for c in configs {
+            let handler = match c.emulation {
+                MsrEmulation::Passthrough if !PASSTHROUGH_MSRS.contains(&c.msr) => {
+                    warn!("MSR {:#x} can not be passed through", c.msr);
+                    return Err(RvmError::InvalidParam);
+                }
+                MsrEmulation::Passthrough => MsrHandler::Passthrough,
+                MsrEmulation::Fixed(value) => MsrHandler::Fixed(value),
+                MsrEmulation::Shadow(value) => MsrHandler::Shadow(AtomicU64::new(value)),
+            };
+            table.register(c.msr, handler);
+        }
+        Ok(table)
+    }
+
+    /// Set the handler of the MSR, replacing the old one.
+    pub fn register(&mut self, msr: u32, handler: MsrHandler) {
+        self.handlers.insert(msr, handler);
+    }
+
+    /// Emulate the MSRs in `range` by the device.
+    pub fn register_device(&mut self, range: Range<u32>, dev: Arc<dyn MsrDevice>) {
+        for msr in range {
+            self.register(msr, MsrHandler::Device(dev.clone()));
+        }
+    }
+
+    /// Intercept the MSRs in the table except the passthrough ones.
+    pub fn setup_intercepts(&self, vcpu: &mut Vcpu) {
+        for (&msr, handler) in &self.handlers {
+            let intercept = !matches!(handler, MsrHandler::Passthrough);
+            if vcpu.set_msr_intercept(msr, intercept, intercept).is_err() {
+                debug!("MSR {:#x} is passed through by VM exits", msr);
+            }
+        }
+    }
+
+    /// Handle `RDMSR` of the guest, returns [`RvmError::InvalidParam`] if it
+    /// should cause `#GP(0)`.
+    pub fn read(&self, vcpu: &mut Vcpu, msr: u32) -> RvmResult<u64> {
+        match self.handlers.get(&msr) {
+            Some(handler) => handler.read(vcpu, msr),
+            None => Err(RvmError::Unsupported),
+        }
+    }
+
+    /// Handle `WRMSR` of the guest, returns [`RvmError::InvalidParam`] if it
+    /// should cause `#GP(0)`.
+    pub fn write(&self, vcpu: &mut Vcpu, msr: u32, value: u64) -> RvmResult {
+        match self.handlers.get(&msr) {
+            Some(handler) => handler.write(vcpu, msr, value),
+            None => Err(RvmError::Unsupported),
+        }
+    }
+}
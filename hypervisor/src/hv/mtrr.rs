@@ -0,0 +1,274 @@
+//! Emulated memory type range registers (MTRRs) of the guest. (SDM Vol. 3A,
+//! Section 11.11)
+//!
+//! The memory type of each guest page in the nested page table is computed
+//! from the guest MTRRs and the region flags, and the guest PAT is still
+//! effective unless the hypervisor overrides the guest memory type:
+//!
+//! * Normal memory has the memory type of the guest MTRRs.
+//! * Device memory is uncached or write-combining. If the guest MTRRs make it
+//!   cacheable, it is uncached and the guest PAT is ignored.
+//!
+//! The MTRRs start enabled with the write-back default type, as set by the
+//! firmware, and the memory types are recomputed when they are changed.
+
+use core::ops::Range;
+
+use rvm::{GuestPhysAddr, MemFlags, RvmError, RvmResult, RvmVcpu};
+use spin::Mutex;
+
+use super::device_emu::MsrDevice;
+use super::hal::RvmHalImpl;
+use super::vm;
+
+type Vcpu = RvmVcpu<RvmHalImpl>;
+
+const IA32_MTRRCAP: u32 = 0xfe;
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+const IA32_MTRR_FIX16K_A0000: u32 = 0x259;
+const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+const IA32_MTRR_FIX4K_F8000: u32 = 0x26f;
+const IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+
+/// Number of the variable ranges.
+const VAR_COUNT: usize = 8;
+const FIXED_COUNT: usize = 11;
+
+const MTRRCAP_FIX: u64 = 1 << 8;
+const MTRRCAP_WC: u64 = 1 << 10;
+
+const DEF_TYPE_MASK: u64 = 0xff;
+const DEF_TYPE_FE: u64 = 1 << 10;
+const DEF_TYPE_E: u64 = 1 << 11;
+
+const PHYSMASK_VALID: u64 = 1 << 11;
+const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // 12..52
+
+/// The end of the memory covered by the fixed ranges.
+const FIXED_RANGE_END: GuestPhysAddr = 0x10_0000;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemType {
+    Uncached = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+}
+
+impl MemType {
+    fn from_bits(bits: u8) -> Option<Self> {
+        Some(match bits {
+            0 => Self::Uncached,
+            1 => Self::WriteCombining,
+            4 => Self::WriteThrough,
+            5 => Self::WriteProtected,
+            6 => Self::WriteBack,
+            _ => return None,
+        })
+    }
+
+    fn flags(self) -> MemFlags {
+        match self {
+            Self::Uncached => MemFlags::UNCACHED,
+            Self::WriteCombining => MemFlags::WRITE_COMBINING,
+            Self::WriteThrough => MemFlags::WRITE_THROUGH,
+            Self::WriteProtected => MemFlags::WRITE_PROTECTED,
+            Self::WriteBack => MemFlags::empty(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct MtrrState {
+    def_type: u64,
+    /// `IA32_MTRR_FIX*` in the order of addresses, 8 ranges each.
+    fixed: [u64; FIXED_COUNT],
+    /// `IA32_MTRR_PHYSBASEn` and `IA32_MTRR_PHYSMASKn`.
+    variable: [(u64, u64); VAR_COUNT],
+}
+
+impl MtrrState {
+    /// Index in `fixed` of a fixed range MSR.
+    fn fixed_index(msr: u32) -> Option<usize> {
+        match msr {
+            IA32_MTRR_FIX64K_00000 => Some(0),
+            IA32_MTRR_FIX16K_80000 => Some(1),
+            IA32_MTRR_FIX16K_A0000 => Some(2),
+            IA32_MTRR_FIX4K_C0000..=IA32_MTRR_FIX4K_F8000 => {
+                Some((msr - IA32_MTRR_FIX4K_C0000) as usize + 3)
+            }
+            _ => None,
+        }
+    }
+
+    fn read(&self, msr: u32) -> RvmResult<u64> {
+        Ok(match msr {
+            IA32_MTRRCAP => MTRRCAP_WC | MTRRCAP_FIX | VAR_COUNT as u64,
+            IA32_MTRR_DEF_TYPE => self.def_type,
+            _ if Self::fixed_index(msr).is_some() => self.fixed[Self::fixed_index(msr).unwrap()],
+            _ => {
+                let (base, mask) = self.variable[(msr - IA32_MTRR_PHYSBASE0) as usize / 2];
+                if msr % 2 == 0 {
+                    base
+                } else {
+                    mask
+                }
+            }
+        })
+    }
+
+    /// Write an MTRR, returns an error if it should cause #GP for reserved bits
+    /// or invalid memory types.
+    fn write(&mut self, msr: u32, value: u64) -> RvmResult {
+        let valid_type = |bits: u64| MemType::from_bits(bits as u8).is_some();
+        match msr {
+            IA32_MTRRCAP => return Err(RvmError::InvalidParam),
+            IA32_MTRR_DEF_TYPE => {
+                if value & !(DEF_TYPE_MASK | DEF_TYPE_FE | DEF_TYPE_E) != 0
+                    || !valid_type(value & DEF_TYPE_MASK)
+                {
+                    return Err(RvmError::InvalidParam);
+                }
+                self.def_type = value;
+            }
+            _ if Self::fixed_index(msr).is_some() => {
+                if !(0..64).step_by(8).all(|i| valid_type((value >> i) & 0xff)) {
+                    return Err(RvmError::InvalidParam);
+                }
+                self.fixed[Self::fixed_index(msr).unwrap()] = value;
+            }
+            _ => {
+                let range = &mut self.variable[(msr - IA32_MTRR_PHYSBASE0) as usize / 2];
+                if msr % 2 == 0 {
+                    if value & !(PHYS_ADDR_MASK | 0xff) != 0 || !valid_type(value & 0xff) {
+                        return Err(RvmError::InvalidParam);
+                    }
+                    range.0 = value;
+                } else {
+                    if value & !(PHYS_ADDR_MASK | PHYSMASK_VALID) != 0 {
+                        return Err(RvmError::InvalidParam);
+                    }
+                    range.1 = value;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The memory type of the page at `gpa`. (SDM Vol. 3A, Section 11.11.4.1)
+    fn mem_type(&self, gpa: GuestPhysAddr) -> MemType {
+        if self.def_type & DEF_TYPE_E == 0 {
+            return MemType::Uncached;
+        }
+        if self.def_type & DEF_TYPE_FE != 0 && gpa < FIXED_RANGE_END {
+            // 8 ranges of 64K, 16 ranges of 16K, and 64 ranges of 4K
+            let (index, shift) = match gpa {
+                0..=0x7_ffff => (0, gpa >> 16),
+                0x8_0000..=0xb_ffff => (1 + (gpa - 0x8_0000) / 0x2_0000, (gpa >> 14) % 8),
+                _ => (3 + (gpa - 0xc_0000) / 0x8000, (gpa >> 12) % 8),
+            };
+            let bits = (self.fixed[index] >> (shift * 8)) as u8;
+            return MemType::from_bits(bits).unwrap();
+        }
+
+        let gpa = gpa as u64;
+        let mut matched = None;
+        for &(base, mask) in &self.variable {
+            if mask & PHYSMASK_VALID == 0
+                || gpa & mask & PHYS_ADDR_MASK != base & mask & PHYS_ADDR_MASK
+            {
+                continue;
+            }
+            let ty = MemType::from_bits(base as u8).unwrap();
+            matched = Some(match (matched, ty) {
+                (None, ty) => ty,
+                (Some(old), ty) if old == ty => ty,
+                (Some(MemType::Uncached), _) | (_, MemType::Uncached) => MemType::Uncached,
+                (
+                    Some(MemType::WriteThrough | MemType::WriteBack),
+                    MemType::WriteThrough | MemType::WriteBack,
+                ) => MemType::WriteThrough,
+                _ => MemType::Uncached, // undefined, use the strongest type
+            });
+        }
+        matched.unwrap_or_else(|| MemType::from_bits(self.def_type as u8).unwrap())
+    }
+
+    /// The flags of the page at `gpa` in the region with `flags`.
+    fn page_flags(&self, gpa: GuestPhysAddr, flags: MemFlags) -> MemFlags {
+        let flags =
+            flags & (MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE | MemFlags::DEVICE);
+        let mem_type = self.mem_type(gpa);
+        if !flags.contains(MemFlags::DEVICE) {
+            return flags | mem_type.flags();
+        }
+        match mem_type {
+            MemType::Uncached | MemType::WriteCombining => flags | mem_type.flags(),
+            _ => flags | MemFlags::UNCACHED | MemFlags::IGNORE_PAT,
+        }
+    }
+}
+
+/// The MTRRs of the only vCPU.
+pub struct GuestMtrrs {
+    state: Mutex<MtrrState>,
+}
+
+impl Default for GuestMtrrs {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(MtrrState {
+                def_type: DEF_TYPE_E | MemType::WriteBack as u64,
+                fixed: [0; FIXED_COUNT],
+                variable: [(0, 0); VAR_COUNT],
+            }),
+        }
+    }
+}
+
+impl GuestMtrrs {
+    /// The MSRs of the MTRRs, all accesses to them cause VM exits.
+    pub fn msr_ranges() -> [Range<u32>; 6] {
+        [
+            IA32_MTRRCAP..IA32_MTRRCAP + 1,
+            IA32_MTRR_PHYSBASE0..IA32_MTRR_PHYSBASE0 + VAR_COUNT as u32 * 2,
+            IA32_MTRR_FIX64K_00000..IA32_MTRR_FIX64K_00000 + 1,
+            IA32_MTRR_FIX16K_80000..IA32_MTRR_FIX16K_A0000 + 1,
+            IA32_MTRR_FIX4K_C0000..IA32_MTRR_FIX4K_F8000 + 1,
+            IA32_MTRR_DEF_TYPE..IA32_MTRR_DEF_TYPE + 1,
+        ]
+    }
+
+    /// Returns a function computing the flags of a guest page from the
+    /// flags of its region, by the current MTRRs.
+    pub fn page_flags(&self) -> impl Fn(GuestPhysAddr, MemFlags) -> MemFlags {
+        let state = self.state.lock().clone();
+        move |gpa, flags| state.page_flags(gpa, flags)
+    }
+}
+
+impl MsrDevice for GuestMtrrs {
+    fn rdmsr(&self, _vcpu: &mut Vcpu, msr: u32) -> RvmResult<u64> {
+        self.state.lock().read(msr)
+    }
+
+    fn wrmsr(&self, vcpu: &mut Vcpu, msr: u32, value: u64) -> RvmResult {
+        let changed = {
+            let mut state = self.state.lock();
+            let old = state.read(msr)?;
+            state.write(msr, value)?;
+            // the ranges have no effect until the MTRRs are enabled
+            old != value && (msr == IA32_MTRR_DEF_TYPE || state.def_type & DEF_TYPE_E != 0)
+        };
+        if changed {
+            debug!("MTRR {:#x} changed to {:#x}", msr, value);
+            vm::current().update_mem_types()?;
+            vcpu.flush_nested_page_table()?;
+        }
+        Ok(())
+    }
+}
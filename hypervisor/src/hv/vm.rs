@@ -0,0 +1,258 @@
+//! Guest VMs built from the guest configuration.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use rvm::{RvmError, RvmPerCpu, RvmResult, RvmVcpu};
+use spin::Mutex;
+
+use super::boot::{
+    linux,
+    multiboot::{self, GuestModule},
+};
+use super::cpuid::CpuidTable;
+use super::device_emu::{PowerEvent, VirtDeviceList};
+use super::gconfig::{GuestConfig, ImageConfig, ImageKind, ImageSource, ResetPolicy};
+use super::gpm::{GuestPhysMemorySet, MapRegion};
+use super::hal::RvmHalImpl;
+use super::msr::MsrTable;
+use super::mtrr::GuestMtrrs;
+use crate::mm::address::phys_to_virt;
+use crate::timer::{current_time, TimeValue};
+
+type Vcpu = RvmVcpu<RvmHalImpl>;
+
+/// The exit status of a VM stopped by the hypervisor on errors.
+pub const EXIT_FAILURE: u32 = 1;
+
+static CURRENT_VM: Mutex<Option<Arc<Vm>>> = Mutex::new(None);
+
+pub struct Vm {
+    id: usize,
+    config: GuestConfig,
+    gpm: GuestPhysMemorySet,
+    devices: VirtDeviceList,
+    cpuid: CpuidTable,
+    msrs: MsrTable,
+    mtrrs: Arc<GuestMtrrs>,
+    start_time: TimeValue,
+    exit_count: AtomicUsize,
+}
+
+fn image_data(image: &ImageConfig) -> RvmResult<&'static [u8]> {
+    match &image.source {
+        ImageSource::Module(name) => match crate::multiboot::find_module(name) {
+            Some(m) => Ok(m.data()),
+            None => {
+                warn!("Multiboot module {:?} not found", name);
+                Err(RvmError::InvalidParam)
+            }
+        },
+        &ImageSource::Phys { paddr, size } => {
+            Ok(unsafe { core::slice::from_raw_parts(phys_to_virt(paddr) as *const u8, size) })
+        }
+    }
+}
+
+impl Vm {
+    /// Create the guest memory and devices from `config`.
+    pub fn new(id: usize, config: GuestConfig) -> RvmResult<Self> {
+        let mut gpm = GuestPhysMemorySet::new()?;
+        for m in &config.memory {
+            let region = match m.hpa {
+                Some(hpa) => MapRegion::new_offset(m.gpa, hpa, m.size, m.flags),
+                None => MapRegion::new_framed(m.gpa, m.size, m.flags)?,
+            };
+            gpm.map_region(region)?;
+        }
+        let devices = VirtDeviceList::new(&config.devices)?;
+        for ports in &config.io_passthrough {
+            if let Some(port) = ports
+                .clone()
+                .find(|&p| devices.find_port_io_device(p).is_some())
+            {
+                warn!(
+                    "Passthrough I/O port {:#x} is used by an emulated device",
+                    port
+                );
+                return Err(RvmError::InvalidParam);
+            }
+        }
+        let cpuid = CpuidTable::new(config.cpuid_policy, &config.cpuid);
+        let mtrrs = Arc::new(GuestMtrrs::default());
+        let msrs = MsrTable::new(&devices, &mtrrs, &config.msrs)?;
+        let vm = Self {
+            id,
+            config,
+            gpm,
+            devices,
+            cpuid,
+            msrs,
+            mtrrs,
+            start_time: current_time(),
+            exit_count: AtomicUsize::new(0),
+        };
+        vm.update_mem_types()?;
+        Ok(vm)
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn config(&self) -> &GuestConfig {
+        &self.config
+    }
+
+    pub fn gpm(&self) -> &GuestPhysMemorySet {
+        &self.gpm
+    }
+
+    pub fn devices(&self) -> &VirtDeviceList {
+        &self.devices
+    }
+
+    pub fn cpuid(&self) -> &CpuidTable {
+        &self.cpuid
+    }
+
+    pub fn msrs(&self) -> &MsrTable {
+        &self.msrs
+    }
+
+    /// Recompute the memory types of the guest memory from the guest MTRRs,
+    /// the vCPU must flush the nested page table after the update.
+    pub fn update_mem_types(&self) -> RvmResult {
+        self.gpm.update_mem_types(self.mtrrs.page_flags())
+    }
+
+    /// Count a VM exit for the summary printed when the VM stops.
+    pub fn count_exit(&self) {
+        self.exit_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Create the vCPU on the current physical CPU, and load the guest images
+    /// with the boot protocol of the kernel.
+    pub fn create_vcpu(&self, percpu: &RvmPerCpu<RvmHalImpl>) -> RvmResult<Vcpu> {
+        let mut vcpu = percpu.create_vcpu(self.config.entry, self.gpm.nest_page_table_root())?;
+        self.msrs.setup_intercepts(&mut vcpu);
+        for ports in &self.config.io_passthrough {
+            vcpu.set_io_intercept(ports.clone(), false);
+        }
+        self.setup_boot(&mut vcpu)?;
+        Ok(vcpu)
+    }
+
+    /// Put the vCPU of another VM with the same configuration into the reset
+    /// state to run on this VM, and load the guest images again.
+    fn reset_vcpu(&self, vcpu: &mut Vcpu) -> RvmResult {
+        vcpu.reset(self.config.entry, self.gpm.nest_page_table_root())?;
+        self.msrs.setup_intercepts(vcpu);
+        self.setup_boot(vcpu)
+    }
+
+    fn setup_boot(&self, vcpu: &mut Vcpu) -> RvmResult {
+        let config = &self.config;
+        self.devices.lapic().setup_virtual_wire(vcpu)?;
+        if let Some(kernel) = config.image(ImageKind::Kernel) {
+            let image = image_data(kernel)?;
+            if linux::is_bzimage(image) {
+                // enter the 64-bit Linux kernel directly, without the BIOS
+                let initrd = match config.image(ImageKind::Initrd) {
+                    Some(initrd) => image_data(initrd)?,
+                    None => &[],
+                };
+                return linux::setup_linux_boot(&self.gpm, vcpu, image, initrd, &config.cmdline);
+            }
+        }
+
+        // copy BIOS, kernel and module images
+        let mut modules = Vec::new();
+        for image in &config.images {
+            if image.kind == ImageKind::Initrd {
+                continue;
+            }
+            let data = image_data(image)?;
+            self.gpm.write(image.gpa, data)?;
+            if image.kind == ImageKind::Module {
+                modules.push(GuestModule {
+                    gpa: image.gpa,
+                    size: data.len(),
+                    cmdline: &image.cmdline,
+                });
+            }
+        }
+
+        // pass the memory map, command line and modules to the guest
+        multiboot::setup_multiboot_info(&self.gpm, config.boot_info, &config.cmdline, &modules)?;
+        vcpu.regs_mut().rbx = config.boot_info as u64;
+        Ok(())
+    }
+}
+
+/// Set the VM running on the current physical CPU.
+pub fn set_current(vm: Arc<Vm>) {
+    *CURRENT_VM.lock() = Some(vm);
+}
+
+/// The VM running on the current physical CPU.
+pub fn current() -> Arc<Vm> {
+    CURRENT_VM
+        .lock()
+        .clone()
+        .expect("No VM is running on this CPU")
+}
+
+/// Handle the reset, power-off or exit requested by the guest on the current
+/// VM. A reset is handled by the reset policy of the VM, the VM is re-created
+/// with fresh memory and devices on reboot.
+pub fn handle_power_event(vcpu: &mut Vcpu, event: PowerEvent) -> RvmResult {
+    let vm = current();
+    match (event, vm.config.reset_policy) {
+        (PowerEvent::Reset, ResetPolicy::Reboot) => {}
+        (PowerEvent::Reset, ResetPolicy::Dump) => {
+            println!("VM {} reset:\n{:#x?}", vm.id, vcpu);
+            stop(vm, EXIT_FAILURE)
+        }
+        (PowerEvent::Reset, ResetPolicy::Halt) => stop(vm, EXIT_FAILURE),
+        (PowerEvent::PowerOff, _) => stop(vm, 0),
+        (PowerEvent::Exit(status), _) => stop(vm, status),
+    }
+
+    // drop the old VM first to free its memory
+    let (id, config) = (vm.id, vm.config.clone());
+    drop(vm);
+    CURRENT_VM.lock().take();
+    // no VM is current now, so the errors can not be handled by VM exits
+    let vm = match Vm::new(id, config) {
+        Ok(vm) => Arc::new(vm),
+        Err(e) => {
+            error!("Failed to re-create VM {}: {:?}", id, e);
+            super::shutdown(EXIT_FAILURE);
+        }
+    };
+    if let Err(e) = vm.reset_vcpu(vcpu) {
+        error!("Failed to reset the vCPU of VM {}: {:?}", id, e);
+        super::shutdown(EXIT_FAILURE);
+    }
+    println!("VM {} reset", id);
+    set_current(vm);
+    Ok(())
+}
+
+/// Tear down the VM with the exit `status`. As it's the last VM, the
+/// hypervisor is shut down then.
+pub fn stop(vm: Arc<Vm>, status: u32) -> ! {
+    let run_time = current_time().saturating_sub(vm.start_time);
+    println!(
+        "VM {} stopped with status {:#x}: {} VM exits in {}.{:03}s",
+        vm.id,
+        status,
+        vm.exit_count.load(Ordering::Relaxed),
+        run_time.as_secs(),
+        run_time.subsec_millis(),
+    );
+    CURRENT_VM.lock().take();
+    drop(vm);
+    super::shutdown(status)
+}
@@ -1,5 +1,9 @@
-use super::device_emu::{self, VirtLocalApic};
+use super::device_emu::PowerEvent;
+use super::gconfig::FaultPolicy;
 use super::hal::RvmHalImpl;
+use super::hypercall;
+use super::mmio;
+use super::vm;
 use rvm::arch::{VmxExitInfo, VmxExitReason};
 use rvm::{RvmError, RvmResult, RvmVcpu};
 
@@ -10,48 +14,39 @@ const VM_EXIT_INSTR_LEN_RDMSR: u8 = 2;
 const VM_EXIT_INSTR_LEN_WRMSR: u8 = 2;
 const VM_EXIT_INSTR_LEN_VMCALL: u8 = 3;
 
+const NMI_VECTOR: u8 = 2;
+const INVALID_OPCODE: u8 = 6;
+const GENERAL_PROTECTION_FAULT: u8 = 13;
+
 fn handle_external_interrupt(vcpu: &mut Vcpu) -> RvmResult {
     let int_info = vcpu.interrupt_exit_info()?;
     trace!("VM-exit: external interrupt: {:#x?}", int_info);
     assert!(int_info.valid);
     crate::arch::handle_irq(int_info.vector);
+    // the host console may have received input in the IRQ
+    vm::current().devices().handle_console_input();
     Ok(())
 }
 
-fn handle_cpuid(vcpu: &mut Vcpu) -> RvmResult {
-    use raw_cpuid::{cpuid, CpuIdResult};
-
-    const LEAF_FEATURE_INFO: u32 = 0x1;
-    const LEAF_HYPERVISOR_INFO: u32 = 0x4000_0000;
-    const LEAF_HYPERVISOR_FEATURE: u32 = 0x4000_0001;
-    const VENDOR_STR: &[u8; 12] = b"RVMRVMRVMRVM";
-    let vendor_regs = unsafe { &*(VENDOR_STR.as_ptr() as *const [u32; 3]) };
+fn handle_exception_nmi(vcpu: &mut Vcpu) -> RvmResult {
+    let int_info = vcpu.interrupt_exit_info()?;
+    trace!("VM exit: exception or NMI: {:#x?}", int_info);
+    // exceptions of the guest do not cause VM exits
+    if !int_info.valid || int_info.vector != NMI_VECTOR {
+        warn!("Unexpected exception in the guest: {:#x?}", int_info);
+        return Err(RvmError::BadState);
+    }
+    // the NMI is for the host, the guest resumes unchanged
+    crate::arch::instructions::raise_nmi();
+    Ok(())
+}
 
+fn handle_cpuid(vcpu: &mut Vcpu) -> RvmResult {
+    let apic_id = vcpu.lapic().id();
     let regs = vcpu.regs_mut();
-    let function = regs.rax as u32;
-    let res = match function {
-        LEAF_FEATURE_INFO => {
-            const FEATURE_VMX: u32 = 1 << 5;
-            const FEATURE_HYPERVISOR: u32 = 1 << 31;
-            let mut res = cpuid!(regs.rax, regs.rcx);
-            res.ecx &= !FEATURE_VMX;
-            res.ecx |= FEATURE_HYPERVISOR;
-            res
-        }
-        LEAF_HYPERVISOR_INFO => CpuIdResult {
-            eax: LEAF_HYPERVISOR_FEATURE,
-            ebx: vendor_regs[0],
-            ecx: vendor_regs[1],
-            edx: vendor_regs[2],
-        },
-        LEAF_HYPERVISOR_FEATURE => CpuIdResult {
-            eax: 0,
-            ebx: 0,
-            ecx: 0,
-            edx: 0,
-        },
-        _ => cpuid!(regs.rax, regs.rcx),
-    };
+    let res = vm::current()
+        .cpuid()
+        .lookup(regs.rax as u32, regs.rcx as u32, apic_id);
 
     debug!(
         "VM exit: CPUID({:#x}, {:#x}): {:?}",
@@ -66,12 +61,7 @@ fn handle_cpuid(vcpu: &mut Vcpu) -> RvmResult {
 }
 
 fn handle_hypercall(vcpu: &mut Vcpu) -> RvmResult {
-    let regs = vcpu.regs();
-    info!(
-        "VM exit: VMCALL({:#x}): {:?}",
-        regs.rax,
-        [regs.rdi, regs.rsi, regs.rdx, regs.rcx]
-    );
+    hypercall::handle(vcpu);
     vcpu.advance_rip(VM_EXIT_INSTR_LEN_VMCALL)?;
     Ok(())
 }
@@ -92,22 +82,11 @@ fn handle_io_instruction(vcpu: &mut Vcpu, exit_info: &VmxExitInfo) -> RvmResult
         return Err(RvmError::Unsupported);
     }
 
-    if let Some(dev) = device_emu::all_virt_devices().find_port_io_device(io_info.port) {
+    let vm = vm::current();
+    if let Some(dev) = vm.devices().find_port_io_device(io_info.port) {
         if io_info.is_in {
             let value = dev.read(io_info.port, io_info.access_size)?;
-            let rax = &mut vcpu.regs_mut().rax;
-            // SDM Vol. 1, Section 3.4.1.1:
-            // * 32-bit operands generate a 32-bit result, zero-extended to a 64-bit result in the
-            //   destination general-purpose register.
-            // * 8-bit and 16-bit operands generate an 8-bit or 16-bit result. The upper 56 bits or
-            //   48 bits (respectively) of the destination general-purpose register are not modified
-            //   by the operation.
-            match io_info.access_size {
-                1 => *rax = (*rax & !0xff) | (value & 0xff) as u64,
-                2 => *rax = (*rax & !0xffff) | (value & 0xffff) as u64,
-                4 => *rax = value as u64,
-                _ => unreachable!(),
-            }
+            set_io_result(vcpu, io_info.access_size, value);
         } else {
             let rax = vcpu.regs().rax;
             let value = match io_info.access_size {
@@ -119,35 +98,50 @@ fn handle_io_instruction(vcpu: &mut Vcpu, exit_info: &VmxExitInfo) -> RvmResult
             dev.write(io_info.port, io_info.access_size, value)?;
         }
     } else {
-        panic!(
+        warn!(
             "Unsupported I/O port {:#x} access: {:#x?}",
             io_info.port, io_info
-        )
+        );
+        return Err(RvmError::Unsupported);
     }
     vcpu.advance_rip(exit_info.exit_instruction_length as _)?;
     Ok(())
 }
 
+/// Write the result of `IN` to `AL`, `AX` or `EAX`.
+fn set_io_result(vcpu: &mut Vcpu, access_size: u8, value: u32) {
+    let rax = &mut vcpu.regs_mut().rax;
+    // SDM Vol. 1, Section 3.4.1.1:
+    // * 32-bit operands generate a 32-bit result, zero-extended to a 64-bit result in the
+    //   destination general-purpose register.
+    // * 8-bit and 16-bit operands generate an 8-bit or 16-bit result. The upper 56 bits or
+    //   48 bits (respectively) of the destination general-purpose register are not modified
+    //   by the operation.
+    match access_size {
+        1 => *rax = (*rax & !0xff) | (value & 0xff) as u64,
+        2 => *rax = (*rax & !0xffff) | (value & 0xffff) as u64,
+        4 => *rax = value as u64,
+        _ => unreachable!(),
+    }
+}
+
 fn handle_msr_read(vcpu: &mut Vcpu) -> RvmResult {
     let msr = vcpu.regs().rcx as u32;
-
-    use x86::msr::*;
-    let res = if msr == IA32_APIC_BASE {
-        let mut apic_base = unsafe { rdmsr(IA32_APIC_BASE) };
-        apic_base |= 1 << 11 | 1 << 10; // enable xAPIC and x2APIC
-        Ok(apic_base)
-    } else if VirtLocalApic::msr_range().contains(&msr) {
-        VirtLocalApic::rdmsr(vcpu, msr)
-    } else {
-        Err(RvmError::Unsupported)
-    };
-
-    if let Ok(value) = res {
-        debug!("VM exit: RDMSR({:#x}) -> {:#x}", msr, value);
-        vcpu.regs_mut().rax = value & 0xffff_ffff;
-        vcpu.regs_mut().rdx = value >> 32;
-    } else {
-        panic!("Failed to handle RDMSR({:#x}): {:?}", msr, res);
+    match vm::current().msrs().read(vcpu, msr) {
+        Ok(value) => {
+            debug!("VM exit: RDMSR({:#x}) -> {:#x}", msr, value);
+            vcpu.regs_mut().rax = value & 0xffff_ffff;
+            vcpu.regs_mut().rdx = value >> 32;
+        }
+        Err(RvmError::InvalidParam) => {
+            debug!("VM exit: RDMSR({:#x}) -> #GP", msr);
+            vcpu.inject_exception(GENERAL_PROTECTION_FAULT, Some(0));
+            return Ok(());
+        }
+        Err(e) => {
+            warn!("Failed to handle RDMSR({:#x}): {:?}", msr, e);
+            return Err(e);
+        }
     }
     vcpu.advance_rip(VM_EXIT_INSTR_LEN_RDMSR)?;
     Ok(())
@@ -157,35 +151,159 @@ fn handle_msr_write(vcpu: &mut Vcpu) -> RvmResult {
     let msr = vcpu.regs().rcx as u32;
     let value = (vcpu.regs().rax & 0xffff_ffff) | (vcpu.regs().rdx << 32);
     debug!("VM exit: WRMSR({:#x}) <- {:#x}", msr, value);
-
-    use x86::msr::*;
-    let res = if msr == IA32_APIC_BASE {
-        Ok(()) // ignore
-    } else if VirtLocalApic::msr_range().contains(&msr) {
-        VirtLocalApic::wrmsr(vcpu, msr, value)
-    } else {
-        Err(RvmError::Unsupported)
-    };
-
-    if res.is_err() {
-        panic!(
-            "Failed to handle WRMSR({:#x}) <- {:#x}: {:?}",
-            msr, value, res
-        );
+    match vm::current().msrs().write(vcpu, msr, value) {
+        Ok(()) => {}
+        Err(RvmError::InvalidParam) => {
+            warn!("Invalid WRMSR({:#x}) <- {:#x}", msr, value);
+            vcpu.inject_exception(GENERAL_PROTECTION_FAULT, Some(0));
+            return Ok(());
+        }
+        Err(e) => {
+            warn!(
+                "Failed to handle WRMSR({:#x}) <- {:#x}: {:?}",
+                msr, value, e
+            );
+            return Err(e);
+        }
     }
     vcpu.advance_rip(VM_EXIT_INSTR_LEN_WRMSR)?;
     Ok(())
 }
 
-fn handle_ept_violation(vcpu: &Vcpu, guest_rip: usize) -> RvmResult {
+fn handle_ept_violation(vcpu: &mut Vcpu, guest_rip: usize) -> RvmResult {
     let fault_info = vcpu.nested_page_fault_info()?;
-    panic!(
+    let gpa = fault_info.fault_guest_paddr;
+    let vm = vm::current();
+    let lapic = vm.devices().lapic();
+    if lapic.xapic_mmio_range().map_or(false, |r| r.contains(&gpa)) {
+        return mmio::emulate_mmio(
+            vcpu,
+            vm.gpm(),
+            |vcpu, size| lapic.mmio_read(vcpu, gpa, size),
+            |vcpu, size, value| lapic.mmio_write(vcpu, gpa, size, value),
+        );
+    }
+    if let Some(dev) = vm.devices().find_mmio_device(gpa) {
+        return mmio::handle_mmio(vcpu, vm.gpm(), dev.as_ref(), gpa);
+    }
+    warn!(
         "VM exit: EPT violation @ {:#x}, fault_paddr={:#x}, access_flags=({:?})",
-        guest_rip, fault_info.fault_guest_paddr, fault_info.access_flags
+        guest_rip, gpa, fault_info.access_flags
     );
+    Err(RvmError::Unsupported)
+}
+
+fn handle_virtualized_eoi(vcpu: &mut Vcpu) -> RvmResult {
+    let vector = vcpu.virtualized_eoi_vector()?;
+    trace!("VM exit: virtualized EOI, vector {:#x}", vector);
+    vm::current().devices().lapic().eoi_broadcast(vector);
+    Ok(())
+}
+
+fn handle_apic_write(vcpu: &mut Vcpu) -> RvmResult {
+    let offset = vcpu.apic_write_offset()?;
+    let value = vcpu.lapic().read_reg((offset >> 4) as u32);
+    debug!("VM exit: APIC write @ {:#x} <- {:#x}", offset, value);
+    vm::current().devices().lapic().apic_write(vcpu, offset)
+}
+
+/// Whether the VM exit is caused by a guest instruction not supported by the
+/// hypervisor, which is `#UD` on the hardware without the feature.
+fn is_unsupported_instruction(reason: VmxExitReason) -> bool {
+    use VmxExitReason::*;
+    matches!(
+        reason,
+        GETSEC
+            | INVD
+            | RDPMC
+            | RSM
+            | VMCLEAR
+            | VMLAUNCH
+            | VMPTRLD
+            | VMPTRST
+            | VMREAD
+            | VMRESUME
+            | VMWRITE
+            | VMOFF
+            | VMON
+            | MWAIT_INSTRUCTION
+            | MONITOR_INSTRUCTION
+            | INVEPT
+            | INVVPID
+            | XSETBV
+            | RDRAND
+            | RDSEED
+            | VMFUNC
+            | ENCLS
+            | XSAVES
+            | XRSTORS
+            | PCONFIG
+            | UMWAIT
+            | TPAUSE
+            | LOADIWKEY
+    )
+}
+
+/// Handle the failure of emulating a guest operation by the fault policy of
+/// the VM, either stop the VM, or make the guest see what the hardware does
+/// without the device or feature. Failures not caused by guest instructions
+/// always stop the VM.
+fn handle_guest_fault(vcpu: &mut Vcpu, exit_info: &VmxExitInfo, err: RvmError) -> RvmResult {
+    let vm = vm::current();
+    let reason = exit_info.exit_reason;
+    let by_guest = matches!(
+        reason,
+        VmxExitReason::MSR_READ
+            | VmxExitReason::MSR_WRITE
+            | VmxExitReason::IO_INSTRUCTION
+            | VmxExitReason::EPT_VIOLATION
+    ) || is_unsupported_instruction(reason);
+    if vm.config().fault_policy == FaultPolicy::Strict || !by_guest {
+        error!(
+            "Failed to handle VM-exit {:?} ({:?}):\n{:#x?}",
+            exit_info.exit_reason, err, vcpu
+        );
+        vm::stop(vm, vm::EXIT_FAILURE);
+    }
+    warn!(
+        "VM exit: reflect the failure of {:?} @ {:#x} ({:?}) to the guest",
+        exit_info.exit_reason, exit_info.guest_rip, err
+    );
+    match exit_info.exit_reason {
+        VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE => {
+            vcpu.inject_exception(GENERAL_PROTECTION_FAULT, Some(0));
+        }
+        VmxExitReason::IO_INSTRUCTION => {
+            let io_info = vcpu.io_exit_info()?;
+            if io_info.is_string || io_info.is_repeat {
+                vcpu.inject_exception(INVALID_OPCODE, None);
+                return Ok(());
+            }
+            // no device responds, reads float high and writes are dropped
+            if io_info.is_in {
+                set_io_result(vcpu, io_info.access_size, u32::MAX);
+            }
+            vcpu.advance_rip(exit_info.exit_instruction_length as _)?;
+        }
+        VmxExitReason::EPT_VIOLATION => {
+            let res = mmio::emulate_mmio(
+                vcpu,
+                vm.gpm(),
+                |_, size| Ok(u64::MAX >> (64 - size as u32 * 8)),
+                |_, _, _| Ok(()),
+            );
+            if res.is_err() {
+                // the instruction can not be decoded
+                vcpu.inject_exception(INVALID_OPCODE, None);
+            }
+        }
+        _ => vcpu.inject_exception(INVALID_OPCODE, None), // unsupported instructions
+    }
+    Ok(())
 }
 
 pub fn vmexit_handler(vcpu: &mut Vcpu) -> RvmResult {
+    vm::current().count_exit();
     let exit_info = vcpu.exit_info()?;
     trace!("VM exit: {:#x?}", exit_info);
 
@@ -194,26 +312,41 @@ pub fn vmexit_handler(vcpu: &mut Vcpu) -> RvmResult {
     }
 
     let res = match exit_info.exit_reason {
+        VmxExitReason::EXCEPTION_NMI => handle_exception_nmi(vcpu),
         VmxExitReason::EXTERNAL_INTERRUPT => handle_external_interrupt(vcpu),
         VmxExitReason::INTERRUPT_WINDOW => vcpu.set_interrupt_window(false),
+        VmxExitReason::NMI_WINDOW => vcpu.set_nmi_window(false),
         VmxExitReason::CPUID => handle_cpuid(vcpu),
         VmxExitReason::VMCALL => handle_hypercall(vcpu),
         VmxExitReason::IO_INSTRUCTION => handle_io_instruction(vcpu, &exit_info),
         VmxExitReason::MSR_READ => handle_msr_read(vcpu),
         VmxExitReason::MSR_WRITE => handle_msr_write(vcpu),
         VmxExitReason::EPT_VIOLATION => handle_ept_violation(vcpu, exit_info.guest_rip),
-        _ => panic!(
-            "Unhandled VM-Exit reason {:?}:\n{:#x?}",
-            exit_info.exit_reason, vcpu
-        ),
+        // the pending interrupts are checked again before VM entry
+        VmxExitReason::TPR_BELOW_THRESHOLD => Ok(()),
+        VmxExitReason::VIRTUALIZED_EOI => handle_virtualized_eoi(vcpu),
+        VmxExitReason::APIC_WRITE => handle_apic_write(vcpu),
+        VmxExitReason::TRIPLE_FAULT => {
+            warn!("VM exit: triple fault @ {:#x}", exit_info.guest_rip);
+            vm::current()
+                .devices()
+                .request_power_event(PowerEvent::Reset);
+            Ok(())
+        }
+        _ => {
+            warn!("Unhandled VM-Exit reason {:?}", exit_info.exit_reason);
+            Err(RvmError::Unsupported)
+        }
     };
 
-    if res.is_err() {
-        panic!(
-            "Failed to handle VM-exit {:?}:\n{:#x?}",
-            exit_info.exit_reason, vcpu
-        );
+    if let Err(err) = res {
+        handle_guest_fault(vcpu, &exit_info, err)?;
     }
 
-    Ok(())
+    let event = vm::current().devices().take_power_event();
+    if let Some(event) = event {
+        return vm::handle_power_event(vcpu, event);
+    }
+
+    vm::current().devices().inject_interrupts(vcpu)
 }
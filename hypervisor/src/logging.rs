@@ -1,7 +1,9 @@
+use alloc::vec::Vec;
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use log::{self, Level, LevelFilter, Log, Metadata, Record};
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 
 use crate::arch::uart;
 
@@ -9,6 +11,23 @@ struct Stdout;
 
 static PRINT_LOCK: Mutex<()> = Mutex::new(());
 
+static FILTER: RwLock<LogFilter> = RwLock::new(LogFilter::new());
+
+/// Prefix of log level hotkeys in the host console input.
+const HOTKEY_PREFIX: u8 = 0x14; // Ctrl-T
+
+static HOTKEY_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Log levels indexed by their numbers used in hotkeys and hypercalls.
+pub const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for c in s.chars() {
@@ -24,17 +43,103 @@ impl Write for Stdout {
     }
 }
 
-pub fn init() {
-    static LOGGER: SimpleLogger = SimpleLogger;
-    log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(match option_env!("LOG") {
+/// The log level of the `LOG` variable at build time.
+fn build_level() -> LevelFilter {
+    match option_env!("LOG") {
         Some("error") => LevelFilter::Error,
         Some("warn") => LevelFilter::Warn,
         Some("info") => LevelFilter::Info,
         Some("debug") => LevelFilter::Debug,
         Some("trace") => LevelFilter::Trace,
         _ => LevelFilter::Off,
-    });
+    }
+}
+
+/// Log levels of module path prefixes, and the default level for others.
+struct LogFilter {
+    level: LevelFilter,
+    targets: Vec<(&'static str, LevelFilter)>,
+}
+
+impl LogFilter {
+    const fn new() -> Self {
+        Self {
+            level: LevelFilter::Off,
+            targets: Vec::new(),
+        }
+    }
+
+    fn add_directive(&mut self, target: Option<&'static str>, level: LevelFilter) {
+        match target {
+            Some(target) => {
+                self.targets.retain(|&(t, _)| t != target);
+                self.targets.push((target, level));
+            }
+            None => self.level = level,
+        }
+    }
+
+    /// The level of the longest module path prefix matching `target`.
+    fn target_level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target
+                    .strip_prefix(prefix)
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.level, Ord::max)
+    }
+}
+
+/// Initialize the logger with filter directives from the hypervisor command
+/// line, the default level is the build time level if not given.
+pub fn init() {
+    static LOGGER: SimpleLogger = SimpleLogger;
+    let mut filter = FILTER.write();
+    filter.level = build_level();
+    for (target, level) in crate::cmdline::log_directives() {
+        filter.add_directive(target, level);
+    }
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(filter.max_level());
+}
+
+/// Change the default log level at runtime.
+pub fn set_level(level: LevelFilter) {
+    let mut filter = FILTER.write();
+    filter.level = level;
+    log::set_max_level(filter.max_level());
+}
+
+/// Handle log level hotkeys in the host console input: `Ctrl-T` followed by
+/// `0` to `5` sets the default level from `off` to `trace`, and `Ctrl-T` twice
+/// sends a `Ctrl-T`. Returns `c` if it's not consumed by a hotkey.
+pub fn filter_hotkey(c: u8) -> Option<u8> {
+    if !HOTKEY_PENDING.swap(false, Ordering::Relaxed) {
+        if c == HOTKEY_PREFIX {
+            HOTKEY_PENDING.store(true, Ordering::Relaxed);
+            return None;
+        }
+        return Some(c);
+    }
+    match c {
+        b'0'..=b'5' => {
+            let level = LEVELS[(c - b'0') as usize];
+            set_level(level);
+            print(format_args!("\n[log level: {}]\n", level));
+            None
+        }
+        _ => Some(c),
+    }
 }
 
 pub fn print(args: fmt::Arguments) {
@@ -87,8 +192,8 @@ enum ColorCode {
 struct SimpleLogger;
 
 impl Log for SimpleLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.read().target_level(metadata.target())
     }
 
     fn log(&self, record: &Record) {
@@ -12,9 +12,11 @@ extern crate alloc;
 mod logging;
 
 mod arch;
+mod cmdline;
 mod config;
 mod hv;
 mod mm;
+mod multiboot;
 mod timer;
 
 #[cfg(not(test))]
@@ -53,8 +55,9 @@ pub fn init_ok() -> bool {
     INIT_OK.load(Ordering::SeqCst)
 }
 
-fn main() -> ! {
+extern "C" fn main(magic: usize, mbi_paddr: usize) -> ! {
     clear_bss();
+    multiboot::init(magic, mbi_paddr);
     arch::init_early();
     println!("{}", LOGO);
     println!(
@@ -62,15 +65,18 @@ fn main() -> ! {
         arch = {}\n\
         build_mode = {}\n\
         log_level = {}\n\
+        cmdline = {:?}\n\
         ",
         option_env!("ARCH").unwrap_or(""),
         option_env!("MODE").unwrap_or(""),
-        option_env!("LOG").unwrap_or(""),
+        cmdline::get("log").or(option_env!("LOG")).unwrap_or(""),
+        multiboot::cmdline(),
     );
 
     mm::init_heap_early();
     logging::init();
     info!("Logging is enabled.");
+    cmdline::check();
 
     arch::init();
     mm::init();
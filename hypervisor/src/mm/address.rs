@@ -1,7 +1,7 @@
 use super::PAGE_SIZE;
 use crate::config::PHYS_VIRT_OFFSET;
 
-pub(super) type PhysAddr = usize;
+pub type PhysAddr = usize;
 pub(super) type VirtAddr = usize;
 
 pub const fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
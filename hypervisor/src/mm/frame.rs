@@ -54,7 +54,9 @@ pub(super) fn init() {
         fn ekernel();
     }
 
-    let mem_pool_start = align_up(virt_to_phys(ekernel as usize));
+    // skip the boot modules loaded after the kernel image
+    let kernel_end = virt_to_phys(ekernel as usize);
+    let mem_pool_start = align_up(kernel_end.max(crate::multiboot::reserved_end()));
     let mem_pool_end = align_down(PHYS_MEMORY_END);
     let mem_pool_size = mem_pool_end - mem_pool_start;
     println!(
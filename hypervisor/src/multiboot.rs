@@ -0,0 +1,190 @@
+//! Multiboot (version 1) information structures.
+//! (ref: https://www.gnu.org/software/grub/manual/multiboot/multiboot.html)
+
+#![allow(dead_code)]
+
+use crate::mm::address::{phys_to_virt, virt_to_phys, PhysAddr};
+
+/// The magic value in `EAX` which indicates that the OS was loaded by a
+/// Multiboot-compliant boot loader.
+pub const BOOTLOADER_MAGIC: u32 = 0x2BAD_B002;
+
+bitflags::bitflags! {
+    /// Indicate the presence and validity of other fields in [`MultibootInfo`].
+    pub struct InfoFlags: u32 {
+        const MEMORY            = 1 << 0;
+        const BOOT_DEVICE       = 1 << 1;
+        const CMDLINE           = 1 << 2;
+        const MODS              = 1 << 3;
+        const AOUT_SYMS         = 1 << 4;
+        const ELF_SHDR          = 1 << 5;
+        const MEM_MAP           = 1 << 6;
+        const DRIVE_INFO        = 1 << 7;
+        const CONFIG_TABLE      = 1 << 8;
+        const BOOT_LOADER_NAME  = 1 << 9;
+        const APM_TABLE         = 1 << 10;
+        const VBE_INFO          = 1 << 11;
+        const FRAMEBUFFER_INFO  = 1 << 12;
+    }
+}
+
+/// Boot information structure, its physical address is passed in `EBX`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MultibootInfo {
+    pub flags: u32,
+    /// Amount of lower memory in kilobytes, starts at address 0.
+    pub mem_lower: u32,
+    /// Amount of upper memory in kilobytes, starts at address 1M.
+    pub mem_upper: u32,
+    pub boot_device: u32,
+    /// Physical address of the null-terminated command line.
+    pub cmdline: u32,
+    pub mods_count: u32,
+    /// Physical address of the first [`ModuleEntry`].
+    pub mods_addr: u32,
+    pub syms: [u32; 4],
+    /// Total size of the memory map buffer.
+    pub mmap_length: u32,
+    /// Physical address of the first [`MemoryMapEntry`].
+    pub mmap_addr: u32,
+    pub drives_length: u32,
+    pub drives_addr: u32,
+    pub config_table: u32,
+    /// Physical address of the null-terminated boot loader name.
+    pub boot_loader_name: u32,
+    pub apm_table: u32,
+    pub vbe_control_info: u32,
+    pub vbe_mode_info: u32,
+    pub vbe_mode: u16,
+    pub vbe_interface_seg: u16,
+    pub vbe_interface_off: u16,
+    pub vbe_interface_len: u16,
+}
+
+/// Memory map entry, the same as the E820 memory types.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct MemoryMapEntry {
+    /// Size of the rest of this entry (not include this field itself).
+    pub size: u32,
+    pub addr: u64,
+    pub len: u64,
+    pub mem_type: u32,
+}
+
+/// Boot module entry.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleEntry {
+    pub mod_start: u32,
+    pub mod_end: u32,
+    /// Physical address of the null-terminated module command line.
+    pub string: u32,
+    pub reserved: u32,
+}
+
+impl MemoryMapEntry {
+    pub const fn new(addr: u64, len: u64, mem_type: u32) -> Self {
+        Self {
+            size: (core::mem::size_of::<Self>() - core::mem::size_of::<u32>()) as u32,
+            addr,
+            len,
+            mem_type,
+        }
+    }
+}
+
+static mut BOOT_INFO: Option<&'static MultibootInfo> = None;
+
+/// A boot module loaded by the boot loader.
+#[derive(Debug, Clone, Copy)]
+pub struct BootModule {
+    /// Physical address of the module contents.
+    pub paddr: PhysAddr,
+    /// Size of the module in bytes.
+    pub size: usize,
+    /// The module command line, starts with the module file name usually.
+    pub cmdline: &'static str,
+}
+
+impl BootModule {
+    /// The module file name, i.e. the base name of the first word in the
+    /// module command line.
+    pub fn name(&self) -> &'static str {
+        let path = self.cmdline.split_whitespace().next().unwrap_or("");
+        path.rsplit('/').next().unwrap_or(path)
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(phys_to_virt(self.paddr) as *const u8, self.size) }
+    }
+}
+
+fn boot_info() -> Option<&'static MultibootInfo> {
+    unsafe { BOOT_INFO }
+}
+
+fn c_str(paddr: u32) -> &'static str {
+    let ptr = phys_to_virt(paddr as PhysAddr) as *const u8;
+    let len = (0..).take_while(|&i| unsafe { *ptr.add(i) } != 0).count();
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+    core::str::from_utf8(bytes).unwrap_or("")
+}
+
+/// The hypervisor command line.
+pub fn cmdline() -> &'static str {
+    match boot_info() {
+        Some(info) if info.flags & InfoFlags::CMDLINE.bits() != 0 => c_str(info.cmdline),
+        _ => "",
+    }
+}
+
+/// Iterate over all boot modules.
+pub fn modules() -> impl Iterator<Item = BootModule> {
+    let entries: &'static [ModuleEntry] = match boot_info() {
+        Some(info) if info.flags & InfoFlags::MODS.bits() != 0 => unsafe {
+            core::slice::from_raw_parts(
+                phys_to_virt(info.mods_addr as PhysAddr) as *const ModuleEntry,
+                info.mods_count as usize,
+            )
+        },
+        _ => &[],
+    };
+    entries.iter().map(|m| BootModule {
+        paddr: m.mod_start as PhysAddr,
+        size: (m.mod_end - m.mod_start) as usize,
+        cmdline: c_str(m.string),
+    })
+}
+
+/// Find the boot module by its file name.
+pub fn find_module(name: &str) -> Option<BootModule> {
+    modules().find(|m| m.name() == name)
+}
+
+/// The end physical address of the boot information and modules, the memory
+/// below it must not be allocated before they are no longer used.
+pub fn reserved_end() -> PhysAddr {
+    let info_end = match boot_info() {
+        Some(info) => {
+            virt_to_phys(info as *const _ as usize) + core::mem::size_of::<MultibootInfo>()
+        }
+        None => return 0,
+    };
+    let cmdline = cmdline();
+    let cmdline_end = virt_to_phys(cmdline.as_ptr() as usize) + cmdline.len() + 1;
+    modules()
+        .map(|m| {
+            (m.paddr + m.size).max(virt_to_phys(m.cmdline.as_ptr() as usize) + m.cmdline.len() + 1)
+        })
+        .fold(info_end.max(cmdline_end), PhysAddr::max)
+}
+
+/// Save the boot information passed by the boot loader, `magic` and `info_paddr`
+/// are the values of `EAX` and `EBX` on entry.
+pub fn init(magic: usize, info_paddr: usize) {
+    if magic as u32 == BOOTLOADER_MAGIC {
+        unsafe { BOOT_INFO = Some(&*(phys_to_virt(info_paddr) as *const MultibootInfo)) };
+    }
+}
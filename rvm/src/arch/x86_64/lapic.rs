@@ -1,7 +1,8 @@
 use bit_field::BitField;
 use core::marker::PhantomData;
 
-use crate::{RvmHal, RvmResult};
+use crate::mm::PhysFrame;
+use crate::{HostPhysAddr, RvmHal, RvmResult};
 
 const APIC_FREQ_MHZ: u64 = 1000; // 1000 MHz
 const APIC_CYCLE_NANOS: u64 = 1000 / APIC_FREQ_MHZ;
@@ -32,7 +33,7 @@ pub struct ApicTimer<H: RvmHal> {
 impl<H: RvmHal> ApicTimer<H> {
     pub(crate) const fn new() -> Self {
         Self {
-            lvt_timer_bits: 0x1_0000, // masked
+            lvt_timer_bits: LVT_MASKED,
             divide_shift: 0,
             initial_count: 0,
             last_start_ns: 0,
@@ -59,7 +60,7 @@ impl<H: RvmHal> ApicTimer<H> {
 
     /// Whether the timer interrupt is masked.
     pub const fn is_masked(&self) -> bool {
-        self.lvt_timer_bits & (1 << 16) != 0
+        self.lvt_timer_bits & LVT_MASKED != 0
     }
 
     /// Whether the timer mode is periodic.
@@ -89,8 +90,12 @@ impl<H: RvmHal> ApicTimer<H> {
         self.initial_count
     }
 
-    /// Current Count Register.
+    /// Current Count Register, 0 if the timer is stopped by writing 0 to the
+    /// initial count.
     pub fn current_counter(&self) -> u32 {
+        if self.initial_count == 0 {
+            return 0;
+        }
         let elapsed_ns = H::current_time_nanos() - self.last_start_ns;
         let elapsed_cycles = (elapsed_ns / APIC_CYCLE_NANOS) >> self.divide_shift;
         if self.is_periodic() {
@@ -130,6 +135,11 @@ impl<H: RvmHal> ApicTimer<H> {
         Ok(())
     }
 
+    /// Set the mask bit of LVT Timer Register, the timer keeps counting.
+    pub(crate) fn mask(&mut self) {
+        self.lvt_timer_bits |= LVT_MASKED;
+    }
+
     const fn interval_ns(&self) -> u64 {
         (self.initial_count as u64 * APIC_CYCLE_NANOS) << self.divide_shift
     }
@@ -143,3 +153,363 @@ impl<H: RvmHal> ApicTimer<H> {
         }
     }
 }
+
+/// Register indexes, the same as the x2APIC MSR number minus 0x800, and the
+/// xAPIC MMIO offset divided by 16. (SDM Vol. 3A, Section 10.12.1.2, Table 10-6)
+const ID: u32 = 0x02;
+const VERSION: u32 = 0x03;
+const TPR: u32 = 0x08;
+const PPR: u32 = 0x0a;
+const LDR: u32 = 0x0d;
+const DFR: u32 = 0x0e;
+const SVR: u32 = 0x0f;
+const ISR: u32 = 0x10;
+const TMR: u32 = 0x18;
+const IRR: u32 = 0x20;
+const ESR: u32 = 0x28;
+const ICR_LOW: u32 = 0x30;
+const ICR_HIGH: u32 = 0x31;
+const LVT_TIMER: u32 = 0x32;
+const LVT_THERMAL: u32 = 0x33;
+const LVT_PMI: u32 = 0x34;
+const LVT_LINT0: u32 = 0x35;
+const LVT_LINT1: u32 = 0x36;
+const LVT_ERR: u32 = 0x37;
+const EOI: u32 = 0x0b;
+const SELF_IPI: u32 = 0x3f;
+
+/// Version 0x14 with 6 LVT entries.
+const LAPIC_VERSION: u32 = 0x5_0014;
+
+const LVT_MASKED: u32 = 1 << 16;
+const SVR_ENABLE: u32 = 1 << 8;
+/// Vector, APIC Software Enable, Focus Processor Checking and EOI-Broadcast
+/// Suppression. (SDM Vol. 3A, Section 10.9, Figure 10-23)
+const SVR_WRITABLE_BITS: u32 = 0x13ff;
+/// Vector, Delivery Mode, Destination Mode, Level, Trigger Mode and
+/// Destination Shorthand. (SDM Vol. 3A, Section 10.6.1, Figure 10-12)
+const ICR_WRITABLE_BITS: u32 = 0xc_cfff;
+/// Received Illegal Vector. (SDM Vol. 3A, Section 10.5.3, Figure 10-9)
+const ESR_RECEIVE_ILLEGAL_VECTOR: u32 = 1 << 6;
+
+/// The register state of a virtual local APIC, kept in a page with the layout
+/// of the xAPIC register page. (SDM Vol. 3A, Chapter 10)
+///
+/// Interrupts sent to the local APIC are pending in IRR, and the highest one
+/// with a higher priority class than PPR is injected and moved to ISR, until
+/// the guest writes EOI.
+pub struct VirtualApic<H: RvmHal> {
+    page: PhysFrame<H>,
+    timer: ApicTimer<H>,
+    /// Errors detected since the last write to ESR.
+    pending_esr: u32,
+    /// Whether the registers are in the x2APIC format.
+    x2apic: bool,
+}
+
+impl<H: RvmHal> VirtualApic<H> {
+    /// Create a local APIC in the power-up state. (SDM Vol. 3A, Section 10.4.7.1)
+    pub(crate) fn new() -> RvmResult<Self> {
+        let mut lapic = Self {
+            page: PhysFrame::alloc_zero()?,
+            timer: ApicTimer::new(),
+            pending_esr: 0,
+            x2apic: false,
+        };
+        lapic.reset();
+        Ok(lapic)
+    }
+
+    /// Put the local APIC into the power-up state, as the processor is reset.
+    pub(crate) fn reset(&mut self) {
+        self.page.fill(0);
+        self.timer = ApicTimer::new();
+        self.pending_esr = 0;
+        self.x2apic = false;
+        self.write_reg(VERSION, LAPIC_VERSION);
+        self.write_reg(DFR, 0xffff_ffff);
+        self.write_reg(SVR, 0xff);
+        for reg in LVT_THERMAL..=LVT_ERR {
+            self.write_reg(reg, LVT_MASKED);
+        }
+    }
+
+    /// Read a register by its index, returns 0 for the registers not kept in
+    /// the register page, such as EOI and the timer registers.
+    pub fn read_reg(&self, index: u32) -> u32 {
+        assert!(index < 0x40);
+        unsafe { self.reg_ptr(index).read_volatile() }
+    }
+
+    /// The local APIC ID.
+    pub fn id(&self) -> u32 {
+        if self.x2apic {
+            self.read_reg(ID)
+        } else {
+            self.read_reg(ID) >> 24
+        }
+    }
+
+    /// Switch the format of ID, LDR and ICR between the xAPIC and x2APIC modes,
+    /// the logical x2APIC ID is derived from the x2APIC ID.
+    /// (SDM Vol. 3A, Section 10.12.10.2)
+    pub(crate) fn set_x2apic_mode(&mut self, enable: bool) {
+        let id = self.id();
+        let icr = self.icr();
+        self.x2apic = enable;
+        self.set_icr(icr as u32, (icr >> 32) as u32);
+        if enable {
+            self.write_reg(ID, id);
+            self.write_reg(LDR, ((id >> 4) << 16) | (1 << (id & 0xf)));
+        } else {
+            self.write_reg(ID, id << 24);
+            self.write_reg(LDR, 0);
+        }
+    }
+
+    /// Whether the local APIC is software enabled by the Spurious Interrupt
+    /// Vector Register.
+    pub fn is_enabled(&self) -> bool {
+        self.read_reg(SVR) & SVR_ENABLE != 0
+    }
+
+    /// Set Task Priority Register, and update the processor priority.
+    pub fn set_tpr(&mut self, tpr: u32) {
+        self.write_reg(TPR, tpr & 0xff);
+        self.update_ppr();
+    }
+
+    /// Set Logical Destination Register, only the logical APIC ID in bits
+    /// 24-31 is writable.
+    pub fn set_ldr(&mut self, ldr: u32) {
+        self.write_reg(LDR, ldr & 0xff00_0000);
+    }
+
+    /// Set Destination Format Register, only the model in bits 28-31 is
+    /// writable.
+    pub fn set_dfr(&mut self, dfr: u32) {
+        self.write_reg(DFR, dfr | 0x0fff_ffff);
+    }
+
+    /// Set Spurious Interrupt Vector Register. All LVT entries are masked when
+    /// the local APIC is software disabled. (SDM Vol. 3A, Section 10.4.7.2)
+    pub fn set_svr(&mut self, svr: u32) {
+        self.write_reg(SVR, svr & SVR_WRITABLE_BITS);
+        if svr & SVR_ENABLE == 0 {
+            for reg in LVT_THERMAL..=LVT_ERR {
+                self.write_reg(reg, self.read_reg(reg) | LVT_MASKED);
+            }
+            self.timer.mask();
+        }
+    }
+
+    /// Update Error Status Register with the errors detected since the last
+    /// update, which is done by writing to it. (SDM Vol. 3A, Section 10.5.3)
+    pub fn update_esr(&mut self) {
+        self.write_reg(ESR, self.pending_esr);
+        self.pending_esr = 0;
+    }
+
+    /// Interrupt Command Register, with the destination field in bits 32-63.
+    pub fn icr(&self) -> u64 {
+        let high = if self.x2apic {
+            unsafe { self.reg_ptr(ICR_LOW).add(1).read_volatile() }
+        } else {
+            self.read_reg(ICR_HIGH)
+        };
+        self.read_reg(ICR_LOW) as u64 | (high as u64) << 32
+    }
+
+    /// Set Interrupt Command Register, `high` is the destination field.
+    ///
+    /// In the x2APIC mode, ICR is a 64-bit register at offset 0x300, as
+    /// `RDMSR` reads it from the virtual-APIC page with APIC-register
+    /// virtualization. (SDM Vol. 3C, Section 29.5) The xAPIC mode keeps the
+    /// high half at offset 0x310.
+    pub fn set_icr(&mut self, low: u32, high: u32) {
+        self.write_reg(ICR_LOW, low & ICR_WRITABLE_BITS);
+        let (high, xapic_high) = if self.x2apic { (high, 0) } else { (0, high) };
+        unsafe { self.reg_ptr(ICR_LOW).add(1).write_volatile(high) };
+        self.write_reg(ICR_HIGH, xapic_high);
+    }
+
+    /// Read a LVT register at `index`.
+    pub fn lvt(&self, index: u32) -> u32 {
+        match index {
+            LVT_TIMER => self.timer.lvt_timer(),
+            _ => self.read_reg(index),
+        }
+    }
+
+    /// Write a LVT register at `index`, the mask bit can not be cleared when
+    /// the local APIC is software disabled.
+    pub fn set_lvt(&mut self, index: u32, mut value: u32) -> RvmResult {
+        if !self.is_enabled() {
+            value |= LVT_MASKED;
+        }
+        // vector, delivery mode, delivery status, polarity, trigger mode, mask
+        let writable_bits = match index {
+            LVT_TIMER => return self.timer.set_lvt_timer(value & 0x7_00ff),
+            LVT_THERMAL | LVT_PMI => 0x1_07ff,
+            LVT_LINT0 | LVT_LINT1 => 0x1_a7ff,
+            LVT_ERR => 0x1_00ff,
+            _ => return rvm_err!(InvalidParam),
+        };
+        self.write_reg(index, value & writable_bits);
+        Ok(())
+    }
+
+    /// Returns the reference of [`ApicTimer`].
+    pub fn timer(&self) -> &ApicTimer<H> {
+        &self.timer
+    }
+
+    /// Returns the mutable reference of [`ApicTimer`].
+    pub fn timer_mut(&mut self) -> &mut ApicTimer<H> {
+        &mut self.timer
+    }
+
+    /// Accept a fixed interrupt into IRR, the trigger mode is recorded in TMR
+    /// for the EOI of level triggered interrupts. Interrupts are discarded if
+    /// the local APIC is software disabled.
+    pub fn request_interrupt(&mut self, vector: u8, level_triggered: bool) {
+        if vector < 16 {
+            self.pending_esr |= ESR_RECEIVE_ILLEGAL_VECTOR;
+            return;
+        }
+        if !self.is_enabled() {
+            trace!("[RVM] local APIC disabled, discard vector {:#x}", vector);
+            return;
+        }
+        self.set_bit(IRR, vector, true);
+        self.set_bit(TMR, vector, level_triggered);
+    }
+
+    /// Handle an EOI, retire the highest priority interrupt in service.
+    /// Returns the vector and whether it was level triggered, then the I/O
+    /// APIC should be notified.
+    pub fn eoi(&mut self) -> Option<(u8, bool)> {
+        let vector = self.highest_bit(ISR)?;
+        self.set_bit(ISR, vector, false);
+        self.update_ppr();
+        Some((vector, self.test_bit(TMR, vector)))
+    }
+
+    /// The highest priority interrupt in IRR that can be delivered, whose
+    /// priority class is higher than the processor priority.
+    /// (SDM Vol. 3A, Section 10.8.3.1)
+    pub(crate) fn pending_vector(&self) -> Option<u8> {
+        let vector = self.highest_bit(IRR)?;
+        if vector as u32 & 0xf0 > self.read_reg(PPR) & 0xf0 {
+            Some(vector)
+        } else {
+            None
+        }
+    }
+
+    /// The interrupt `vector` has been injected, move it from IRR to ISR.
+    pub(crate) fn accept_interrupt(&mut self, vector: u8) {
+        self.set_bit(IRR, vector, false);
+        self.set_bit(ISR, vector, true);
+        self.update_ppr();
+    }
+
+    /// Physical address of the register page, used as the virtual-APIC page.
+    pub(crate) fn phys_addr(&self) -> HostPhysAddr {
+        self.page.start_paddr()
+    }
+
+    /// Requesting virtual interrupt (RVI) and servicing virtual interrupt (SVI)
+    /// in the guest interrupt status. (SDM Vol. 3C, Section 29.1.1)
+    pub(crate) fn interrupt_status(&self) -> u16 {
+        let rvi = self.highest_bit(IRR).unwrap_or(0) as u16;
+        let svi = self.highest_bit(ISR).unwrap_or(0) as u16;
+        svi << 8 | rvi
+    }
+
+    /// The EOI-exit bitmap, EOIs of the level triggered interrupts cause
+    /// VM exits to notify the I/O APIC.
+    pub(crate) fn eoi_exit_bitmap(&self) -> [u64; 4] {
+        let mut bitmap = [0; 4];
+        for (i, bits) in bitmap.iter_mut().enumerate() {
+            let i = i as u32 * 2;
+            *bits = self.read_reg(TMR + i) as u64 | (self.read_reg(TMR + i + 1) as u64) << 32;
+        }
+        bitmap
+    }
+
+    /// The TPR threshold for the TPR shadow without virtual-interrupt delivery.
+    /// A VM exit occurs when the guest lowers TPR below the priority class of
+    /// the highest pending interrupt. (SDM Vol. 3C, Section 29.1.2)
+    pub(crate) fn tpr_threshold(&self) -> u32 {
+        match self.highest_bit(IRR) {
+            Some(vector) => (vector as u32 >> 4).min(self.read_reg(TPR) >> 4),
+            None => 0,
+        }
+    }
+
+    /// Whether the x2APIC MSR `msr` can be read from the virtual-APIC page
+    /// with APIC-register virtualization. The timer registers are not kept in
+    /// the page. (SDM Vol. 3C, Section 29.5)
+    pub(crate) fn is_read_virtualized(msr: u32) -> bool {
+        matches!(
+            msr - 0x800,
+            ID | VERSION | TPR | PPR | LDR | SVR | ESR | ICR_LOW | LVT_THERMAL..=LVT_ERR
+        ) || (ISR..IRR + 8).contains(&(msr - 0x800))
+    }
+
+    /// Whether writes to the x2APIC MSR `msr` are virtualized with virtual
+    /// interrupt delivery. (SDM Vol. 3C, Section 29.5)
+    pub(crate) fn is_write_virtualized(msr: u32) -> bool {
+        matches!(msr - 0x800, TPR | EOI | SELF_IPI)
+    }
+
+    /// Update Processor Priority Register from TPR and the highest interrupt
+    /// in service. (SDM Vol. 3A, Section 10.8.3.1)
+    pub(crate) fn update_ppr(&mut self) {
+        let tpr = self.read_reg(TPR);
+        let isrv = self.highest_bit(ISR).unwrap_or(0) as u32;
+        let ppr = if tpr & 0xf0 >= isrv & 0xf0 {
+            tpr
+        } else {
+            isrv & 0xf0
+        };
+        self.write_reg(PPR, ppr);
+    }
+}
+
+// Implementation of private methods
+impl<H: RvmHal> VirtualApic<H> {
+    fn reg_ptr(&self, index: u32) -> *mut u32 {
+        unsafe { self.page.as_mut_ptr().add(index as usize * 0x10) as *mut u32 }
+    }
+
+    fn write_reg(&mut self, index: u32, value: u32) {
+        assert!(index < 0x40);
+        unsafe { self.reg_ptr(index).write_volatile(value) }
+    }
+
+    /// Bit `vector` of the 256-bit register IRR, ISR or TMR at `base`.
+    fn test_bit(&self, base: u32, vector: u8) -> bool {
+        self.read_reg(base + vector as u32 / 32)
+            .get_bit(vector as usize % 32)
+    }
+
+    fn set_bit(&mut self, base: u32, vector: u8, value: bool) {
+        let index = base + vector as u32 / 32;
+        let mut bits = self.read_reg(index);
+        bits.set_bit(vector as usize % 32, value);
+        self.write_reg(index, bits);
+    }
+
+    fn highest_bit(&self, base: u32) -> Option<u8> {
+        (0..8).rev().find_map(|i| {
+            let bits = self.read_reg(base + i);
+            if bits != 0 {
+                Some((i * 32 + 31 - bits.leading_zeros()) as u8)
+            } else {
+                None
+            }
+        })
+    }
+}
@@ -14,6 +14,6 @@ cfg_if::cfg_if! {
 
 pub(crate) use vender::{has_hardware_support, ArchPerCpuState};
 
-pub use lapic::ApicTimer;
-pub use regs::GeneralRegisters;
+pub use lapic::{ApicTimer, VirtualApic};
+pub use regs::{GeneralRegisters, GuestModeState, LongModeState};
 pub use vender::{NestedPageTable, RvmVcpu};
@@ -63,3 +63,42 @@ macro_rules! restore_regs_from_stack {
         pop r15"
     };
 }
+
+/// Guest states to start a vCPU directly in 64-bit mode, with paging enabled
+/// and flat segments.
+#[derive(Debug, Default, Clone)]
+pub struct LongModeState {
+    /// Guest `RIP`.
+    pub rip: u64,
+    /// Guest `RSP`.
+    pub rsp: u64,
+    /// Guest physical address of the PML4 table. (`CR3`)
+    pub cr3: u64,
+    /// Guest physical address of the GDT.
+    pub gdt_base: u64,
+    /// Limit of the GDT.
+    pub gdt_limit: u16,
+    /// Selector of a 64-bit code segment in the GDT.
+    pub code_selector: u16,
+    /// Selector of a data segment in the GDT, loaded into `DS`, `ES`, `SS`, `FS` and `GS`.
+    pub data_selector: u16,
+}
+
+/// Guest states to translate guest virtual addresses and decode guest
+/// instructions, read on VM exits.
+#[derive(Debug, Default, Clone)]
+pub struct GuestModeState {
+    /// Guest `CR0`.
+    pub cr0: u64,
+    /// Guest `CR3`.
+    pub cr3: u64,
+    /// Guest `CR4`.
+    pub cr4: u64,
+    /// Guest `IA32_EFER`.
+    pub efer: u64,
+    /// Base address of `CS`.
+    pub cs_base: u64,
+    /// Access rights of `CS`, the `L` (bit 13) and `D/B` (bit 14) bits give
+    /// the default operand and address sizes.
+    pub cs_access_rights: u32,
+}
@@ -68,8 +68,22 @@ impl From<MemFlags> for EPTFlags {
         if f.contains(MemFlags::EXECUTE) {
             ret |= Self::EXECUTE;
         }
-        if !f.contains(MemFlags::DEVICE) {
-            ret.set_mem_type(EPTMemType::WriteBack);
+        let mem_type = if f.contains(MemFlags::UNCACHED) {
+            EPTMemType::Uncached
+        } else if f.contains(MemFlags::WRITE_COMBINING) {
+            EPTMemType::WriteCombining
+        } else if f.contains(MemFlags::WRITE_THROUGH) {
+            EPTMemType::WriteThrough
+        } else if f.contains(MemFlags::WRITE_PROTECTED) {
+            EPTMemType::WriteProtected
+        } else if f.contains(MemFlags::DEVICE) {
+            EPTMemType::Uncached
+        } else {
+            EPTMemType::WriteBack
+        };
+        ret.set_mem_type(mem_type);
+        if f.contains(MemFlags::IGNORE_PAT) {
+            ret |= Self::IGNORE_PAT;
         }
         ret
     }
@@ -87,8 +101,15 @@ impl From<EPTFlags> for MemFlags {
         if f.contains(EPTFlags::EXECUTE) {
             ret |= Self::EXECUTE;
         }
-        if let Ok(EPTMemType::Uncached) = f.mem_type() {
-            ret |= Self::DEVICE;
+        match f.mem_type() {
+            Ok(EPTMemType::Uncached) => ret |= Self::DEVICE,
+            Ok(EPTMemType::WriteCombining) => ret |= Self::WRITE_COMBINING,
+            Ok(EPTMemType::WriteThrough) => ret |= Self::WRITE_THROUGH,
+            Ok(EPTMemType::WriteProtected) => ret |= Self::WRITE_PROTECTED,
+            _ => {}
+        }
+        if f.contains(EPTFlags::IGNORE_PAT) {
+            ret |= Self::IGNORE_PAT;
         }
         ret
     }
@@ -56,6 +56,12 @@ impl<H: RvmHal> MsrBitmap<H> {
         self.frame.start_paddr()
     }
 
+    /// Whether accesses to the MSR can be passed through, the others always
+    /// cause VM exits.
+    pub fn contains(msr: u32) -> bool {
+        msr <= 0x1fff || (0xc000_0000..=0xc000_1fff).contains(&msr)
+    }
+
     fn set_intercept(&mut self, msr: u32, is_write: bool, intercept: bool) {
         let offset = if msr <= 0x1fff {
             if !is_write {
@@ -93,6 +99,48 @@ impl<H: RvmHal> MsrBitmap<H> {
     }
 }
 
+/// I/O bitmaps A (ports 0x0000..0x8000) and B (ports 0x8000..0x10000), a set
+/// bit makes the accesses to the port cause VM exits. (SDM Vol. 3C, Section 24.6.4)
+#[derive(Debug)]
+pub struct IoBitmap<H: RvmHal> {
+    frames: [PhysFrame<H>; 2],
+}
+
+impl<H: RvmHal> IoBitmap<H> {
+    #[allow(unused)]
+    pub fn passthrough_all() -> RvmResult<Self> {
+        Ok(Self {
+            frames: [PhysFrame::alloc_zero()?, PhysFrame::alloc_zero()?],
+        })
+    }
+
+    pub fn intercept_all() -> RvmResult<Self> {
+        let mut frames = [PhysFrame::alloc()?, PhysFrame::alloc()?];
+        frames.iter_mut().for_each(|f| f.fill(u8::MAX));
+        Ok(Self { frames })
+    }
+
+    /// Physical addresses of the bitmaps A and B.
+    pub fn phys_addrs(&self) -> (HostPhysAddr, HostPhysAddr) {
+        (self.frames[0].start_paddr(), self.frames[1].start_paddr())
+    }
+
+    pub fn set_intercept(&mut self, ports: core::ops::Range<u16>, intercept: bool) {
+        for port in ports {
+            let frame = &self.frames[port as usize >> 15];
+            let bitmap = unsafe { core::slice::from_raw_parts_mut(frame.as_mut_ptr(), PAGE_SIZE) };
+            let port = port & 0x7fff;
+            let byte = (port / 8) as usize;
+            let bits = port % 8;
+            if intercept {
+                bitmap[byte] |= 1 << bits;
+            } else {
+                bitmap[byte] &= !(1 << bits);
+            }
+        }
+    }
+}
+
 /// Reporting Register of Basic VMX Capabilities. (SDM Vol. 3D, Appendix A.1)
 #[derive(Debug)]
 pub struct VmxBasic {
@@ -7,16 +7,51 @@ use x86::bits64::vmx;
 use x86::dtables::{self, DescriptorTablePointer};
 use x86::segmentation::SegmentSelector;
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::EferFlags;
 
-use super::structs::{MsrBitmap, VmxRegion};
+use super::structs::{IoBitmap, MsrBitmap, VmxRegion};
 use super::vmcs::{
     self, VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16, VmcsGuest32, VmcsGuest64,
-    VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW,
+    VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW, VmcsReadOnlyNW,
 };
 use super::VmxPerCpuState;
-use crate::arch::{msr::Msr, ApicTimer, GeneralRegisters};
+use crate::arch::{
+    msr::Msr, ApicTimer, GeneralRegisters, GuestModeState, LongModeState, VirtualApic,
+};
 use crate::{GuestPhysAddr, HostPhysAddr, NestedPageFaultInfo, RvmHal, RvmResult};
 
+/// How the local APIC is virtualized, depends on the VMX capabilities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApicVirtMode {
+    /// All accesses are emulated, and the interrupts are injected by software.
+    Software,
+    /// `CR8` accesses use the TPR in the virtual-APIC page.
+    TprShadow,
+    /// The hardware delivers the interrupts pending in the virtual-APIC page,
+    /// and the x2APIC MSR accesses to TPR, EOI, SELF IPI and most readable
+    /// registers do not cause VM exits.
+    VirtualInterruptDelivery,
+}
+
+impl ApicVirtMode {
+    fn detect() -> Self {
+        use super::vmcs::controls::{PrimaryControls, SecondaryControls};
+        let allowed1 = |msr: Msr| (msr.read() >> 32) as u32;
+        let tpr_shadow = PrimaryControls::USE_TPR_SHADOW.bits();
+        let apicv = (SecondaryControls::VIRTUALIZE_X2APIC
+            | SecondaryControls::VIRTUALIZE_APIC_REGISTER
+            | SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY)
+            .bits();
+        if allowed1(Msr::IA32_VMX_TRUE_PROCBASED_CTLS) & tpr_shadow == 0 {
+            Self::Software
+        } else if allowed1(Msr::IA32_VMX_PROCBASED_CTLS2) & apicv != apicv {
+            Self::TprShadow
+        } else {
+            Self::VirtualInterruptDelivery
+        }
+    }
+}
+
 /// A virtual CPU within a guest.
 #[repr(C)]
 pub struct VmxVcpu<H: RvmHal> {
@@ -24,8 +59,20 @@ pub struct VmxVcpu<H: RvmHal> {
     host_stack_top: u64,
     vmcs: VmxRegion<H>,
     msr_bitmap: MsrBitmap<H>,
-    apic_timer: ApicTimer<H>,
-    pending_events: VecDeque<(u8, Option<u32>)>,
+    io_bitmap: IoBitmap<H>,
+    lapic: VirtualApic<H>,
+    apic_virt_mode: ApicVirtMode,
+    /// The exception to inject before NMIs and interrupts.
+    pending_exception: Option<(u8, Option<u32>)>,
+    /// External interrupts not delivered by the local APIC.
+    pending_interrupts: VecDeque<u8>,
+    pending_nmi: bool,
+    /// The event being delivered when the last VM exit occurred, with the
+    /// instruction length for software interrupts and exceptions.
+    vectoring_event: Option<(vmcs::VmxInterruptInfo, u32)>,
+    /// Set if the pending exceptions cause a triple fault, which is reported
+    /// as a `TRIPLE_FAULT` VM exit.
+    triple_fault: bool,
 }
 
 impl<H: RvmHal> VmxVcpu<H> {
@@ -39,12 +86,22 @@ impl<H: RvmHal> VmxVcpu<H> {
             host_stack_top: 0,
             vmcs: VmxRegion::new(percpu.vmcs_revision_id, false)?,
             msr_bitmap: MsrBitmap::passthrough_all()?,
-            apic_timer: ApicTimer::new(),
-            pending_events: VecDeque::with_capacity(8),
+            io_bitmap: IoBitmap::intercept_all()?,
+            lapic: VirtualApic::new()?,
+            apic_virt_mode: ApicVirtMode::detect(),
+            pending_exception: None,
+            pending_interrupts: VecDeque::with_capacity(8),
+            pending_nmi: false,
+            vectoring_event: None,
+            triple_fault: false,
         };
         vcpu.setup_msr_bitmap()?;
         vcpu.setup_vmcs(entry, ept_root)?;
-        info!("[RVM] created VmxVcpu(vmcs: {:#x})", vcpu.vmcs.phys_addr());
+        info!(
+            "[RVM] created VmxVcpu(vmcs: {:#x}, APIC virtualization: {:?})",
+            vcpu.vmcs.phys_addr(),
+            vcpu.apic_virt_mode
+        );
         Ok(vcpu)
     }
 
@@ -58,7 +115,12 @@ impl<H: RvmHal> VmxVcpu<H> {
 
     /// Basic information about VM exits.
     pub fn exit_info(&self) -> RvmResult<vmcs::VmxExitInfo> {
-        vmcs::exit_info()
+        let mut info = vmcs::exit_info()?;
+        if self.triple_fault {
+            // raised when re-injecting events, not by the hardware
+            info.exit_reason = super::VmxExitReason::TRIPLE_FAULT;
+        }
+        Ok(info)
     }
 
     /// Information for VM exits due to external interrupts.
@@ -76,6 +138,26 @@ impl<H: RvmHal> VmxVcpu<H> {
         vmcs::ept_violation_info()
     }
 
+    /// The vector of the EOI for VM exits due to EOI virtualization
+    /// (`VIRTUALIZED_EOI`), the vector has been retired from ISR.
+    pub fn virtualized_eoi_vector(&self) -> RvmResult<u8> {
+        // SDM Vol. 3C, Section 27.2.1, Table 27-1
+        Ok(VmcsReadOnlyNW::EXIT_QUALIFICATION.read()? as u8)
+    }
+
+    /// The offset in the virtual-APIC page of the register written for
+    /// APIC-write VM exits (`APIC_WRITE`), the value has been written to the page.
+    pub fn apic_write_offset(&self) -> RvmResult<usize> {
+        // SDM Vol. 3C, Section 27.2.1, Table 27-1
+        Ok(VmcsReadOnlyNW::EXIT_QUALIFICATION.read()? & 0xfff)
+    }
+
+    /// Flush the TLB entries derived from the nested page table, after the
+    /// permissions or memory types of mapped pages are changed.
+    pub fn flush_nested_page_table(&self) -> RvmResult {
+        vmcs::invalidate_ept()
+    }
+
     /// Guest general-purpose registers.
     pub fn regs(&self) -> &GeneralRegisters {
         &self.guest_regs
@@ -96,15 +178,129 @@ impl<H: RvmHal> VmxVcpu<H> {
         VmcsGuestNW::RSP.write(rsp).unwrap()
     }
 
+    /// Guest instruction pointer. (`RIP`)
+    pub fn rip(&self) -> usize {
+        VmcsGuestNW::RIP.read().unwrap()
+    }
+
+    /// Guest control registers and code segment, to access the guest memory
+    /// by virtual addresses and decode guest instructions.
+    pub fn guest_mode_state(&self) -> RvmResult<GuestModeState> {
+        Ok(GuestModeState {
+            cr0: VmcsGuestNW::CR0.read()? as _,
+            cr3: VmcsGuestNW::CR3.read()? as _,
+            cr4: VmcsGuestNW::CR4.read()? as _,
+            efer: VmcsGuest64::IA32_EFER.read()?,
+            cs_base: VmcsGuestNW::CS_BASE.read()? as _,
+            cs_access_rights: VmcsGuest32::CS_ACCESS_RIGHTS.read()?,
+        })
+    }
+
+    /// The current privilege level of the guest, which is the DPL of `SS`.
+    /// (SDM Vol. 3C, Section 24.4.1)
+    pub fn guest_cpl(&self) -> RvmResult<u8> {
+        Ok(VmcsGuest32::SS_ACCESS_RIGHTS.read()?.get_bits(5..7) as u8)
+    }
+
+    /// Set guest states to start directly in 64-bit mode, instead of the real
+    /// mode at the entry point given in [`RvmPerCpu::create_vcpu`](crate::RvmPerCpu::create_vcpu).
+    pub fn set_long_mode(&mut self, state: &LongModeState) -> RvmResult {
+        let cr0 = Cr0Flags::PROTECTED_MODE_ENABLE
+            | Cr0Flags::EXTENSION_TYPE
+            | Cr0Flags::NUMERIC_ERROR
+            | Cr0Flags::PAGING;
+        let cr4 = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS;
+        let efer = EferFlags::LONG_MODE_ENABLE | EferFlags::LONG_MODE_ACTIVE;
+        VmcsGuestNW::CR0.write(cr0.bits() as _)?;
+        VmcsControlNW::CR0_READ_SHADOW.write(cr0.bits() as _)?;
+        VmcsGuestNW::CR3.write(state.cr3 as _)?;
+        VmcsGuestNW::CR4.write(cr4.bits() as _)?;
+        VmcsControlNW::CR4_READ_SHADOW.write(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() as _)?;
+        VmcsGuest64::IA32_EFER.write(efer.bits())?;
+
+        macro_rules! set_guest_segment {
+            ($seg: ident, $selector: expr, $access_rights: expr) => {{
+                use VmcsGuest16::*;
+                use VmcsGuest32::*;
+                use VmcsGuestNW::*;
+                concat_idents!($seg, _SELECTOR).write($selector)?;
+                concat_idents!($seg, _BASE).write(0)?;
+                concat_idents!($seg, _LIMIT).write(0xffff_ffff)?;
+                concat_idents!($seg, _ACCESS_RIGHTS).write($access_rights)?;
+            }};
+        }
+
+        let ds = state.data_selector;
+        set_guest_segment!(CS, state.code_selector, 0xa09b); // 64-bit, 4K granularity, code, exec/read
+        set_guest_segment!(ES, ds, 0xc093); // 32-bit, 4K granularity, data, read/write
+        set_guest_segment!(SS, ds, 0xc093);
+        set_guest_segment!(DS, ds, 0xc093);
+        set_guest_segment!(FS, ds, 0xc093);
+        set_guest_segment!(GS, ds, 0xc093);
+        VmcsGuestNW::GDTR_BASE.write(state.gdt_base as _)?;
+        VmcsGuest32::GDTR_LIMIT.write(state.gdt_limit as _)?;
+
+        VmcsGuestNW::RSP.write(state.rsp as _)?;
+        VmcsGuestNW::RIP.write(state.rip as _)?;
+        VmcsGuestNW::RFLAGS.write(0x2)?;
+
+        // The "IA-32e mode guest" VM-entry control must be equal to the guest EFER.LMA.
+        let mut ctrl = VmcsControl32::VMENTRY_CONTROLS.read()?;
+        ctrl |= vmcs::controls::EntryControls::IA32E_MODE_GUEST.bits();
+        VmcsControl32::VMENTRY_CONTROLS.write(ctrl)?;
+        Ok(())
+    }
+
     /// Advance guest `RIP` by `instr_len` bytes.
     pub fn advance_rip(&mut self, instr_len: u8) -> RvmResult {
         Ok(VmcsGuestNW::RIP.write(VmcsGuestNW::RIP.read()? + instr_len as usize)?)
     }
 
-    /// Add a virtual interrupt or exception to the pending events list,
-    /// and try to inject it before later VM entries.
-    pub fn inject_event(&mut self, vector: u8, err_code: Option<u32>) {
-        self.pending_events.push_back((vector, err_code));
+    /// Make a virtual exception pending, it's injected on the next VM entry
+    /// before NMIs and interrupts.
+    ///
+    /// If another exception is pending, the two are combined by the rules of
+    /// double faults like [`reinject_event`](Self::reinject_event), and a
+    /// triple fault is reported as a `TRIPLE_FAULT` VM exit.
+    pub fn inject_exception(&mut self, vector: u8, err_code: Option<u32>) {
+        let exception = match self.pending_exception {
+            Some((first, _)) => combine_exceptions(first, (vector, err_code)),
+            None => Some((vector, err_code)),
+        };
+        if exception.is_none() {
+            warn!("[RVM] triple fault on exception {:#x} delivery", vector);
+            self.triple_fault = true;
+        }
+        self.pending_exception = exception;
+    }
+
+    /// Add a virtual external interrupt to the pending list, and try to inject
+    /// it before later VM entries.
+    ///
+    /// The interrupts are injected before the ones of the local APIC, it's for
+    /// the external interrupts not delivered by the local APIC, like the ones
+    /// from the 8259 PIC. Use [`VirtualApic::request_interrupt`] for the
+    /// others.
+    pub fn inject_interrupt(&mut self, vector: u8) {
+        self.pending_interrupts.push_back(vector);
+    }
+
+    /// Make a virtual NMI pending, it's injected when the guest NMIs are not
+    /// blocked. Only one NMI can be pending, like the NMI latch of processors.
+    pub fn inject_nmi(&mut self) {
+        self.pending_nmi = true;
+    }
+
+    /// Whether an external interrupt added by [`VmxVcpu::inject_interrupt`]
+    /// now is injected on the next VM entry, as the guest interrupts are not
+    /// blocked and no other events are waiting. Otherwise, the VMM can enable
+    /// interrupt-window exiting and try again on that VM exit.
+    pub fn can_inject_interrupt(&self) -> bool {
+        self.vectoring_event.is_none()
+            && self.pending_exception.is_none()
+            && self.pending_interrupts.is_empty()
+            && !self.pending_nmi
+            && self.allow_interrupt()
     }
 
     /// If enable, a VM exit occurs at the beginning of any instruction if
@@ -122,9 +318,105 @@ impl<H: RvmHal> VmxVcpu<H> {
         Ok(())
     }
 
+    /// If enable, a VM exit occurs at the beginning of any instruction if there
+    /// is no virtual-NMI blocking. (see SDM, Vol. 3C, Section 24.6.2)
+    pub fn set_nmi_window(&mut self, enable: bool) -> RvmResult {
+        let mut ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
+        let bits = vmcs::controls::PrimaryControls::NMI_WINDOW_EXITING.bits();
+        if enable {
+            ctrl |= bits
+        } else {
+            ctrl &= !bits
+        }
+        VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
+        Ok(())
+    }
+
     /// Returns the mutable reference of [`ApicTimer`].
     pub fn apic_timer_mut(&mut self) -> &mut ApicTimer<H> {
-        &mut self.apic_timer
+        self.lapic.timer_mut()
+    }
+
+    /// Returns the reference of [`VirtualApic`].
+    pub fn lapic(&self) -> &VirtualApic<H> {
+        &self.lapic
+    }
+
+    /// Returns the mutable reference of [`VirtualApic`].
+    pub fn lapic_mut(&mut self) -> &mut VirtualApic<H> {
+        &mut self.lapic
+    }
+
+    /// Put the vCPU into the reset state, as it's just created by
+    /// [`RvmPerCpu::create_vcpu`](crate::RvmPerCpu::create_vcpu) with `entry`
+    /// and `ept_root`. The pending events and the local APIC are cleared.
+    pub fn reset(&mut self, entry: GuestPhysAddr, ept_root: HostPhysAddr) -> RvmResult {
+        self.guest_regs = GeneralRegisters::default();
+        self.pending_exception = None;
+        self.pending_interrupts.clear();
+        self.pending_nmi = false;
+        self.vectoring_event = None;
+        self.triple_fault = false;
+        self.set_x2apic_mode(false)?;
+        self.lapic.reset();
+        self.set_interrupt_window(false)?;
+        self.set_nmi_window(false)?;
+
+        self.setup_vmcs_guest(entry)?;
+        // leave the IA-32e mode set by `set_long_mode`
+        let mut ctrl = VmcsControl32::VMENTRY_CONTROLS.read()?;
+        ctrl &= !vmcs::controls::EntryControls::IA32E_MODE_GUEST.bits();
+        VmcsControl32::VMENTRY_CONTROLS.write(ctrl)?;
+        vmcs::set_ept_pointer(ept_root)?;
+        Ok(())
+    }
+
+    /// Set whether `RDMSR` and `WRMSR` of the MSR cause VM exits. Only the MSRs
+    /// in 0..0x2000 and 0xc000_0000..0xc000_2000 can be passed through, the
+    /// others are always intercepted. The x2APIC MSRs are also set by
+    /// [`set_x2apic_mode`](Self::set_x2apic_mode).
+    pub fn set_msr_intercept(&mut self, msr: u32, read: bool, write: bool) -> RvmResult {
+        if !MsrBitmap::<H>::contains(msr) {
+            if read && write {
+                return Ok(());
+            }
+            return rvm_err!(InvalidParam, "MSR out of the MSR bitmap");
+        }
+        self.msr_bitmap.set_read_intercept(msr, read);
+        self.msr_bitmap.set_write_intercept(msr, write);
+        Ok(())
+    }
+
+    /// Set whether the I/O instructions accessing `ports` cause VM exits, all
+    /// ports are intercepted by default. An access to multiple ports causes a
+    /// VM exit if any of them is intercepted.
+    pub fn set_io_intercept(&mut self, ports: core::ops::Range<u16>, intercept: bool) {
+        self.io_bitmap.set_intercept(ports, intercept);
+    }
+
+    /// Switch the local APIC between the xAPIC and x2APIC modes. With virtual
+    /// interrupt delivery, the x2APIC MSR accesses are also virtualized in the
+    /// x2APIC mode, and the others are still intercepted.
+    pub fn set_x2apic_mode(&mut self, enable: bool) -> RvmResult {
+        self.lapic.set_x2apic_mode(enable);
+        if self.apic_virt_mode != ApicVirtMode::VirtualInterruptDelivery {
+            return Ok(());
+        }
+        let mut ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+        let bits = vmcs::controls::SecondaryControls::VIRTUALIZE_X2APIC.bits();
+        if enable {
+            ctrl |= bits
+        } else {
+            ctrl &= !bits
+        }
+        VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
+        for msr in 0x800..=0x83f {
+            let read = enable && VirtualApic::<H>::is_read_virtualized(msr);
+            let write = enable && VirtualApic::<H>::is_write_virtualized(msr);
+            self.msr_bitmap.set_read_intercept(msr, !read);
+            self.msr_bitmap.set_write_intercept(msr, !write);
+        }
+        Ok(())
     }
 }
 
@@ -249,46 +541,61 @@ impl<H: RvmHal> VmxVcpu<H> {
 
         VmcsGuest64::LINK_PTR.write(u64::MAX)?; // SDM Vol. 3C, Section 24.4.2
         VmcsGuest64::IA32_DEBUGCTL.write(0)?;
-        VmcsGuest64::IA32_PAT.write(Msr::IA32_PAT.read())?;
+        VmcsGuest64::IA32_PAT.write(PAT_POWER_ON_VALUE)?;
         VmcsGuest64::IA32_EFER.write(0)?;
         Ok(())
     }
 
     fn setup_vmcs_control(&mut self, ept_root: HostPhysAddr) -> RvmResult {
-        // Intercept NMI and external interrupts.
+        // Intercept NMI and external interrupts, use virtual NMIs to track the guest NMI
+        // blocking, which is required by NMI-window exiting.
         use super::vmcs::controls::*;
         use PinbasedControls as PinCtrl;
         vmcs::set_control(
             VmcsControl32::PINBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_TRUE_PINBASED_CTLS,
             Msr::IA32_VMX_PINBASED_CTLS.read() as u32,
-            (PinCtrl::NMI_EXITING | PinCtrl::EXTERNAL_INTERRUPT_EXITING).bits(),
+            (PinCtrl::NMI_EXITING | PinCtrl::EXTERNAL_INTERRUPT_EXITING | PinCtrl::VIRTUAL_NMIS)
+                .bits(),
             0,
         )?;
 
-        // Intercept all I/O instructions, use MSR bitmaps, activate secondary controls,
-        // disable CR3 load/store interception.
+        // Use I/O bitmaps and MSR bitmaps, activate secondary controls,
+        // disable CR3 load/store interception. Use the TPR shadow if supported, then
+        // CR8 accesses do not cause VM exits.
         use PrimaryControls as CpuCtrl;
+        let mut set =
+            CpuCtrl::USE_IO_BITMAPS | CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SECONDARY_CONTROLS;
+        let mut clear =
+            CpuCtrl::UNCOND_IO_EXITING | CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING;
+        if self.apic_virt_mode != ApicVirtMode::Software {
+            set |= CpuCtrl::USE_TPR_SHADOW;
+            clear |= CpuCtrl::CR8_LOAD_EXITING | CpuCtrl::CR8_STORE_EXITING;
+        }
         vmcs::set_control(
             VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
             Msr::IA32_VMX_PROCBASED_CTLS.read() as u32,
-            (CpuCtrl::UNCOND_IO_EXITING | CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SECONDARY_CONTROLS)
-                .bits(),
-            (CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING).bits(),
+            set.bits(),
+            clear.bits(),
         )?;
 
-        // Enable EPT, RDTSCP, INVPCID, and unrestricted guest.
+        // Enable EPT, RDTSCP, INVPCID, and unrestricted guest. Enable APIC-register
+        // virtualization and virtual-interrupt delivery if supported, the x2APIC
+        // virtualization is enabled after the guest switches to the x2APIC mode.
         use SecondaryControls as CpuCtrl2;
+        let mut set = CpuCtrl2::ENABLE_EPT
+            | CpuCtrl2::ENABLE_RDTSCP
+            | CpuCtrl2::ENABLE_INVPCID
+            | CpuCtrl2::UNRESTRICTED_GUEST;
+        if self.apic_virt_mode == ApicVirtMode::VirtualInterruptDelivery {
+            set |= CpuCtrl2::VIRTUALIZE_APIC_REGISTER | CpuCtrl2::VIRTUAL_INTERRUPT_DELIVERY;
+        }
         vmcs::set_control(
             VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_PROCBASED_CTLS2,
             0,
-            (CpuCtrl2::ENABLE_EPT
-                | CpuCtrl2::ENABLE_RDTSCP
-                | CpuCtrl2::ENABLE_INVPCID
-                | CpuCtrl2::UNRESTRICTED_GUEST)
-                .bits(),
+            set.bits(),
             0,
         )?;
 
@@ -320,15 +627,28 @@ impl<H: RvmHal> VmxVcpu<H> {
 
         vmcs::set_ept_pointer(ept_root)?;
 
+        // The register page of the virtual local APIC is the virtual-APIC page.
+        if self.apic_virt_mode != ApicVirtMode::Software {
+            VmcsControl64::VIRT_APIC_ADDR.write(self.lapic.phys_addr() as _)?;
+            VmcsControl32::TPR_THRESHOLD.write(0)?;
+        }
+        if self.apic_virt_mode == ApicVirtMode::VirtualInterruptDelivery {
+            for field in EOI_EXIT_BITMAPS {
+                field.write(0)?;
+            }
+            VmcsGuest16::INTERRUPT_STATUS.write(0)?;
+        }
+
         // No MSR switches if hypervisor doesn't use and there is only one vCPU.
         VmcsControl32::VMEXIT_MSR_STORE_COUNT.write(0)?;
         VmcsControl32::VMEXIT_MSR_LOAD_COUNT.write(0)?;
         VmcsControl32::VMENTRY_MSR_LOAD_COUNT.write(0)?;
 
-        // Pass-through exceptions, don't use I/O bitmap, set MSR bitmaps.
+        // Pass-through exceptions, set I/O bitmaps and MSR bitmaps.
         VmcsControl32::EXCEPTION_BITMAP.write(0)?;
-        VmcsControl64::IO_BITMAP_A_ADDR.write(0)?;
-        VmcsControl64::IO_BITMAP_B_ADDR.write(0)?;
+        let (io_bitmap_a, io_bitmap_b) = self.io_bitmap.phys_addrs();
+        VmcsControl64::IO_BITMAP_A_ADDR.write(io_bitmap_a as _)?;
+        VmcsControl64::IO_BITMAP_B_ADDR.write(io_bitmap_b as _)?;
         VmcsControl64::MSR_BITMAPS_ADDR.write(self.msr_bitmap.phys_addr() as _)?;
         Ok(())
     }
@@ -375,34 +695,231 @@ impl<H: RvmHal> VmxVcpu<H> {
         let rflags = VmcsGuestNW::RFLAGS.read().unwrap();
         let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read().unwrap();
         rflags as u64 & x86_64::registers::rflags::RFlags::INTERRUPT_FLAG.bits() != 0
-            && block_state == 0
-    }
-
-    /// Try to inject a pending event before next VM entry.
-    fn check_pending_events(&mut self) -> RvmResult {
-        if let Some(event) = self.pending_events.front() {
-            if event.0 < 32 || self.allow_interrupt() {
-                // if it's an exception, or an interrupt that is not blocked, inject it directly.
-                vmcs::inject_event(event.0, event.1)?;
-                self.pending_events.pop_front();
-            } else {
-                // interrupts are blocked, enable interrupt-window exiting.
-                self.set_interrupt_window(true)?;
+            && block_state & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) == 0
+    }
+
+    /// Whether the guest NMIs are blocked. NMIs are not injected in the STI or
+    /// MOV SS shadow either, as VM entries may fail on some processors.
+    /// (SDM Vol. 3C, Section 26.3.1.5)
+    fn allow_nmi(&self) -> bool {
+        let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read().unwrap();
+        block_state & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS | BLOCKING_BY_NMI) == 0
+    }
+
+    /// If the VM exit occurred during the execution of an IRET that unblocked
+    /// NMIs, the guest is still in the NMI handler after the VM entry, so the
+    /// blocking by NMI must be restored. (SDM Vol. 3C, Section 27.2.3)
+    fn restore_nmi_blocking(&mut self) -> RvmResult {
+        use super::definitions::VmxExitReason;
+        use super::vmcs::VmcsReadOnly32;
+        // the bit is undefined if the VM exit occurred during event delivery
+        if VmcsReadOnly32::IDT_VECTORING_INFO.read()?.get_bit(31) {
+            return Ok(());
+        }
+        let unblocked_by_iret = match vmcs::exit_info()?.exit_reason {
+            VmxExitReason::EXCEPTION_NMI => {
+                VmcsReadOnly32::VMEXIT_INTERRUPTION_INFO.read()?.get_bit(12)
             }
+            VmxExitReason::EPT_VIOLATION => VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?.get_bit(12),
+            _ => false,
+        };
+        if unblocked_by_iret {
+            let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read()?;
+            VmcsGuest32::INTERRUPTIBILITY_STATE.write(block_state | BLOCKING_BY_NMI)?;
+        }
+        Ok(())
+    }
+
+    /// Save the event whose delivery was interrupted by the VM exit, like an
+    /// EPT violation when pushing the exception frame, it must be re-injected
+    /// on the next VM entry. (SDM Vol. 3C, Section 27.2.4)
+    fn save_vectoring_event(&mut self) -> RvmResult {
+        use super::vmcs::VmcsReadOnly32;
+        let info = vmcs::idt_vectoring_info()?;
+        if info.valid {
+            let instr_len = VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?;
+            debug!("[RVM] re-inject the interrupted event {:#x?}", info);
+            self.vectoring_event = Some((info, instr_len));
         }
         Ok(())
     }
 
+    /// Re-inject the interrupted event. If the VM exit handler raised a new
+    /// exception meanwhile, the two exceptions are combined by the rules of
+    /// double faults. Returns `false` if they cause a triple fault, and
+    /// nothing is injected.
+    fn reinject_event(&mut self, info: vmcs::VmxInterruptInfo, instr_len: u32) -> RvmResult<bool> {
+        use super::definitions::VmxInterruptionType;
+        let second = match self.pending_exception {
+            Some(second) if info.int_type == VmxInterruptionType::HardException => second,
+            // the new events, if any, are injected after this one
+            _ => return vmcs::reinject_event(&info, instr_len).map(|_| true),
+        };
+        self.pending_exception = None;
+        // if handled serially, the first exception is generated again when
+        // the faulting instruction is executed again.
+        match combine_exceptions(info.vector, second) {
+            Some((vector, err_code)) => vmcs::inject_event(vector, err_code)?,
+            None => {
+                warn!(
+                    "[RVM] triple fault on exception {:#x} delivery",
+                    info.vector
+                );
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Inject a pending external interrupt, from the pending list or the
+    /// local APIC. Returns whether an interrupt is injected.
+    fn inject_pending_interrupt(&mut self) -> RvmResult<bool> {
+        if let Some(vector) = self.pending_interrupts.pop_front() {
+            vmcs::inject_event(vector, None)?;
+        } else if self.apic_virt_mode == ApicVirtMode::VirtualInterruptDelivery {
+            // the hardware evaluates and delivers the interrupts pending in the
+            // virtual-APIC page. (SDM Vol. 3C, Section 29.2)
+            return Ok(false);
+        } else if let Some(vector) = self.lapic.pending_vector() {
+            vmcs::inject_event(vector, None)?;
+            self.lapic.accept_interrupt(vector);
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn has_pending_interrupt(&self) -> bool {
+        !self.pending_interrupts.is_empty()
+            || (self.apic_virt_mode != ApicVirtMode::VirtualInterruptDelivery
+                && self.lapic.pending_vector().is_some())
+    }
+
+    /// Try to inject a pending event, or the highest priority interrupt of the
+    /// local APIC, before next VM entry. Returns `false` if the guest triple
+    /// faults.
+    fn check_pending_events(&mut self) -> RvmResult<bool> {
+        if self.apic_virt_mode == ApicVirtMode::VirtualInterruptDelivery {
+            VmcsGuest16::INTERRUPT_STATUS.write(self.lapic.interrupt_status())?;
+            for (field, bits) in EOI_EXIT_BITMAPS
+                .into_iter()
+                .zip(self.lapic.eoi_exit_bitmap())
+            {
+                field.write(bits)?;
+            }
+        } else if self.apic_virt_mode == ApicVirtMode::TprShadow {
+            // the guest may have changed TPR in the virtual-APIC page without VM exits.
+            self.lapic.update_ppr();
+        }
+
+        // inject one event at most, the interrupted event first, then exceptions,
+        // NMIs and interrupts. (SDM Vol. 3A, Section 6.9)
+        if self.triple_fault {
+            // raised by `inject_exception`, the interrupted event is discarded
+            self.vectoring_event = None;
+            return Ok(false);
+        } else if let Some((info, instr_len)) = self.vectoring_event.take() {
+            if !self.reinject_event(info, instr_len)? {
+                return Ok(false);
+            }
+        } else if let Some((vector, err_code)) = self.pending_exception.take() {
+            vmcs::inject_event(vector, err_code)?;
+        } else if self.pending_nmi && self.allow_nmi() {
+            vmcs::inject_event(NMI_VECTOR, None)?;
+            self.pending_nmi = false;
+        } else if self.allow_interrupt() {
+            self.inject_pending_interrupt()?;
+        }
+
+        // the remaining events are blocked, or can't be injected together,
+        // enable NMI-window or interrupt-window exiting.
+        if self.pending_nmi {
+            self.set_nmi_window(true)?;
+        }
+        if self.has_pending_interrupt() {
+            self.set_interrupt_window(true)?;
+        }
+
+        if self.apic_virt_mode == ApicVirtMode::TprShadow {
+            VmcsControl32::TPR_THRESHOLD.write(self.lapic.tpr_threshold())?;
+        }
+        Ok(true)
+    }
+
     fn vmexit_handler(&mut self) {
+        self.restore_nmi_blocking().unwrap();
+        self.save_vectoring_event().unwrap();
         H::vmexit_handler(self);
         // Check if there is an APIC timer interrupt
-        if self.apic_timer.check_interrupt() {
-            self.inject_event(self.apic_timer.vector(), None);
+        if self.lapic.timer_mut().check_interrupt() {
+            let vector = self.lapic.timer().vector();
+            self.lapic.request_interrupt(vector, false);
+        }
+        if !self.check_pending_events().unwrap() {
+            // let the VMM handle the triple fault like the hardware one
+            self.triple_fault = true;
+            H::vmexit_handler(self);
+            self.triple_fault = false;
+            self.check_pending_events().unwrap();
         }
-        self.check_pending_events().unwrap();
     }
 }
 
+const NMI_VECTOR: u8 = 2;
+const DOUBLE_FAULT_VECTOR: u8 = 8;
+
+/// Exception classes for the double fault rules. (SDM Vol. 3A, Section 6.15, Table 6-4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExceptionClass {
+    Benign,
+    Contributory,
+    PageFault,
+    DoubleFault,
+}
+
+impl ExceptionClass {
+    const fn of(vector: u8) -> Self {
+        match vector {
+            // #DE, #TS, #NP, #SS, #GP, #CP
+            0 | 10..=13 | 21 => Self::Contributory,
+            // #PF, #VE
+            14 | 20 => Self::PageFault,
+            DOUBLE_FAULT_VECTOR => Self::DoubleFault,
+            _ => Self::Benign,
+        }
+    }
+}
+
+/// The exception to deliver if `second` is raised while delivering the
+/// exception `first`, `second` itself if they are handled serially, or a
+/// double fault. Returns `None` for a triple fault.
+/// (SDM Vol. 3A, Section 6.15, Table 6-5)
+fn combine_exceptions(first: u8, second: (u8, Option<u32>)) -> Option<(u8, Option<u32>)> {
+    use ExceptionClass::*;
+    match (ExceptionClass::of(first), ExceptionClass::of(second.0)) {
+        (DoubleFault, Contributory | PageFault) => None,
+        (Contributory, Contributory) | (PageFault, Contributory | PageFault) => {
+            Some((DOUBLE_FAULT_VECTOR, Some(0)))
+        }
+        _ => Some(second),
+    }
+}
+
+/// WB, WT, UC- and UC for both PAT0-3 and PAT4-7. (SDM Vol. 3A, Section 11.12.4)
+const PAT_POWER_ON_VALUE: u64 = 0x0007_0406_0007_0406;
+
+/// Bits of the guest interruptibility state. (SDM Vol. 3C, Section 24.4.2, Table 24-3)
+const BLOCKING_BY_STI: u32 = 1 << 0;
+const BLOCKING_BY_MOV_SS: u32 = 1 << 1;
+const BLOCKING_BY_NMI: u32 = 1 << 3;
+
+const EOI_EXIT_BITMAPS: [VmcsControl64; 4] = [
+    VmcsControl64::EOI_EXIT0,
+    VmcsControl64::EOI_EXIT1,
+    VmcsControl64::EOI_EXIT2,
+    VmcsControl64::EOI_EXIT3,
+];
+
 impl<H: RvmHal> Drop for VmxVcpu<H> {
     fn drop(&mut self) {
         unsafe { vmx::vmclear(self.vmcs.phys_addr() as u64).unwrap() };
@@ -596,6 +596,15 @@ pub fn set_ept_pointer(pml4_paddr: HostPhysAddr) -> RvmResult {
     Ok(())
 }
 
+/// Invalidate the cached mappings of the current EPT, after changing the
+/// entries in use.
+pub fn invalidate_ept() -> RvmResult {
+    use super::instructions::{invept, InvEptType};
+    let eptp = VmcsControl64::EPTP.read()?;
+    unsafe { invept(InvEptType::SingleContext, eptp)? };
+    Ok(())
+}
+
 pub fn instruction_error() -> VmxInstructionError {
     VmcsReadOnly32::VM_INSTRUCTION_ERROR.read().unwrap().into()
 }
@@ -628,6 +637,21 @@ pub fn interrupt_exit_info() -> RvmResult<VmxInterruptInfo> {
     })
 }
 
+pub fn idt_vectoring_info() -> RvmResult<VmxInterruptInfo> {
+    // SDM Vol. 3C, Section 24.9.3
+    let info = VmcsReadOnly32::IDT_VECTORING_INFO.read()?;
+    Ok(VmxInterruptInfo {
+        vector: info.get_bits(0..8) as u8,
+        int_type: VmxInterruptionType::try_from(info.get_bits(8..11) as u8).unwrap(),
+        err_code: if info.get_bit(11) {
+            Some(VmcsReadOnly32::IDT_VECTORING_ERR_CODE.read()?)
+        } else {
+            None
+        },
+        valid: info.get_bit(31),
+    })
+}
+
 pub fn inject_event(vector: u8, err_code: Option<u32>) -> RvmResult {
     // SDM Vol. 3C, Section 24.8.3
     let err_code = if VmxInterruptionType::vector_has_error_code(vector) {
@@ -647,6 +671,20 @@ pub fn inject_event(vector: u8, err_code: Option<u32>) -> RvmResult {
     Ok(())
 }
 
+/// Inject the event described by `int_info` as is, with the instruction
+/// length `instr_len` for software interrupts and exceptions.
+pub fn reinject_event(int_info: &VmxInterruptInfo, instr_len: u32) -> RvmResult {
+    // SDM Vol. 3C, Section 27.2.4
+    if let Some(err_code) = int_info.err_code {
+        VmcsControl32::VMENTRY_EXCEPTION_ERR_CODE.write(err_code)?;
+    }
+    if int_info.int_type.is_soft() {
+        VmcsControl32::VMENTRY_INSTRUCTION_LEN.write(instr_len)?;
+    }
+    VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD.write(int_info.bits())?;
+    Ok(())
+}
+
 pub fn io_exit_info() -> RvmResult<VmxIoExitInfo> {
     // SDM Vol. 3C, Section 27.2.1, Table 27-5
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
@@ -19,11 +19,20 @@ pub type HostPhysAddr = usize;
 
 bitflags::bitflags! {
     /// Permission and type of a guest physical memory region.
+    ///
+    /// The memory type is write-back by default, or uncached for `DEVICE`,
+    /// unless one of the memory type flags is set.
     pub struct MemFlags: u64 {
-        const READ          = 1 << 0;
-        const WRITE         = 1 << 1;
-        const EXECUTE       = 1 << 2;
-        const DEVICE        = 1 << 3;
+        const READ              = 1 << 0;
+        const WRITE             = 1 << 1;
+        const EXECUTE           = 1 << 2;
+        const DEVICE            = 1 << 3;
+        const UNCACHED          = 1 << 4;
+        const WRITE_COMBINING   = 1 << 5;
+        const WRITE_THROUGH     = 1 << 6;
+        const WRITE_PROTECTED   = 1 << 7;
+        /// Ignore the memory type of the guest page tables (PAT).
+        const IGNORE_PAT        = 1 << 8;
     }
 }
 